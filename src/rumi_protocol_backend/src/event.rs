@@ -1,10 +1,15 @@
-use crate::numeric::{UsdIcp, ICUSD, ICP};
-use crate::state::{PendingMarginTransfer, State};
+use crate::numeric::{Ratio, UsdIcp, ICUSD, ICP};
+use crate::saga::{Saga, SagaId, SagaKind, SagaStatus};
+use crate::state::{
+    BadDebtPolicy, CollateralType, ModeChangeReason, ModeTransition, State,
+    TransferId, TransferPurpose,
+};
 use crate::storage::record_event;
 use crate::vault::Vault;
 use crate::{InitArg, Mode, UpgradeArg};
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Event {
@@ -15,10 +20,51 @@ pub enum Event {
     CloseVault {
         vault_id: u64,
         block_index: Option<u64>,
+        #[serde(default)]
+        timestamp: u64,
     },
 
     #[serde(rename = "margin_transfer")]
-    MarginTransfer { vault_id: u64, block_index: u64 },
+    MarginTransfer {
+        vault_id: u64,
+        block_index: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transfer_id: Option<TransferId>,
+    },
+
+    #[serde(rename = "transfer_queued")]
+    TransferQueued {
+        vault_id: u64,
+        purpose: TransferPurpose,
+        destination: Principal,
+        amount: ICP,
+        timestamp: u64,
+    },
+
+    #[serde(rename = "transfer_dropped")]
+    TransferDropped {
+        vault_id: u64,
+        transfer_id: TransferId,
+        amount: ICP,
+    },
+
+    #[serde(rename = "transfer_requeued")]
+    TransferRequeued {
+        vault_id: u64,
+        transfer_id: TransferId,
+        timestamp: u64,
+    },
+
+    #[serde(rename = "redemption_transfer_requeued")]
+    RedemptionTransferRequeued {
+        icusd_block_index: u64,
+        timestamp: u64,
+    },
+
+    #[serde(rename = "set_icp_ledger_fee")]
+    SetIcpLedgerFee {
+        fee: ICP,
+    },
 
     #[serde(rename = "liquidate_vault")]
     LiquidateVault {
@@ -46,6 +92,8 @@ pub enum Event {
         icusd_amount: ICUSD,
         fee_amount: ICUSD,
         icusd_block_index: u64,
+        #[serde(default)]
+        timestamp: u64,
     },
 
     #[serde(rename = "redemption_transfered")]
@@ -63,6 +111,8 @@ pub enum Event {
         borrowed_amount: ICUSD,
         fee_amount: ICUSD,
         block_index: u64,
+        #[serde(default)]
+        liquidation_reserve: ICUSD,
     },
 
     #[serde(rename = "repay_to_vault")]
@@ -125,7 +175,114 @@ pub enum Event {
         vault_id: u64,
         amount: ICP,
         block_index: Option<u64>,
+        #[serde(default)]
+        timestamp: u64,
+    },
+
+    #[serde(rename = "mode_changed")]
+    ModeChanged {
+        from: Mode,
+        to: Mode,
+        tcr: Ratio,
+        reason: ModeChangeReason,
+        timestamp: u64,
+    },
+
+    #[serde(rename = "set_debt_ceiling")]
+    SetDebtCeiling {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        collateral_type: Option<CollateralType>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ceiling: Option<ICUSD>,
+    },
+
+    #[serde(rename = "set_liquidation_protocol_share")]
+    SetLiquidationProtocolShare { share: Ratio },
+
+    #[serde(rename = "set_bad_debt_policy")]
+    SetBadDebtPolicy { policy: BadDebtPolicy },
+
+    #[serde(rename = "bad_debt")]
+    BadDebt { vault_id: u64, amount: ICUSD },
+
+    #[serde(rename = "bad_debt_covered")]
+    BadDebtCovered {
+        amount: ICUSD,
+        caller: Principal,
+        block_index: u64,
+    },
+
+    #[serde(rename = "set_flash_mint_params")]
+    SetFlashMintParams { cap: ICUSD, fee: Ratio },
+
+    #[serde(rename = "set_flash_mint_borrower")]
+    SetFlashMintBorrower { borrower: Principal, allowed: bool },
+
+    #[serde(rename = "flash_mint")]
+    FlashMint {
+        caller: Principal,
+        amount: ICUSD,
+        fee_amount: ICUSD,
+        mint_block_index: u64,
+        repay_block_index: u64,
+    },
+
+    #[serde(rename = "flash_mint_default")]
+    FlashMintDefault {
+        caller: Principal,
+        amount: ICUSD,
+        mint_block_index: u64,
+    },
+
+    #[serde(rename = "set_redemption_bootstrap")]
+    SetRedemptionBootstrap { end: u64 },
+
+    #[serde(rename = "set_min_vault_age_for_redemption")]
+    SetMinVaultAgeForRedemption { min_age: u64 },
+
+    #[serde(rename = "set_redemption_rebate_share")]
+    SetRedemptionRebateShare { share: Ratio },
+
+    #[serde(rename = "claim_redemption_rebate")]
+    ClaimRedemptionRebate {
+        vault_id: u64,
+        amount: ICUSD,
+        block_index: u64,
+    },
+
+    #[serde(rename = "accrue_interest")]
+    AccrueInterest { timestamp: u64 },
+
+    #[serde(rename = "saga_started")]
+    SagaStarted { kind: SagaKind, timestamp: u64 },
+
+    #[serde(rename = "saga_updated")]
+    SagaUpdated {
+        saga_id: u64,
+        status: SagaStatus,
+        timestamp: u64,
     },
+
+    #[serde(rename = "liquidation_refund_owed")]
+    LiquidationRefundOwed { liquidator: Principal, amount: ICUSD },
+
+    #[serde(rename = "liquidation_refund_claimed")]
+    LiquidationRefundClaimed {
+        liquidator: Principal,
+        amount: ICUSD,
+        block_index: u64,
+    },
+
+    #[serde(rename = "adjust_interest_rate")]
+    AdjustInterestRate {
+        vault_id: u64,
+        interest_rate: Ratio,
+        fee_amount: ICUSD,
+        timestamp: u64,
+    },
+
+    #[serde(rename = "migrate_legacy_interest_rates")]
+    MigrateLegacyInterestRates { timestamp: u64 },
 }
 
 impl Event {
@@ -135,6 +292,11 @@ impl Event {
             Event::OpenVault { vault, .. } => &vault.vault_id == filter_vault_id,
             Event::CloseVault { vault_id, .. } => vault_id == filter_vault_id,
             Event::MarginTransfer { vault_id, .. } => vault_id == filter_vault_id,
+            Event::TransferQueued { vault_id, .. } => vault_id == filter_vault_id,
+            Event::TransferDropped { vault_id, .. } => vault_id == filter_vault_id,
+            Event::TransferRequeued { vault_id, .. } => vault_id == filter_vault_id,
+            Event::RedemptionTransferRequeued { .. } => false,
+            Event::SetIcpLedgerFee { .. } => false,
             Event::LiquidateVault { vault_id, .. } => vault_id == filter_vault_id,
             Event::PartialLiquidateVault { vault_id, .. } => vault_id == filter_vault_id,
             Event::RedemptionOnVaults { .. } => true,
@@ -151,6 +313,55 @@ impl Event {
             Event::CollateralWithdrawn { vault_id, .. } => vault_id == filter_vault_id,
             Event::VaultWithdrawnAndClosed { vault_id, .. } => vault_id == filter_vault_id,
             Event::WithdrawAndCloseVault { vault_id, .. } => vault_id == filter_vault_id,
+            Event::ModeChanged { .. } => false,
+            Event::SetDebtCeiling { .. } => false,
+            Event::SetLiquidationProtocolShare { .. } => false,
+            Event::SetBadDebtPolicy { .. } => false,
+            Event::BadDebt { vault_id, .. } => vault_id == filter_vault_id,
+            Event::BadDebtCovered { .. } => false,
+            Event::SetFlashMintParams { .. } => false,
+            Event::SetFlashMintBorrower { .. } => false,
+            Event::FlashMint { .. } => false,
+            Event::FlashMintDefault { .. } => false,
+            Event::SetRedemptionBootstrap { .. } => false,
+            Event::SetMinVaultAgeForRedemption { .. } => false,
+            Event::SetRedemptionRebateShare { .. } => false,
+            Event::ClaimRedemptionRebate { vault_id, .. } => vault_id == filter_vault_id,
+            Event::AccrueInterest { .. } => false,
+            Event::SagaStarted { kind, .. } => match kind {
+                SagaKind::OpenVault { .. } => false,
+                SagaKind::WithdrawAndCloseVault { vault_id, .. }
+                | SagaKind::WithdrawCollateral { vault_id, .. } => vault_id == filter_vault_id,
+            },
+            Event::SagaUpdated { status, .. } => match status {
+                SagaStatus::Completed { vault_id, .. } => vault_id == filter_vault_id,
+                _ => false,
+            },
+            Event::AdjustInterestRate { vault_id, .. } => vault_id == filter_vault_id,
+            Event::LiquidationRefundOwed { .. } => false,
+            Event::LiquidationRefundClaimed { .. } => false,
+            Event::MigrateLegacyInterestRates { .. } => false,
+        }
+    }
+
+    /// Whether the event concerns the given principal or one of their vaults.
+    pub fn is_principal_related(&self, principal: &Principal, vault_ids: &BTreeSet<u64>) -> bool {
+        match self {
+            // Redemptions touch every vault, only the redeemer is relevant.
+            Event::RedemptionOnVaults { owner, .. } => owner == principal,
+            Event::TransferQueued { destination, .. } if destination == principal => true,
+            Event::ProvideLiquidity { caller, .. }
+            | Event::WithdrawLiquidity { caller, .. }
+            | Event::ClaimLiquidityReturns { caller, .. }
+            | Event::VaultWithdrawnAndClosed { caller, .. }
+            | Event::BadDebtCovered { caller, .. }
+            | Event::FlashMint { caller, .. }
+            | Event::FlashMintDefault { caller, .. } => caller == principal,
+            Event::LiquidationRefundOwed { liquidator, .. }
+            | Event::LiquidationRefundClaimed { liquidator, .. } => liquidator == principal,
+            _ => vault_ids
+                .iter()
+                .any(|vault_id| self.is_vault_related(vault_id)),
         }
     }
 }
@@ -187,7 +398,8 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
             Event::CloseVault {
                 vault_id,
                 block_index: _,
-            } => state.close_vault(vault_id),
+                timestamp,
+            } => state.close_vault(vault_id, timestamp),
             Event::LiquidateVault {
                 vault_id,
                 mode,
@@ -207,9 +419,10 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
                 borrowed_amount,
                 fee_amount,
                 block_index: _,
+                liquidation_reserve,
             } => {
                 state.provide_liquidity(fee_amount, state.developer_principal);
-                state.borrow_from_vault(vault_id, borrowed_amount)
+                state.borrow_from_vault(vault_id, borrowed_amount, liquidation_reserve)
             }
             Event::RedemptionOnVaults {
                 owner,
@@ -217,13 +430,11 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
                 icusd_amount,
                 fee_amount,
                 icusd_block_index,
+                timestamp,
             } => {
-                state.provide_liquidity(fee_amount, state.developer_principal);
-                state.redeem_on_vaults(icusd_amount, current_icp_rate);
+                state.settle_redemption(icusd_amount, fee_amount, current_icp_rate, timestamp);
                 let margin: ICP = icusd_amount / current_icp_rate;
-                state
-                    .pending_redemption_transfer
-                    .insert(icusd_block_index, PendingMarginTransfer { owner, margin });
+                state.queue_redemption_transfer(icusd_block_index, owner, margin, timestamp);
             }
             Event::RedemptionTransfered {
                 icusd_block_index, ..
@@ -255,31 +466,127 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
             Event::Upgrade(upgrade_args) => {
                 state.upgrade(upgrade_args);
             }
-            Event::MarginTransfer { vault_id, .. } => {
-                state.pending_margin_transfers.remove(&vault_id);
+            Event::MarginTransfer {
+                vault_id,
+                transfer_id,
+                ..
+            } => match transfer_id {
+                Some(transfer_id) => state.complete_transfer(transfer_id),
+                None => state.complete_vault_transfer(vault_id),
+            },
+            Event::TransferQueued {
+                vault_id,
+                purpose,
+                destination,
+                amount,
+                timestamp,
+            } => {
+                state.enqueue_transfer(vault_id, purpose, destination, amount, timestamp);
             }
-            Event::CollateralWithdrawn { vault_id, .. } => {
-                // The vault's margin has already been set to 0 in the vault.rs function
+            Event::TransferDropped { transfer_id, .. } => state.complete_transfer(transfer_id),
+            Event::TransferRequeued {
+                transfer_id,
+                timestamp,
+                ..
+            } => state.requeue_transfer(transfer_id, timestamp),
+            Event::RedemptionTransferRequeued {
+                icusd_block_index,
+                timestamp,
+            } => state.requeue_redemption_transfer(icusd_block_index, timestamp),
+            Event::SetIcpLedgerFee { fee } => state.set_icp_ledger_fee(fee),
+            Event::CollateralWithdrawn {
+                vault_id, amount, ..
+            } => {
+                // Live calls take the margin out of the vault before the transfer.
+                if let Some(vault) = state.vault_id_to_vaults.get_mut(&vault_id) {
+                    vault.icp_margin_amount = vault.icp_margin_amount.saturating_sub(amount);
+                }
             }
             // In the match statement inside replay function
             Event::VaultWithdrawnAndClosed {
                 vault_id,
                 caller: _,   // Ignore caller
                 amount: _,   // Ignore amount
-                timestamp: _, // Ignore timestamp
+                timestamp,
             } => {
                 // Simply close the vault - previous implementation was incorrect
-                state.close_vault(vault_id);
+                state.close_vault(vault_id, timestamp);
             },
             // Add this case:
             Event::WithdrawAndCloseVault { 
                 vault_id,
                 amount: _,
                 block_index: _,
+                timestamp,
             } => {
                 // Close the vault during replay
-                state.close_vault(vault_id);
+                state.close_vault(vault_id, timestamp);
             },
+            Event::ModeChanged {
+                from,
+                to,
+                tcr,
+                reason,
+                timestamp,
+            } => state.change_mode(ModeTransition {
+                from,
+                to,
+                total_collateral_ratio: tcr,
+                reason,
+                timestamp,
+            }),
+            Event::SetDebtCeiling {
+                collateral_type,
+                ceiling,
+            } => state.set_debt_ceiling(collateral_type, ceiling),
+            Event::SetLiquidationProtocolShare { share } => {
+                state.set_liquidation_protocol_share(share)
+            }
+            Event::SetBadDebtPolicy { policy } => state.set_bad_debt_policy(policy),
+            Event::BadDebt { amount, .. } => state.absorb_bad_debt(amount),
+            Event::BadDebtCovered { amount, .. } => state.cover_bad_debt(amount),
+            Event::SetFlashMintParams { cap, fee } => state.set_flash_mint_params(cap, fee),
+            Event::SetFlashMintBorrower { borrower, allowed } => {
+                state.set_flash_mint_borrower(borrower, allowed);
+            }
+            Event::FlashMint { fee_amount, .. } => {
+                state.provide_liquidity(fee_amount, state.developer_principal);
+            }
+            Event::FlashMintDefault { caller, amount, .. } => {
+                state.add_flash_mint_default(caller, amount);
+            }
+            Event::SetRedemptionBootstrap { end } => state.set_redemption_bootstrap_end(end),
+            Event::SetMinVaultAgeForRedemption { min_age } => {
+                state.set_min_vault_age_for_redemption(min_age)
+            }
+            Event::SetRedemptionRebateShare { share } => state.set_redemption_rebate_share(share),
+            Event::ClaimRedemptionRebate {
+                vault_id, amount, ..
+            } => state.claim_redemption_rebate(vault_id, amount),
+            Event::AccrueInterest { timestamp } => {
+                state.accrue_interest(timestamp);
+            }
+            Event::MigrateLegacyInterestRates { .. } => state.migrate_legacy_interest_rates(),
+            Event::SagaStarted { kind, timestamp } => {
+                state.start_saga(kind, timestamp);
+            }
+            Event::SagaUpdated {
+                saga_id,
+                status,
+                timestamp,
+            } => state.update_saga(saga_id, status, timestamp),
+            Event::AdjustInterestRate {
+                vault_id,
+                interest_rate,
+                fee_amount,
+                timestamp,
+            } => state.adjust_interest_rate(vault_id, interest_rate, fee_amount, timestamp),
+            Event::LiquidationRefundOwed { liquidator, amount } => {
+                state.add_liquidation_refund(liquidator, amount);
+            }
+            Event::LiquidationRefundClaimed {
+                liquidator, amount, ..
+            } => state.claim_liquidation_refund(liquidator, amount),
         }
     }
     state.next_available_vault_id = vault_id;
@@ -351,20 +658,93 @@ pub fn record_open_vault(state: &mut State, vault: Vault, block_index: u64) {
     state.open_vault(vault);
 }
 
-pub fn record_close_vault(state: &mut State, vault_id: u64, block_index: Option<u64>) {
+pub fn record_close_vault(
+    state: &mut State,
+    vault_id: u64,
+    block_index: Option<u64>,
+    timestamp: u64,
+) {
     record_event(&Event::CloseVault {
         vault_id,
         block_index,
+        timestamp,
+    });
+    state.close_vault(vault_id, timestamp);
+}
+
+pub fn record_transfer_queued(
+    state: &mut State,
+    vault_id: u64,
+    purpose: TransferPurpose,
+    destination: Principal,
+    amount: ICP,
+    timestamp: u64,
+) -> TransferId {
+    record_event(&Event::TransferQueued {
+        vault_id,
+        purpose,
+        destination,
+        amount,
+        timestamp,
     });
-    state.close_vault(vault_id);
+    state.enqueue_transfer(vault_id, purpose, destination, amount, timestamp)
 }
 
-pub fn record_margin_transfer(state: &mut State, vault_id: u64, block_index: u64) {
+pub fn record_margin_transfer(state: &mut State, transfer_id: TransferId, block_index: u64) {
+    let vault_id = match state.pending_transfers.get(&transfer_id) {
+        Some(transfer) => transfer.vault_id,
+        None => return,
+    };
     record_event(&Event::MarginTransfer {
         vault_id,
         block_index,
+        transfer_id: Some(transfer_id),
+    });
+    state.complete_transfer(transfer_id);
+}
+
+/// Drops a pending transfer that can't cover the ledger fee.
+pub fn record_transfer_dropped(state: &mut State, transfer_id: TransferId) {
+    let (vault_id, amount) = match state.pending_transfers.get(&transfer_id) {
+        Some(transfer) => (transfer.vault_id, transfer.amount),
+        None => return,
+    };
+    record_event(&Event::TransferDropped {
+        vault_id,
+        transfer_id,
+        amount,
+    });
+    state.complete_transfer(transfer_id);
+}
+
+pub fn record_transfer_requeued(state: &mut State, transfer_id: TransferId, timestamp: u64) {
+    let vault_id = match state.pending_transfers.get(&transfer_id) {
+        Some(transfer) => transfer.vault_id,
+        None => return,
+    };
+    record_event(&Event::TransferRequeued {
+        vault_id,
+        transfer_id,
+        timestamp,
     });
-    state.pending_margin_transfers.remove(&vault_id);
+    state.requeue_transfer(transfer_id, timestamp);
+}
+
+pub fn record_redemption_transfer_requeued(
+    state: &mut State,
+    icusd_block_index: u64,
+    timestamp: u64,
+) {
+    record_event(&Event::RedemptionTransferRequeued {
+        icusd_block_index,
+        timestamp,
+    });
+    state.requeue_redemption_transfer(icusd_block_index, timestamp);
+}
+
+pub fn record_set_icp_ledger_fee(state: &mut State, fee: ICP) {
+    record_event(&Event::SetIcpLedgerFee { fee });
+    state.set_icp_ledger_fee(fee);
 }
 
 pub fn record_borrow_from_vault(
@@ -372,6 +752,7 @@ pub fn record_borrow_from_vault(
     vault_id: u64,
     borrowed_amount: ICUSD,
     fee_amount: ICUSD,
+    liquidation_reserve: ICUSD,
     block_index: u64,
 ) {
     record_event(&Event::BorrowFromVault {
@@ -379,8 +760,9 @@ pub fn record_borrow_from_vault(
         block_index,
         fee_amount,
         borrowed_amount,
+        liquidation_reserve,
     });
-    state.borrow_from_vault(vault_id, borrowed_amount);
+    state.borrow_from_vault(vault_id, borrowed_amount, liquidation_reserve);
     state.provide_liquidity(fee_amount, state.developer_principal);
 }
 
@@ -419,20 +801,21 @@ pub fn record_redemption_on_vaults(
     fee_amount: ICUSD,
     current_icp_rate: UsdIcp,
     icusd_block_index: u64,
-) {
+    timestamp: u64,
+) -> ICUSD {
     record_event(&Event::RedemptionOnVaults {
         owner,
         current_icp_rate,
         icusd_amount,
         fee_amount,
         icusd_block_index,
+        timestamp,
     });
-    state.provide_liquidity(fee_amount, state.developer_principal);
-    state.redeem_on_vaults(icusd_amount, current_icp_rate);
+    let protocol_fee =
+        state.settle_redemption(icusd_amount, fee_amount, current_icp_rate, timestamp);
     let margin: ICP = icusd_amount / current_icp_rate;
-    state
-        .pending_redemption_transfer
-        .insert(icusd_block_index, PendingMarginTransfer { owner, margin });
+    state.queue_redemption_transfer(icusd_block_index, owner, margin, timestamp);
+    protocol_fee
 }
 
 pub fn record_redemption_transfered(
@@ -465,14 +848,231 @@ pub fn record_withdraw_and_close_vault(
     state: &mut State,
     vault_id: u64,
     amount: ICP,
-    block_index: Option<u64>
+    block_index: Option<u64>,
+    timestamp: u64,
 ) {
     record_event(&Event::WithdrawAndCloseVault {
         vault_id,
         amount,
         block_index,
+        timestamp,
     });
     
     // Close the vault (withdrawal is already handled in vault.rs)
-    state.close_vault(vault_id);
+    state.close_vault(vault_id, timestamp);
+}
+
+pub fn record_mode_changed(
+    state: &mut State,
+    to: Mode,
+    reason: ModeChangeReason,
+    timestamp: u64,
+) {
+    let from = state.mode;
+    let tcr = state.total_collateral_ratio;
+    record_event(&Event::ModeChanged {
+        from,
+        to,
+        tcr,
+        reason,
+        timestamp,
+    });
+    state.change_mode(ModeTransition {
+        from,
+        to,
+        total_collateral_ratio: tcr,
+        reason,
+        timestamp,
+    });
+}
+
+pub fn record_upgrade(state: &mut State, upgrade_args: UpgradeArg, timestamp: u64) {
+    if let Some(mode) = upgrade_args.mode {
+        if mode != state.mode {
+            record_mode_changed(state, mode, ModeChangeReason::Upgrade, timestamp);
+        }
+    }
+    if upgrade_args.migrate_legacy_interest_rates == Some(true)
+        && state.has_legacy_interest_rates()
+    {
+        record_migrate_legacy_interest_rates(state, timestamp);
+    }
+    record_event(&Event::Upgrade(upgrade_args.clone()));
+    state.upgrade(upgrade_args);
+}
+
+pub fn record_set_debt_ceiling(
+    state: &mut State,
+    collateral_type: Option<CollateralType>,
+    ceiling: Option<ICUSD>,
+) {
+    record_event(&Event::SetDebtCeiling {
+        collateral_type,
+        ceiling,
+    });
+    state.set_debt_ceiling(collateral_type, ceiling);
+}
+
+pub fn record_accrue_interest(state: &mut State, timestamp: u64) -> ICUSD {
+    record_event(&Event::AccrueInterest { timestamp });
+    state.accrue_interest(timestamp)
+}
+
+pub fn record_migrate_legacy_interest_rates(state: &mut State, timestamp: u64) {
+    record_event(&Event::MigrateLegacyInterestRates { timestamp });
+    state.migrate_legacy_interest_rates();
+}
+
+pub fn record_adjust_interest_rate(
+    state: &mut State,
+    vault_id: u64,
+    interest_rate: Ratio,
+    fee_amount: ICUSD,
+    timestamp: u64,
+) {
+    record_event(&Event::AdjustInterestRate {
+        vault_id,
+        interest_rate,
+        fee_amount,
+        timestamp,
+    });
+    state.adjust_interest_rate(vault_id, interest_rate, fee_amount, timestamp);
+}
+
+pub fn record_set_redemption_rebate_share(state: &mut State, share: Ratio) {
+    record_event(&Event::SetRedemptionRebateShare { share });
+    state.set_redemption_rebate_share(share);
+}
+
+pub fn record_claim_redemption_rebate(
+    state: &mut State,
+    vault_id: u64,
+    amount: ICUSD,
+    block_index: u64,
+) {
+    record_event(&Event::ClaimRedemptionRebate {
+        vault_id,
+        amount,
+        block_index,
+    });
+    state.claim_redemption_rebate(vault_id, amount);
+}
+
+pub fn record_set_redemption_bootstrap(state: &mut State, end: u64) {
+    record_event(&Event::SetRedemptionBootstrap { end });
+    state.set_redemption_bootstrap_end(end);
+}
+
+pub fn record_set_min_vault_age_for_redemption(state: &mut State, min_age: u64) {
+    record_event(&Event::SetMinVaultAgeForRedemption { min_age });
+    state.set_min_vault_age_for_redemption(min_age);
+}
+
+pub fn record_set_flash_mint_params(state: &mut State, cap: ICUSD, fee: Ratio) {
+    record_event(&Event::SetFlashMintParams { cap, fee });
+    state.set_flash_mint_params(cap, fee);
+}
+
+pub fn record_set_flash_mint_borrower(state: &mut State, borrower: Principal, allowed: bool) {
+    record_event(&Event::SetFlashMintBorrower { borrower, allowed });
+    state.set_flash_mint_borrower(borrower, allowed);
+}
+
+pub fn record_flash_mint(
+    state: &mut State,
+    caller: Principal,
+    amount: ICUSD,
+    fee_amount: ICUSD,
+    mint_block_index: u64,
+    repay_block_index: u64,
+) {
+    record_event(&Event::FlashMint {
+        caller,
+        amount,
+        fee_amount,
+        mint_block_index,
+        repay_block_index,
+    });
+    state.provide_liquidity(fee_amount, state.developer_principal);
+}
+
+pub fn record_flash_mint_default(
+    state: &mut State,
+    caller: Principal,
+    minted: ICUSD,
+    amount: ICUSD,
+    mint_block_index: u64,
+) {
+    record_event(&Event::FlashMintDefault {
+        caller,
+        amount,
+        mint_block_index,
+    });
+    state.default_flash_mint(caller, minted, amount);
+}
+
+pub fn record_set_bad_debt_policy(state: &mut State, policy: BadDebtPolicy) {
+    record_event(&Event::SetBadDebtPolicy { policy });
+    state.set_bad_debt_policy(policy);
+}
+
+pub fn record_bad_debt(state: &mut State, vault_id: u64, amount: ICUSD) {
+    record_event(&Event::BadDebt { vault_id, amount });
+    state.absorb_bad_debt(amount);
+}
+
+pub fn record_bad_debt_covered(
+    state: &mut State,
+    amount: ICUSD,
+    caller: Principal,
+    block_index: u64,
+) {
+    record_event(&Event::BadDebtCovered {
+        amount,
+        caller,
+        block_index,
+    });
+    state.cover_bad_debt(amount);
+}
+
+pub fn record_set_liquidation_protocol_share(state: &mut State, share: Ratio) {
+    record_event(&Event::SetLiquidationProtocolShare { share });
+    state.set_liquidation_protocol_share(share);
+}
+
+pub fn record_saga_started(state: &mut State, kind: SagaKind, timestamp: u64) -> Saga {
+    record_event(&Event::SagaStarted {
+        kind: kind.clone(),
+        timestamp,
+    });
+    let saga_id = state.start_saga(kind, timestamp);
+    state.sagas[&saga_id].clone()
+}
+
+pub fn record_saga_updated(state: &mut State, saga_id: SagaId, status: SagaStatus, timestamp: u64) {
+    record_event(&Event::SagaUpdated {
+        saga_id,
+        status: status.clone(),
+        timestamp,
+    });
+    state.update_saga(saga_id, status, timestamp);
+}
+
+pub fn record_liquidation_refund_owed(state: &mut State, liquidator: Principal, amount: ICUSD) {
+    record_event(&Event::LiquidationRefundOwed { liquidator, amount });
+    state.add_liquidation_refund(liquidator, amount);
+}
+
+pub fn record_liquidation_refund_claimed(
+    state: &mut State,
+    liquidator: Principal,
+    amount: ICUSD,
+    block_index: u64,
+) {
+    record_event(&Event::LiquidationRefundClaimed {
+        liquidator,
+        amount,
+        block_index,
+    });
+    state.claim_liquidation_refund(liquidator, amount);
 }
@@ -0,0 +1,125 @@
+use crate::event::{record_flash_mint, record_flash_mint_default};
+use crate::guard::GuardPrincipal;
+use crate::logs::{DEBUG, INFO};
+use crate::management::{mint_icusd, transfer_icusd_from};
+use crate::{mutate_state, ProtocolError, ICUSD};
+use candid::{CandidType, Deserialize};
+use ic_canister_log::log;
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct FlashMintArg {
+    pub amount: u64,
+    /// Method of the calling canister invoked once the icUSD is minted.
+    pub callback_method: String,
+    /// Opaque bytes handed back to the callback.
+    pub payload: Vec<u8>,
+}
+
+/// Argument of the callback. Before it returns, the callee must approve the
+/// protocol for `amount + fee_amount` (plus the ledger fee).
+#[derive(CandidType, Deserialize, Debug)]
+pub struct FlashMintCallbackArg {
+    pub amount: u64,
+    pub fee_amount: u64,
+    pub mint_block_index: u64,
+    pub payload: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct FlashMintSuccess {
+    pub mint_block_index: u64,
+    pub repay_block_index: u64,
+    pub fee_amount_paid: u64,
+}
+
+/// Mints `amount` icUSD to the calling canister, calls it back and burns
+/// `amount` plus the flash mint fee from it with a transfer-from. Only
+/// canisters allowlisted by the developer can flash mint.
+pub async fn flash_mint(arg: FlashMintArg) -> Result<FlashMintSuccess, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let guard_principal = GuardPrincipal::new(caller, "flash_mint")?;
+
+    let amount: ICUSD = arg.amount.into();
+    let fee_amount = match mutate_state(|s| s.reserve_flash_mint(caller, amount)) {
+        Ok(fee_amount) => fee_amount,
+        Err(error) => {
+            guard_principal.fail();
+            return Err(error);
+        }
+    };
+
+    let mint_block_index = match mint_icusd(amount, caller).await {
+        Ok(block_index) => block_index,
+        Err(transfer_error) => {
+            mutate_state(|s| s.release_flash_mint(amount));
+            guard_principal.fail();
+            return Err(ProtocolError::TransferError(transfer_error));
+        }
+    };
+    log!(
+        DEBUG,
+        "[flash_mint] minted {amount} to {caller} at block {mint_block_index}"
+    );
+
+    let callback_arg = FlashMintCallbackArg {
+        amount: amount.to_u64(),
+        fee_amount: fee_amount.to_u64(),
+        mint_block_index,
+        payload: arg.payload,
+    };
+    // A failing callback doesn't end the flow: the minted icUSD is out
+    // either way and still has to be returned.
+    let callback_result: Result<(), _> =
+        ic_cdk::call(caller, &arg.callback_method, (callback_arg,)).await;
+    if let Err((code, message)) = callback_result {
+        log!(
+            INFO,
+            "[flash_mint] callback {} of {caller} failed: {:?} {message}",
+            arg.callback_method,
+            code
+        );
+    }
+
+    let repay_amount = amount + fee_amount;
+    match transfer_icusd_from(repay_amount, caller).await {
+        Ok(repay_block_index) => {
+            mutate_state(|s| {
+                s.release_flash_mint(amount);
+                record_flash_mint(
+                    s,
+                    caller,
+                    amount,
+                    fee_amount,
+                    mint_block_index,
+                    repay_block_index,
+                );
+            });
+            log!(
+                INFO,
+                "[flash_mint] {caller} returned {repay_amount} at block {repay_block_index}"
+            );
+            guard_principal.complete();
+            Ok(FlashMintSuccess {
+                mint_block_index,
+                repay_block_index,
+                fee_amount_paid: fee_amount.to_u64(),
+            })
+        }
+        Err(transfer_from_error) => {
+            // The defaulted amount keeps counting against the cap and the debt ceilings.
+            mutate_state(|s| {
+                record_flash_mint_default(s, caller, amount, repay_amount, mint_block_index);
+            });
+            log!(
+                INFO,
+                "[flash_mint] {caller} failed to return {repay_amount}: {:?}",
+                transfer_from_error
+            );
+            guard_principal.fail();
+            Err(ProtocolError::TransferFromError(
+                transfer_from_error,
+                repay_amount.to_u64(),
+            ))
+        }
+    }
+}
@@ -1,220 +1,155 @@
-use crate::state::mutate_state;
-use candid::Principal;
-use std::marker::PhantomData;
-use ic_cdk::api::time;
+use crate::logs::INFO;
+use crate::numeric::ICUSD;
+use crate::state::{mutate_state, CollateralType};
+use crate::{ProtocolError, SEC_NANOS};
+use candid::{CandidType, Principal};
 use ic_canister_log::log;
+use ic_cdk::api::time;
+use serde::{Deserialize, Serialize};
 
-const MAX_CONCURRENT: usize = 100;
+pub const MAX_CONCURRENT: usize = 100;
 
-// Create a unique operation key combining principal and operation name
-fn create_operation_key(principal: Principal, operation_name: &str) -> String {
-    format!("{}:{}", principal.to_string(), operation_name)
-}
-// Add a timeout duration for guards
-const GUARD_TIMEOUT_NANOS: u64 = 5 * 60 * 1_000_000_000; // 5 minutes in nanoseconds
+// A lock that was never released, e.g. because its holder trapped, is freed
+// once its lease runs out
+pub const LOCK_LEASE_NANOS: u64 = 5 * 60 * SEC_NANOS;
 
-// Add maximum allowed operation time
-const MAX_OPERATION_TIME_NANOS: u64 = 30 * 1_000_000_000; // 30 seconds in nanoseconds
+// Held by every operation that mints the liquidation refunds owed to a caller
+const LIQUIDATION_REFUND_OPERATION: &str = "liquidation_refund";
 
-// Track operation state
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum OperationState {
-    InProgress,
-    Completed,
-    Failed,
+/// What a lock protects.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum LockKey {
+    /// An operation of a caller, which may not run twice at the same time.
+    Caller {
+        principal: Principal,
+        operation: String,
+    },
+    /// A vault, which may be changed by one operation at a time, whoever
+    /// the caller is.
+    Vault(u64),
 }
 
-/// Guards a block from executing twice when called by the same user and from being
-/// executed [MAX_CONCURRENT] or more times in parallel.
-#[must_use]
-pub struct GuardPrincipal {
-    principal: Principal,
-    created_at: u64,
-    operation_id: String, // Identify the specific operation
-    _marker: PhantomData<GuardPrincipal>,
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Lock {
+    pub key: LockKey,
+    pub holder: Principal,
+    pub operation: String,
+    pub acquired_at: u64,
+    pub expires_at: u64,
+}
+
+impl Lock {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum GuardError {
     AlreadyProcessing,
+    VaultLocked(u64),
     TooManyConcurrentRequests,
-    StaleOperation,
+}
+
+/// Guards a block from executing twice at the same time for the same caller
+/// or vault, and from being executed [MAX_CONCURRENT] or more times in
+/// parallel. The locks are released when the guard is dropped.
+#[must_use]
+pub struct GuardPrincipal {
+    holder: Principal,
+    operation: String,
+    acquired_at: u64,
+    keys: Vec<LockKey>,
 }
 
 impl GuardPrincipal {
-    /// Attempts to create a new guard for the current block. Fails if there is
-    /// already a pending request for the specified [operation_key] or if there
-    /// are at least [MAX_CONCURRENT] pending requests.
+    /// Locks `operation_name` for `principal`.
     pub fn new(principal: Principal, operation_name: &str) -> Result<Self, GuardError> {
-        let operation_key = create_operation_key(principal, operation_name);
-        
-        mutate_state(|s| {
-            // Clean up any stale guards before processing new request
-            let current_time = time();
-            
-            // Remove guards that are older than the timeout or explicitly marked as failed
-            let mut stale_operations = Vec::new();
-            for op_key in s.operation_guards.iter() {
-                if let Some(timestamp) = s.operation_guard_timestamps.get(op_key) {
-                    // Check if operation has been running too long
-                    if current_time.saturating_sub(*timestamp) > GUARD_TIMEOUT_NANOS {
-                        if let Some((op_principal, op_name)) = s.operation_details.get(op_key) {
-                            log!(crate::INFO, 
-                                "[guard] Removing stale operation: {} for principal: {} (age: {}s)",
-                                op_name,
-                                op_principal.to_string(), 
-                                current_time.saturating_sub(*timestamp) / 1_000_000_000
-                            );
-                        }
-                        stale_operations.push(op_key.clone());
-                    } 
-                    
-                    // Also check for operations marked as failed or with errors
-                    if let Some(state) = s.operation_states.get(op_key) {
-                        if *state == OperationState::Failed {
-                            if let Some((op_principal, op_name)) = s.operation_details.get(op_key) {
-                                log!(crate::INFO, 
-                                    "[guard] Removing failed operation: {} for principal: {}", 
-                                    op_name,
-                                    op_principal.to_string()
-                                );
-                            }
-                            stale_operations.push(op_key.clone());
-                        }
-                    }
-                } else {
-                    // No timestamp, must be stale
-                    stale_operations.push(op_key.clone());
-                }
-            }
-            
-            // Remove stale guards from all tracking data structures
-            for op_key in stale_operations {
-                s.operation_guards.remove(&op_key);
-                s.operation_guard_timestamps.remove(&op_key);
-                s.operation_states.remove(&op_key);
-                s.operation_details.remove(&op_key);
-            }
-            
-            // Now check if this specific operation already has a guard
-            if s.operation_guards.contains(&operation_key) {
-                let (op_principal, op_name) = s.operation_details.get(&operation_key)
-                    .map(|(p, n)| (*p, n.clone()))
-                    .unwrap_or((principal, operation_name.to_string()));
-                
-                let timestamp = s.operation_guard_timestamps.get(&operation_key)
-                    .copied()
-                    .unwrap_or_default();
-                
-                let age_seconds = (current_time - timestamp) / 1_000_000_000;
-                
-                if age_seconds > (GUARD_TIMEOUT_NANOS / 1_000_000_000) / 2 {
-                    // If operation is more than half of timeout old, treat it as stale
-                    log!(crate::INFO, 
-                        "[guard] Operation '{}' for principal {} is stale ({}s old), allowing new request",
-                        op_name, op_principal.to_string(), age_seconds
-                    );
-                    
-                    // Clean up the stale operation
-                    s.operation_guards.remove(&operation_key);
-                    s.operation_guard_timestamps.remove(&operation_key);
-                    s.operation_states.remove(&operation_key);
-                    s.operation_details.remove(&operation_key);
-                    
-                    // Continue with new guard creation below
-                } else {
-                    // Operation is still considered active
-                    log!(crate::INFO, 
-                        "[guard] Operation '{}' for principal {} is already in progress ({}s old)",
-                        op_name, op_principal.to_string(), age_seconds
-                    );
-                    return Err(GuardError::AlreadyProcessing);
-                }
-            }
-            
-            if s.operation_guards.len() >= MAX_CONCURRENT {
-                return Err(GuardError::TooManyConcurrentRequests);
-            }
-            
-            // Add the guard and tracking data using operation key
-            s.operation_guards.insert(operation_key.clone());
-            s.operation_guard_timestamps.insert(operation_key.clone(), current_time);
-            s.operation_states.insert(operation_key.clone(), OperationState::InProgress);
-            s.operation_details.insert(operation_key.clone(), (principal, operation_name.to_string()));
-            
-            log!(crate::INFO, 
-                "[guard] Created new guard for principal {} operation '{}' with key {}",
-                principal.to_string(), operation_name, &operation_key
-            );
-            
-            Ok(Self {
-                principal,
-                created_at: current_time,
-                operation_id: operation_key,
-                _marker: PhantomData,
-            })
+        let key = LockKey::Caller {
+            principal,
+            operation: operation_name.to_string(),
+        };
+        Self::acquire(principal, operation_name, vec![key])
+    }
+
+    /// Locks `operation_name` for `principal` along with the liquidation
+    /// refunds owed to them, so that a refund is never minted twice.
+    pub fn new_for_liquidation_refund(
+        principal: Principal,
+        operation_name: &str,
+    ) -> Result<Self, GuardError> {
+        Self::acquire(
+            principal,
+            operation_name,
+            liquidation_refund_keys(principal, operation_name),
+        )
+    }
+
+    /// Locks the vault `vault_id` on behalf of `principal`, so that neither
+    /// its owner nor a liquidator can change it concurrently.
+    pub fn new_for_vault(
+        principal: Principal,
+        operation_name: &str,
+        vault_id: u64,
+    ) -> Result<Self, GuardError> {
+        Self::acquire(principal, operation_name, vec![LockKey::Vault(vault_id)])
+    }
+
+    fn acquire(principal: Principal, operation_name: &str, keys: Vec<LockKey>) -> Result<Self, GuardError> {
+        let now = time();
+        mutate_state(|s| s.acquire_locks(&keys, principal, operation_name, now, LOCK_LEASE_NANOS))
+            .map_err(|err| {
+                log!(INFO,
+                    "[guard] {} could not start '{}': {:?}",
+                    principal, operation_name, err
+                );
+                err
+            })?;
+        Ok(Self {
+            holder: principal,
+            operation: operation_name.to_string(),
+            acquired_at: now,
+            keys,
         })
     }
-    
-    // Method to mark this operation as complete
-    pub fn complete(self) {
+
+    /// Also locks the given vaults until the guard is dropped. The vaults
+    /// must not be locked already.
+    pub fn lock_vaults(&mut self, vault_ids: &[u64]) -> Result<(), GuardError> {
+        let keys: Vec<LockKey> = vault_ids.iter().map(|id| LockKey::Vault(*id)).collect();
+        let now = time();
         mutate_state(|s| {
-            if let Some(state) = s.operation_states.get_mut(&self.operation_id) {
-                *state = OperationState::Completed;
-                log!(crate::INFO, 
-                    "[guard] Marked operation {} as completed", 
-                    self.operation_id
-                );
-            }
-        });
+            s.extend_locks(&keys, self.holder, &self.operation, self.acquired_at, now, LOCK_LEASE_NANOS)
+        })?;
+        self.keys.extend(keys);
+        Ok(())
     }
-    
-    // Method to mark this operation as failed
+
+    pub fn complete(self) {
+        log!(INFO, "[guard] '{}' of {} completed", self.operation, self.holder);
+    }
+
     pub fn fail(self) {
-        mutate_state(|s| {
-            if let Some(state) = s.operation_states.get_mut(&self.operation_id) {
-                *state = OperationState::Failed;
-                log!(crate::INFO, 
-                    "[guard] Marked operation {} as failed", 
-                    self.operation_id
-                );
-            }
-        });
+        log!(INFO, "[guard] '{}' of {} failed", self.operation, self.holder);
     }
 }
 
+pub fn liquidation_refund_keys(principal: Principal, operation_name: &str) -> Vec<LockKey> {
+    vec![
+        LockKey::Caller {
+            principal,
+            operation: operation_name.to_string(),
+        },
+        LockKey::Caller {
+            principal,
+            operation: LIQUIDATION_REFUND_OPERATION.to_string(),
+        },
+    ]
+}
+
 impl Drop for GuardPrincipal {
     fn drop(&mut self) {
-        mutate_state(|s| {
-            // Only remove if we're specifically in the "completed" state,
-            // otherwise keep for potential error recovery
-            if let Some(state) = s.operation_states.get(&self.operation_id) {
-                if *state == OperationState::Completed {
-                    s.operation_guards.remove(&self.operation_id);
-                    s.operation_guard_timestamps.remove(&self.operation_id);
-                    s.operation_states.remove(&self.operation_id);
-                    s.operation_details.remove(&self.operation_id);
-                    log!(crate::INFO, 
-                        "[guard] Cleaned up completed operation {}", 
-                        self.operation_id
-                    );
-                } else {
-                    log!(crate::INFO, 
-                        "[guard] Operation {} dropped but not removed (state: {:?})", 
-                        self.operation_id, state
-                    );
-                }
-            } else {
-                // If no state exists (odd case), do full cleanup
-                s.operation_guards.remove(&self.operation_id);
-                s.operation_guard_timestamps.remove(&self.operation_id);
-                s.operation_details.remove(&self.operation_id);
-                log!(crate::INFO, 
-                    "[guard] Operation {} dropped with no state, cleaned up", 
-                    self.operation_id
-                );
-            }
-        });
+        mutate_state(|s| s.release_locks(&self.keys, self.holder, self.acquired_at));
     }
 }
 
@@ -263,3 +198,23 @@ impl Drop for FetchXrcGuard {
         });
     }
 }
+
+/// Debt capacity held for a borrow in flight. The capacity is given back when
+/// the reservation is dropped, including when the borrow traps after an await.
+#[must_use]
+pub struct DebtReservation {
+    amount: ICUSD,
+}
+
+impl DebtReservation {
+    pub fn new(collateral_type: CollateralType, amount: ICUSD) -> Result<Self, ProtocolError> {
+        mutate_state(|s| s.reserve_debt(collateral_type, amount))?;
+        Ok(Self { amount })
+    }
+}
+
+impl Drop for DebtReservation {
+    fn drop(&mut self) {
+        mutate_state(|s| s.release_debt_reservation(self.amount));
+    }
+}
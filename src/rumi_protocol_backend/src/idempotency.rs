@@ -0,0 +1,94 @@
+use crate::logs::INFO;
+use crate::state::mutate_state;
+use crate::{ProtocolError, SEC_NANOS};
+use candid::{CandidType, Principal};
+use ic_canister_log::log;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+
+pub const MAX_REQUEST_ID_LENGTH: usize = 64;
+// Completed requests are remembered for a day
+pub const REQUEST_ID_TTL_NANOS: u64 = 24 * 3600 * SEC_NANOS;
+// A request still in flight after this long is assumed to have trapped, its
+// outcome is unknown until the id expires
+pub const IN_FLIGHT_TIMEOUT_NANOS: u64 = 5 * 60 * SEC_NANOS;
+pub const MAX_REMEMBERED_REQUESTS: usize = 10_000;
+
+/// The caller, the method and the request id. Keying on the method keeps a
+/// reused id from replaying the result of another endpoint.
+pub type RequestKey = (Principal, String, String);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestEntry {
+    InFlight { started_at: u64 },
+    /// The candid encoded result of a successful call.
+    Completed { result: Vec<u8>, completed_at: u64 },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestStart {
+    New,
+    InFlight,
+    /// The call trapped and may have partly gone through.
+    Unknown,
+    Completed(Vec<u8>),
+}
+
+/// Runs `operation` at most once per caller, `method` and request id. A replayed id
+/// returns the result of the first successful call; failed calls are
+/// forgotten so that they can be retried. A call that trapped is not run
+/// again under the same id. Request ids are saved in stable memory across
+/// upgrades.
+pub async fn run_once<T, F>(
+    method: &'static str,
+    request_id: Option<String>,
+    operation: F,
+) -> Result<T, ProtocolError>
+where
+    T: CandidType + DeserializeOwned,
+    F: Future<Output = Result<T, ProtocolError>>,
+{
+    let request_id = match request_id {
+        Some(request_id) => request_id,
+        None => return operation.await,
+    };
+    if request_id.is_empty() || request_id.len() > MAX_REQUEST_ID_LENGTH {
+        return Err(ProtocolError::GenericError(format!(
+            "request id must be between 1 and {} bytes",
+            MAX_REQUEST_ID_LENGTH
+        )));
+    }
+
+    let key = (ic_cdk::caller(), method.to_string(), request_id);
+    match mutate_state(|s| s.begin_request(key.clone(), ic_cdk::api::time())) {
+        RequestStart::New => {}
+        RequestStart::InFlight => return Err(ProtocolError::AlreadyProcessing),
+        RequestStart::Unknown => {
+            return Err(ProtocolError::GenericError(format!(
+                "the outcome of request {} is unknown, check your vaults before retrying with a new id",
+                key.2
+            )))
+        }
+        RequestStart::Completed(result) => {
+            log!(INFO, "[run_once] Replaying {} request {} of {}", key.1, key.2, key.0);
+            return candid::decode_one(&result).map_err(|err| {
+                ProtocolError::GenericError(format!(
+                    "failed to decode the result of request {}: {err}",
+                    key.2
+                ))
+            });
+        }
+    }
+
+    let result = operation.await;
+    mutate_state(|s| match &result {
+        Ok(value) => s.complete_request(
+            key,
+            candid::encode_one(value).expect("failed to encode result"),
+            ic_cdk::api::time(),
+        ),
+        Err(_) => s.forget_request(&key),
+    });
+    result
+}
@@ -10,14 +10,14 @@ use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
 use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 use icrc_ledger_types::icrc3::transactions::{Approve, Burn, Mint, Transaction, Transfer};
 use std::cell::RefCell;
-use crate::state::PendingMarginTransfer;
+use crate::state::{PendingMarginTransfer, PendingTransfer};
 
-use crate::event::{record_liquidate_vault, record_redistribute_vault};
+use crate::event::{record_liquidate_vault, record_redistribute_vault, Event};
 use crate::guard::GuardError;
 use crate::logs::{DEBUG, INFO};
 use crate::numeric::{Ratio, ICUSD, ICP, UsdIcp};
-use crate::state::{mutate_state, read_state, Mode};
-use crate::vault::Vault;
+use crate::state::{mutate_state, read_state, CollateralType, Mode};
+use crate::vault::{CandidVault, Vault};
 use candid::{CandidType, Deserialize, Principal};
 use ic_canister_log::log;
 use rust_decimal::Decimal;
@@ -26,11 +26,14 @@ use rust_decimal_macros::dec;
 
 pub mod dashboard;
 pub mod event;
+pub mod flash_mint;
 pub mod guard;
+pub mod idempotency;
 pub mod liquidity_pool;
 pub mod logs;
 pub mod management;
 pub mod numeric;
+pub mod saga;
 pub mod state;
 pub mod storage;
 pub mod vault;
@@ -48,11 +51,30 @@ pub const E8S: u64 = 100_000_000;
 pub const MIN_LIQUIDITY_AMOUNT: ICUSD = ICUSD::new(1_000_000_000);
 pub const MIN_ICP_AMOUNT: ICP = ICP::new(100_000);  // Instead of MIN_CKBTC_AMOUNT
 pub const MIN_ICUSD_AMOUNT: ICUSD = ICUSD::new(10_000_000); // 0.10 icUSD (10 cents)
+// Smallest debt a vault can carry, excluding the liquidation reserve
+pub const MIN_NET_DEBT: ICUSD = ICUSD::new(1_000_000_000); // 10 icUSD
+// Added to a vault's debt when it starts borrowing, paid to whoever liquidates it
+pub const LIQUIDATION_RESERVE: ICUSD = ICUSD::new(200_000_000); // 2 icUSD
 
 // Update collateral ratios per whitepaper
 pub const RECOVERY_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.5));  // 150%
 pub const MINIMUM_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.33));  // 133%
 
+// Mode thresholds, exits sit above entries so the mode doesn't flap around a boundary
+pub const RECOVERY_EXIT_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.55));  // 155%
+pub const READ_ONLY_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.0));  // 100%
+pub const READ_ONLY_EXIT_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.05));  // 105%
+pub const MIN_ICP_RATE: UsdIcp = UsdIcp::new(dec!(0.01));
+
+// Borrower-chosen annual interest rates
+pub const MIN_INTEREST_RATE: Ratio = Ratio::new(dec!(0.005));  // 0.5%
+pub const MAX_INTEREST_RATE: Ratio = Ratio::new(dec!(2.5));  // 250%
+pub const YEAR_NANOS: u64 = 365 * 24 * 3600 * SEC_NANOS;
+/// Adjusting the rate again within this window costs this much interest up front.
+pub const INTEREST_RATE_ADJUSTMENT_COOLDOWN_NANOS: u64 = 7 * 24 * 3600 * SEC_NANOS;
+// The ledger deduplicates transfers created in the last 24 hours, keep a margin
+pub const TRANSFER_DEDUP_WINDOW_NANOS: u64 = 23 * 3600 * SEC_NANOS;
+
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ProtocolArg {
@@ -74,6 +96,10 @@ pub struct InitArg {
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UpgradeArg {
     pub mode: Option<Mode>,
+    /// Moves the vaults opened before interest rates existed from 0% to
+    /// `MIN_INTEREST_RATE`, which starts charging their owners interest.
+    #[serde(default)]
+    pub migrate_legacy_interest_rates: Option<bool>,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -84,12 +110,93 @@ pub struct ProtocolStatus {
     pub total_icusd_borrowed: u64,
     pub total_collateral_ratio: f64,
     pub mode: Mode,
+    /// Seconds until redemptions open again, zero outside a bootstrap period.
+    pub redemption_bootstrap_remaining_secs: u64,
+    pub min_vault_age_for_redemption_secs: u64,
+    pub cumulative_bad_debt: u64,
+    pub outstanding_bad_debt: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct CollateralDebtCeiling {
+    pub collateral_type: CollateralType,
+    pub ceiling: Option<u64>,
+    pub debt: u64,
+    pub remaining_capacity: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct DebtCeilingStatus {
+    pub global_ceiling: Option<u64>,
+    pub total_debt: u64,
+    pub reserved_debt: u64,
+    pub collaterals: Vec<CollateralDebtCeiling>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RedeemedVault {
+    pub vault_id: u64,
+    pub icusd_amount: u64,
+    pub icp_amount: u64,
+}
+
+/// Outcome a redemption would have if it executed now.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RedemptionPreview {
+    pub vaults: Vec<RedeemedVault>,
+    pub icp_amount: u64,
+    pub fee_amount: u64,
+    pub fee_rate: f64,
+    pub resulting_base_rate: f64,
+}
+
+/// Effect a change of the ICP price would have on the vaults.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct StressTestResult {
+    pub price_change_pct: f64,
+    pub icp_rate: f64,
+    pub liquidatable_vault_count: u64,
+    pub liquidatable_debt: u64,
+    pub total_collateral_ratio: f64,
+    pub mode: Mode,
+    /// icUSD deposited in the stability pool, if it could be queried.
+    pub stability_pool_deposits: Option<u64>,
+    pub covered_by_stability_pool: Option<bool>,
+}
+
+/// ICP owed to a redeemer, keyed by the block index of the icUSD they burned.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct PendingRedemptionTransfer {
+    pub icusd_block_index: u64,
+    pub margin: u64,
+    pub created_at: u64,
+}
+
+/// Everything the protocol holds for or owes to a principal.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct AccountOverview {
+    pub vaults: Vec<CandidVault>,
+    pub liquidity: LiquidityStatus,
+    pub redemption_rebates: Vec<RedemptionRebateEntry>,
+    pub pending_transfers: Vec<PendingTransfer>,
+    pub pending_redemption_transfers: Vec<PendingRedemptionTransfer>,
+    /// The most recent events of the principal and of the vaults it opened,
+    /// oldest first. Only the last `MAX_ACCOUNT_EVENTS_SCANNED` events of the
+    /// log are searched.
+    pub recent_events: Vec<Event>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RedemptionRebateEntry {
+    pub vault_id: u64,
+    pub amount: u64,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
 pub struct Fees {
     pub borrowing_fee: f64,
     pub redemption_fee: f64,
+    pub base_rate: f64,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -122,9 +229,57 @@ pub enum ProtocolError {
     AnonymousCallerNotAllowed,
     CallerNotOwner,
     AmountTooLow { minimum_amount: u64 },
+    RecoveryModeViolation(RecoveryModeViolation),
+    DebtCeilingReached { remaining_capacity: u64 },
+    RedemptionLimitExceeded { fee_rate: f64, icp_out: u64 },
+    DeadlineExpired,
+    // The variants below are only returned to callers that opted into them,
+    // see `into_legacy`.
+    VaultNotFound { vault_id: u64 },
+    NotLiquidatable { ratio: f64, minimum: f64 },
+    /// The operation would leave the vault below the required collateral ratio.
+    CollateralRatioTooLow { resulting: f64, required: f64 },
+    OperationPaused { reason: String },
+    /// The last known ICP price is `age` seconds old.
+    StalePrice { age: u64 },
     GenericError(String),
 }
 
+/// The Recovery mode rule an operation would have broken.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub enum RecoveryModeViolation {
+    /// The vault would be left below the recovery collateral ratio.
+    VaultBelowRecoveryRatio { vault_ratio: f64, required_ratio: f64 },
+    /// The operation would lower the total collateral ratio.
+    TotalCollateralRatioDecreased { current_ratio: f64, resulting_ratio: f64 },
+}
+
+impl ProtocolError {
+    /// The error as it was returned before the typed variants were added.
+    /// Clients built against that interface can't decode the typed variants,
+    /// and some match on these messages.
+    pub fn into_legacy(self) -> Self {
+        match self {
+            Self::VaultNotFound { vault_id } => {
+                Self::GenericError(format!("Vault #{} not found", vault_id))
+            }
+            Self::NotLiquidatable { ratio, minimum } => Self::GenericError(format!(
+                "Vault is not liquidatable. Current ratio: {}, minimum: {}",
+                ratio, minimum
+            )),
+            Self::CollateralRatioTooLow { resulting, required } => Self::GenericError(format!(
+                "the operation would leave the vault at a collateral ratio of {}, below the minimum of {}",
+                resulting, required
+            )),
+            Self::OperationPaused { reason } => Self::TemporarilyUnavailable(reason),
+            Self::StalePrice { .. } => {
+                Self::TemporarilyUnavailable("Last known ICP price too old".to_string())
+            }
+            error => error,
+        }
+    }
+}
+
 impl From<GuardError> for ProtocolError {
     fn from(e: GuardError) -> Self {
         match e {
@@ -132,9 +287,10 @@ impl From<GuardError> for ProtocolError {
             GuardError::TooManyConcurrentRequests => {
                 Self::TemporarilyUnavailable("too many concurrent requests".to_string())
             },
-            GuardError::StaleOperation => {
-                Self::TemporarilyUnavailable("previous operation is being cleaned up".to_string())
-            }
+            GuardError::VaultLocked(vault_id) => Self::TemporarilyUnavailable(format!(
+                "vault #{} is locked by another operation",
+                vault_id
+            )),
         }
     }
 }
@@ -204,7 +360,97 @@ pub fn compute_collateral_ratio(vault: &Vault, icp_rate: UsdIcp) -> Ratio {
     margin_value / vault.borrowed_icusd_amount
 }
 
-pub(crate) async fn process_pending_transfer() {
+/// Returns the ICP price below which `vault` falls under `minimum_ratio`, or
+/// `None` if the vault has no debt.
+pub fn compute_liquidation_price(vault: &Vault, minimum_ratio: Ratio) -> Option<UsdIcp> {
+    if vault.borrowed_icusd_amount == 0 {
+        return None;
+    }
+    if vault.icp_margin_amount == 0 {
+        return Some(UsdIcp::from(Decimal::MAX));
+    }
+    let price: Ratio =
+        (vault.borrowed_icusd_amount * minimum_ratio) / ICUSD::from(vault.icp_margin_amount);
+    Some(UsdIcp::from(price.0))
+}
+
+/// Whether the ledger no longer deduplicates a transfer created at
+/// `created_at`. Transfers queued before they had a creation time count as
+/// expired.
+pub fn dedup_window_expired(created_at: u64, now: u64) -> bool {
+    created_at == 0 || now.saturating_sub(created_at) >= TRANSFER_DEDUP_WINDOW_NANOS
+}
+
+/// Returns the `created_at_time` to send for a transfer queued at `created_at`,
+/// or `None` once the ledger would reject it as too old to deduplicate.
+pub(crate) fn dedup_created_at_time(created_at: u64) -> Option<u64> {
+    if dedup_window_expired(created_at, ic_cdk::api::time()) {
+        None
+    } else {
+        Some(created_at)
+    }
+}
+
+pub(crate) fn transfer_memo(prefix: &[u8], id: u64) -> Vec<u8> {
+    let mut memo = prefix.to_vec();
+    memo.extend_from_slice(&id.to_be_bytes());
+    memo
+}
+
+/// Makes a queued ICP transfer. Every attempt carries the same amount,
+/// `created_at_time` and memo, so a retry after an ambiguous failure can't pay
+/// out twice. Once the ledger no longer deduplicates it, the transfer is not
+/// attempted and returns `TooOld`: it may already have gone through, so it
+/// has to be resolved by hand.
+pub async fn execute_pending_transfer(transfer: &PendingTransfer) -> Result<u64, TransferError> {
+    let created_at_time = match dedup_created_at_time(transfer.created_at) {
+        Some(created_at_time) => created_at_time,
+        None => return Err(TransferError::TooOld),
+    };
+    crate::management::transfer_icp_once(
+        transfer.amount - transfer.fee,
+        transfer.destination,
+        Some(created_at_time),
+        transfer_memo(b"rumi/transfer/", transfer.id),
+    )
+    .await
+}
+
+/// Drops `transfer` if it can't cover its ledger fee, since the ledger
+/// would never accept it. Returns whether it was dropped.
+pub fn drop_transfer_if_dust(transfer: &PendingTransfer) -> bool {
+    if transfer.amount > transfer.fee {
+        return false;
+    }
+    log!(
+        INFO,
+        "[drop_transfer_if_dust] Dropping transfer {} of {} ICP to {}, below the ledger fee",
+        transfer.id,
+        transfer.amount.to_u64(),
+        transfer.destination
+    );
+    mutate_state(|s| crate::event::record_transfer_dropped(s, transfer.id));
+    true
+}
+
+async fn execute_redemption_transfer(
+    icusd_block_index: u64,
+    transfer: &PendingMarginTransfer,
+) -> Result<u64, TransferError> {
+    let created_at_time = match dedup_created_at_time(transfer.created_at) {
+        Some(created_at_time) => created_at_time,
+        None => return Err(TransferError::TooOld),
+    };
+    crate::management::transfer_icp_once(
+        transfer.margin - transfer.fee,
+        transfer.owner,
+        Some(created_at_time),
+        transfer_memo(b"rumi/redemption/", icusd_block_index),
+    )
+    .await
+}
+
+pub async fn process_pending_transfer() {
     let _guard = match crate::guard::TimerLogicGuard::new() {
         Some(guard) => guard,
         None => {
@@ -213,46 +459,48 @@ pub(crate) async fn process_pending_transfer() {
         }
     };
 
-    // Process pending margin transfers
+    // Process pending margin transfers. Those the ledger no longer
+    // deduplicates are left for the developer to resolve.
+    let now = ic_cdk::api::time();
     let pending_transfers = read_state(|s| {
         // Log for visibility
-        if !s.pending_margin_transfers.is_empty() {
+        if !s.pending_transfers.is_empty() {
             log!(INFO, "[process_pending_transfer] Found {} pending margin transfers", 
-                 s.pending_margin_transfers.len());
+                 s.pending_transfers.len());
         }
         
-        s.pending_margin_transfers
-            .iter()
-            .map(|(vault_id, margin_transfer)| (*vault_id, *margin_transfer))
-            .collect::<Vec<(u64, PendingMarginTransfer)>>()
+        s.pending_transfers
+            .values()
+            .filter(|transfer| !dedup_window_expired(transfer.created_at, now))
+            .cloned()
+            .collect::<Vec<PendingTransfer>>()
     });
-    let icp_transfer_fee = read_state(|s| s.icp_ledger_fee);
     
-    for (vault_id, transfer) in pending_transfers {
-        match crate::management::transfer_icp(
-            transfer.margin - icp_transfer_fee,
-            transfer.owner,
-        )
-        .await
-        {
+    for transfer in pending_transfers {
+        if drop_transfer_if_dust(&transfer) {
+            continue;
+        }
+        match execute_pending_transfer(&transfer).await {
             Ok(block_index) => {
                 log!(
                     INFO,
                     "[transfering_margins] successfully transferred: {} to {}",
-                    transfer.margin,
-                    transfer.owner
+                    transfer.amount,
+                    transfer.destination
                 );
-                mutate_state(|s| crate::event::record_margin_transfer(s, vault_id, block_index));
+                mutate_state(|s| crate::event::record_margin_transfer(s, transfer.id, block_index));
+                crate::vault::notify_treasury_of_transfer(&transfer, block_index);
             }
             Err(error) => {
                 // Improved error logging with more details
                 log!(
                     DEBUG,
                     "[transfering_margins] failed to transfer margin: {}, to principal: {}, with error: {}",
-                    transfer.margin,
-                    transfer.owner,
+                    transfer.amount,
+                    transfer.destination,
                     error
                 );
+                mutate_state(|s| s.record_transfer_failure(transfer.id, error.to_string()));
                 
                 // If there was a transfer fee error, update the fee
                 if let TransferError::BadFee { expected_fee } = error {
@@ -262,10 +510,11 @@ pub(crate) async fn process_pending_transfer() {
                             .0
                             .try_into()
                             .expect("failed to convert Nat to u64");
-                        s.icp_ledger_fee = ICP::from(expected_fee);
+                        crate::event::record_set_icp_ledger_fee(s, ICP::from(expected_fee));
                     });
                     
-                    // After updating the fee, we should retry this transfer next time
+                    // Queued transfers keep their fee, so that retries stay
+                    // deduplicated; this one now needs to be resolved by hand
                 } else {
                     // For other errors, we still keep the transfer pending for retry
                     log!(INFO, "[transfering_margins] Will retry this transfer later");
@@ -278,17 +527,13 @@ pub(crate) async fn process_pending_transfer() {
     let pending_redemptions = read_state(|s| {
         s.pending_redemption_transfer
             .iter()
+            .filter(|(_, margin_transfer)| !dedup_window_expired(margin_transfer.created_at, now))
             .map(|(icusd_block_index, margin_transfer)| (*icusd_block_index, *margin_transfer))
             .collect::<Vec<(u64, PendingMarginTransfer)>>()
     });
 
     for (icusd_block_index, pending_transfer) in pending_redemptions {
-        match crate::management::transfer_icp(
-            pending_transfer.margin - icp_transfer_fee,
-            pending_transfer.owner,
-        )
-        .await
-        {
+        match execute_redemption_transfer(icusd_block_index, &pending_transfer).await {
             Ok(block_index) => {
                 log!(
                     INFO,
@@ -310,8 +555,15 @@ pub(crate) async fn process_pending_transfer() {
     }
 
     // Schedule another run if needed, but with better timing
+    let now = ic_cdk::api::time();
     if read_state(|s| {
-        !s.pending_margin_transfers.is_empty() || !s.pending_redemption_transfer.is_empty()
+        s.pending_transfers
+            .values()
+            .any(|transfer| !dedup_window_expired(transfer.created_at, now))
+            || s
+                .pending_redemption_transfer
+                .values()
+                .any(|transfer| !dedup_window_expired(transfer.created_at, now))
     }) {
         // Schedule another check in 5 seconds
         log!(INFO, "[process_pending_transfer] Scheduling another transfer attempt in 5 seconds");
@@ -95,7 +95,7 @@ pub async fn claim_liquidity_returns() -> Result<u64, ProtocolError> {
                         .0
                         .try_into()
                         .expect("failed to convert Nat to u64");
-                    s.icp_ledger_fee = ICP::from(expected_fee);
+                    crate::event::record_set_icp_ledger_fee(s, ICP::from(expected_fee));
                 });
             };
             Err(ProtocolError::TransferError(transfer_error))
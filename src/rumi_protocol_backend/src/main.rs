@@ -1,14 +1,26 @@
 use candid::{candid_method, Principal};
 use ic_canister_log::log;
 use ic_canisters_http_types::{HttpRequest, HttpResponse, HttpResponseBuilder};
-use ic_cdk_macros::{init, post_upgrade, query, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 use rumi_protocol_backend::{
     event::Event,
+    flash_mint::{FlashMintArg, FlashMintSuccess},
+    guard::Lock,
+    idempotency::run_once,
+    saga::Saga,
     logs::INFO,
     numeric::{ICUSD, UsdIcp, Ratio},
-    state::{read_state, replace_state, Mode, State},
-    vault::{CandidVault, OpenVaultSuccess, VaultArg},
-    Fees, GetEventsArg, ProtocolArg, ProtocolError, ProtocolStatus, SuccessWithFee,
+    state::{
+        read_state, replace_state, BadDebtPolicy, CollateralType, Mode, ModeTransition, PendingTransfer,
+        State, MAX_ACCOUNT_EVENTS_SCANNED,
+    },
+    vault::{
+        AdjustInterestRateArg, CandidVault, LiquidateVaultsSuccess, LiquidationSimulation,
+        ListVaultsArg, ListVaultsResponse, OpenVaultSuccess, RedeemWithLimitsArg, VaultArg,
+        VaultSimulation,
+    },
+    AccountOverview, CollateralDebtCeiling, DebtCeilingStatus, Fees, GetEventsArg, ProtocolArg, ProtocolError, ProtocolStatus,
+    RedemptionPreview, RedemptionRebateEntry, StressTestResult, SuccessWithFee, MIN_ICUSD_AMOUNT, SEC_NANOS,
 };
 use rumi_protocol_backend::logs::DEBUG;
 use rumi_protocol_backend::state::mutate_state;
@@ -18,7 +30,7 @@ use rust_decimal::prelude::FromPrimitive;
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use rumi_protocol_backend::storage::events;
+use rumi_protocol_backend::storage::{events, latest_events};
 use rumi_protocol_backend::LiquidityStatus;
 use candid_parser::utils::CandidSource;
 use candid_parser::utils::service_equal;
@@ -42,6 +54,12 @@ pub struct StabilityPoolConfig {
     pub enabled: bool,
 }
 
+/// The part of the stability pool's `PoolInfo` read by the stress test.
+#[derive(candid::CandidType, serde::Deserialize, Clone, Debug)]
+struct StabilityPoolInfo {
+    total_icusd_deposited: u64,
+}
+
 #[cfg(feature = "self_check")]
 fn ok_or_die(result: Result<(), String>) {
     if let Err(msg) = result {
@@ -79,19 +97,30 @@ fn check_postcondition<T>(t: T) -> T {
     t
 }
 
+/// Returns the typed error variants only to callers that enabled them,
+/// others get the errors they got before.
+fn for_caller(error: ProtocolError) -> ProtocolError {
+    let caller = ic_cdk::caller();
+    if read_state(|s| s.typed_error_callers.contains(&caller)) {
+        error
+    } else {
+        error.into_legacy()
+    }
+}
+
 fn validate_call() -> Result<(), ProtocolError> {
     if ic_cdk::caller() == Principal::anonymous() {
         return Err(ProtocolError::AnonymousCallerNotAllowed);
     }
-    read_state(|s| s.check_price_not_too_old())
+    read_state(|s| s.check_price_not_too_old()).map_err(for_caller)
 }
 
 fn validate_mode() -> Result<(), ProtocolError> {
     match read_state(|s| s.mode) {
         Mode::ReadOnly => {
-            Err(ProtocolError::TemporarilyUnavailable(
-                "protocol temporarly unavailable, please wait for an upgrade or for total collateral ratio to go above 100%".to_string(),
-            ))
+            Err(for_caller(ProtocolError::OperationPaused {
+                reason: "protocol temporarly unavailable, please wait for an upgrade or for total collateral ratio to go above 100%".to_string(),
+            }))
         }
         Mode::GeneralAvailability => Ok(()),
         Mode::Recovery => Ok(())
@@ -102,6 +131,15 @@ fn setup_timers() {
     ic_cdk_timers::set_timer_interval(rumi_protocol_backend::xrc::FETCHING_ICP_RATE_INTERVAL, || {
         ic_cdk::spawn(rumi_protocol_backend::xrc::fetch_icp_rate())
     });
+    ic_cdk_timers::set_timer_interval(
+        rumi_protocol_backend::vault::INTEREST_ACCRUAL_INTERVAL,
+        rumi_protocol_backend::vault::accrue_interest,
+    );
+    ic_cdk_timers::set_timer_interval(rumi_protocol_backend::saga::SAGA_RESUME_INTERVAL, || {
+        ic_cdk::spawn(rumi_protocol_backend::saga::resume_sagas(
+            rumi_protocol_backend::saga::SAGA_RESUME_DELAY_NANOS,
+        ))
+    });
 }
 
 fn main() {}
@@ -124,26 +162,29 @@ fn init(arg: ProtocolArg) {
     setup_timers();
 }
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    read_state(|s| {
+        rumi_protocol_backend::storage::save_recent_requests(&s.recent_requests);
+        rumi_protocol_backend::storage::save_typed_error_callers(&s.typed_error_callers);
+    });
+}
+
 #[post_upgrade]
 fn post_upgrade(arg: ProtocolArg) {
-    use rumi_protocol_backend::event::replay;
-    use rumi_protocol_backend::storage::{count_events, events, record_event};
+    use rumi_protocol_backend::event::{record_upgrade, replay};
+    use rumi_protocol_backend::storage::{
+        count_events, events, take_recent_requests, take_typed_error_callers,
+    };
 
     let start = ic_cdk::api::instruction_counter();
 
-    log!(INFO, "[upgrade]: replaying {} events", count_events());
-
-    match arg {
+    let upgrade_args = match arg {
         ProtocolArg::Init(_) => ic_cdk::trap("expected Upgrade got Init"),
-        ProtocolArg::Upgrade(upgrade_args) => {
-            log!(
-                INFO,
-                "[upgrade]: updating configuration with {:?}",
-                upgrade_args
-            );
-            record_event(&Event::Upgrade(upgrade_args));
-        }
-    }
+        ProtocolArg::Upgrade(upgrade_args) => upgrade_args,
+    };
+
+    log!(INFO, "[upgrade]: replaying {} events", count_events());
 
     let state = replay(events()).unwrap_or_else(|e| {
         ic_cdk::trap(&format!(
@@ -153,6 +194,17 @@ fn post_upgrade(arg: ProtocolArg) {
     });
 
     replace_state(state);
+    mutate_state(|s| {
+        s.recent_requests = take_recent_requests();
+        s.typed_error_callers = take_typed_error_callers();
+    });
+
+    log!(
+        INFO,
+        "[upgrade]: updating configuration with {:?}",
+        upgrade_args
+    );
+    mutate_state(|s| record_upgrade(s, upgrade_args, ic_cdk::api::time()));
 
     let end = ic_cdk::api::instruction_counter();
 
@@ -163,6 +215,11 @@ fn post_upgrade(arg: ProtocolArg) {
     );
 
     setup_timers();
+
+    // No call is in flight after an upgrade, resume every pending saga
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(rumi_protocol_backend::saga::resume_sagas(0))
+    });
 }
 
 #[candid_method(query)]
@@ -178,6 +235,13 @@ fn get_protocol_status() -> ProtocolStatus {
         total_icusd_borrowed: s.total_borrowed_icusd_amount().to_u64(),
         total_collateral_ratio: s.total_collateral_ratio.to_f64(),
         mode: s.mode,
+        redemption_bootstrap_remaining_secs: s
+            .redemption_bootstrap_end
+            .saturating_sub(ic_cdk::api::time())
+            / SEC_NANOS,
+        min_vault_age_for_redemption_secs: s.min_vault_age_for_redemption / SEC_NANOS,
+        cumulative_bad_debt: s.cumulative_bad_debt.to_u64(),
+        outstanding_bad_debt: s.outstanding_bad_debt.to_u64(),
     })
 }
 
@@ -187,9 +251,16 @@ fn get_fees(redeemed_amount: u64) -> Fees {
     read_state(|s| Fees {
         borrowing_fee: s.get_borrowing_fee().to_f64(),
         redemption_fee: s.get_redemption_fee(redeemed_amount.into()).to_f64(),
+        base_rate: s.get_decayed_base_rate().to_f64(),
     })
 }
 
+#[candid_method(query)]
+#[query]
+fn get_mode_history() -> Vec<ModeTransition> {
+    read_state(|s| s.mode_history.clone())
+}
+
 #[candid_method(query)]
 #[query]
 fn get_vault_history(vault_id: u64) -> Vec<Event> {
@@ -223,21 +294,7 @@ fn get_events(args: GetEventsArg) -> Vec<Event> {
 #[candid_method(query)]
 #[query]
 fn get_liquidity_status(owner: Principal) -> LiquidityStatus {
-    let total_liquidity_provided = read_state(|s| s.total_provided_liquidity_amount());
-    let liquidity_pool_share = if total_liquidity_provided == 0 {
-        0.0
-    } else {
-        read_state(|s| {
-            (s.get_provided_liquidity(owner) / s.total_provided_liquidity_amount()).to_f64()
-        })
-    };
-    read_state(|s| LiquidityStatus {
-        liquidity_provided: s.get_provided_liquidity(owner).to_u64(),
-        total_liquidity_provided: s.total_provided_liquidity_amount().to_u64(),
-        liquidity_pool_share,
-        available_liquidity_reward: s.get_liquidity_returns_of(owner).to_u64(),
-        total_available_returns: s.total_available_returns().to_u64(),
-    })
+    read_state(|s| s.get_liquidity_status_of(owner))
 }
 
 #[candid_method(query)]
@@ -249,12 +306,9 @@ fn get_vaults(target: Option<Principal>) -> Vec<CandidVault> {
                 .iter()
                 .filter_map(|id| {
                     // Use filter_map with proper error handling instead of unwrap
-                    s.vault_id_to_vaults.get(id).map(|vault| CandidVault {
-                        owner: vault.owner,
-                        borrowed_icusd_amount: vault.borrowed_icusd_amount.to_u64(),
-                        icp_margin_amount: vault.icp_margin_amount.to_u64(),
-                        vault_id: vault.vault_id,
-                    })
+                    s.vault_id_to_vaults
+                        .get(id)
+                        .map(|vault| CandidVault::with_health(vault.clone(), s))
                 })
                 .collect(),
             None => vec![],
@@ -262,23 +316,166 @@ fn get_vaults(target: Option<Principal>) -> Vec<CandidVault> {
         None => read_state(|s| {
             s.vault_id_to_vaults
                 .values()
-                .map(|vault| CandidVault {
-                    owner: vault.owner,
-                    borrowed_icusd_amount: vault.borrowed_icusd_amount.to_u64(),
-                    icp_margin_amount: vault.icp_margin_amount.to_u64(),
-                    vault_id: vault.vault_id,
-                })
+                .map(|vault| CandidVault::with_health(vault.clone(), s))
                 .collect::<Vec<CandidVault>>()
         }),
     }
 }
 
+#[candid_method(query)]
+#[query]
+fn list_vaults(arg: ListVaultsArg) -> Result<ListVaultsResponse, ProtocolError> {
+    read_state(|s| rumi_protocol_backend::vault::list_vaults(s, arg)).map_err(for_caller)
+}
+
+#[candid_method(query)]
+#[query]
+fn get_vault(vault_id: u64) -> Option<CandidVault> {
+    read_state(|s| {
+        s.vault_id_to_vaults
+            .get(&vault_id)
+            .map(|vault| CandidVault::with_health(vault.clone(), s))
+    })
+}
+
 // Vault related operations
 #[candid_method(update)]
 #[update]
-async fn redeem_icp(icusd_amount: u64) -> Result<SuccessWithFee, ProtocolError> {
+async fn redeem_icp(
+    icusd_amount: u64,
+    request_id: Option<String>,
+) -> Result<SuccessWithFee, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::vault::redeem_icp(icusd_amount).await)
+    check_postcondition(
+        run_once(
+            "redeem_icp",
+            request_id,
+            rumi_protocol_backend::vault::redeem_icp(icusd_amount),
+        )
+        .await,
+    )
+    .map_err(for_caller)
+}
+
+#[candid_method(update)]
+#[update]
+async fn redeem_icp_with_limits(
+    arg: RedeemWithLimitsArg,
+    request_id: Option<String>,
+) -> Result<SuccessWithFee, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        run_once(
+            "redeem_icp_with_limits",
+            request_id,
+            rumi_protocol_backend::vault::redeem_icp_with_limits(arg),
+        )
+        .await,
+    )
+    .map_err(for_caller)
+}
+
+#[candid_method(query)]
+#[query]
+fn preview_redemption(icusd_amount: u64) -> Result<RedemptionPreview, ProtocolError> {
+    let icusd_amount = ICUSD::from(icusd_amount);
+    if icusd_amount < MIN_ICUSD_AMOUNT {
+        return Err(ProtocolError::AmountTooLow {
+            minimum_amount: MIN_ICUSD_AMOUNT.to_u64(),
+        });
+    }
+    read_state(|s| match s.last_icp_rate {
+        Some(rate) => s.preview_redemption(icusd_amount, rate, ic_cdk::api::time()),
+        None => Err(ProtocolError::TemporarilyUnavailable(
+            "no ICP rate available".to_string(),
+        )),
+    })
+    .map_err(for_caller)
+}
+
+#[candid_method(composite_query)]
+#[query(composite = true)]
+async fn stress_test(price_change_pct: f64) -> Result<StressTestResult, ProtocolError> {
+    let mut result = read_state(|s| s.stress_test(price_change_pct)).map_err(for_caller)?;
+    if let Some(stability_pool) = read_state(|s| s.stability_pool_canister) {
+        match ic_cdk::call::<(), (StabilityPoolInfo,)>(stability_pool, "get_total_pool_info", ())
+            .await
+        {
+            Ok((info,)) => {
+                result.stability_pool_deposits = Some(info.total_icusd_deposited);
+                result.covered_by_stability_pool =
+                    Some(info.total_icusd_deposited >= result.liquidatable_debt);
+            }
+            Err((code, msg)) => {
+                log!(INFO, "[stress_test] Failed to query the stability pool: {:?} {}", code, msg);
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Simulations run the validation of the corresponding update without executing it
+
+#[candid_method(query)]
+#[query]
+fn simulate_borrow(arg: VaultArg) -> Result<VaultSimulation, ProtocolError> {
+    validate_call()?;
+    validate_mode()?;
+    read_state(|s| rumi_protocol_backend::vault::simulate_borrow(s, ic_cdk::caller(), arg))
+        .map_err(for_caller)
+}
+
+#[candid_method(query)]
+#[query]
+fn simulate_repay(arg: VaultArg) -> Result<VaultSimulation, ProtocolError> {
+    validate_call()?;
+    read_state(|s| rumi_protocol_backend::vault::simulate_repay(s, ic_cdk::caller(), arg))
+        .map_err(for_caller)
+}
+
+#[candid_method(query)]
+#[query]
+fn simulate_withdraw(vault_id: u64) -> Result<VaultSimulation, ProtocolError> {
+    validate_call()?;
+    read_state(|s| rumi_protocol_backend::vault::simulate_withdraw(s, ic_cdk::caller(), vault_id))
+        .map_err(for_caller)
+}
+
+#[candid_method(query)]
+#[query]
+fn simulate_liquidation(vault_id: u64) -> Result<LiquidationSimulation, ProtocolError> {
+    read_state(|s| rumi_protocol_backend::vault::simulate_liquidation(s, vault_id)).map_err(for_caller)
+}
+
+#[candid_method(query)]
+#[query]
+fn simulate_partial_liquidation(
+    vault_id: u64,
+    icusd_amount: u64,
+) -> Result<LiquidationSimulation, ProtocolError> {
+    read_state(|s| {
+        rumi_protocol_backend::vault::simulate_partial_liquidation(s, vault_id, icusd_amount)
+    })
+    .map_err(for_caller)
+}
+
+#[candid_method(update)]
+#[update]
+async fn flash_mint(
+    arg: FlashMintArg,
+    request_id: Option<String>,
+) -> Result<FlashMintSuccess, ProtocolError> {
+    validate_call()?;
+    validate_mode()?;
+    check_postcondition(
+        run_once(
+            "flash_mint",
+            request_id,
+            rumi_protocol_backend::flash_mint::flash_mint(arg),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(query)]
@@ -293,66 +490,199 @@ fn get_redemption_rate() -> f64 {
 
 #[candid_method(update)]
 #[update]
-async fn open_vault(icp_margin: u64) -> Result<OpenVaultSuccess, ProtocolError> {
+async fn open_vault(
+    icp_margin: u64,
+    request_id: Option<String>,
+) -> Result<OpenVaultSuccess, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::vault::open_vault(icp_margin).await)
+    check_postcondition(
+        run_once(
+            "open_vault",
+            request_id,
+            rumi_protocol_backend::vault::open_vault(icp_margin),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(update)]
 #[update]
-async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, ProtocolError> {
+async fn borrow_from_vault(
+    arg: VaultArg,
+    request_id: Option<String>,
+) -> Result<SuccessWithFee, ProtocolError> {
     validate_call()?;
     validate_mode()?;
-    check_postcondition(rumi_protocol_backend::vault::borrow_from_vault(arg).await)
+    check_postcondition(
+        run_once(
+            "borrow_from_vault",
+            request_id,
+            rumi_protocol_backend::vault::borrow_from_vault(arg),
+        )
+        .await,
+    )
+    .map_err(for_caller)
+}
+
+#[candid_method(update)]
+#[update]
+async fn adjust_interest_rate(
+    arg: AdjustInterestRateArg,
+    request_id: Option<String>,
+) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    validate_mode()?;
+    check_postcondition(
+        run_once("adjust_interest_rate", request_id, async {
+            rumi_protocol_backend::vault::adjust_interest_rate(arg)
+        })
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(update)]
 #[update]
-async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
+async fn repay_to_vault(arg: VaultArg, request_id: Option<String>) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::vault::repay_to_vault(arg).await)
+    check_postcondition(
+        run_once(
+            "repay_to_vault",
+            request_id,
+            rumi_protocol_backend::vault::repay_to_vault(arg),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(update)]
 #[update]
-async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
+async fn add_margin_to_vault(
+    arg: VaultArg,
+    request_id: Option<String>,
+) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::vault::add_margin_to_vault(arg).await)
+    check_postcondition(
+        run_once(
+            "add_margin_to_vault",
+            request_id,
+            rumi_protocol_backend::vault::add_margin_to_vault(arg),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(update)]
 #[update]
-async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
+async fn close_vault(
+    vault_id: u64,
+    request_id: Option<String>,
+) -> Result<Option<u64>, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::vault::close_vault(vault_id).await)
+    check_postcondition(
+        run_once(
+            "close_vault",
+            request_id,
+            rumi_protocol_backend::vault::close_vault(vault_id),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 // Add the new withdraw collateral endpoint
 #[candid_method(update)]
 #[update]
-async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
+async fn withdraw_collateral(
+    vault_id: u64,
+    request_id: Option<String>,
+) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::vault::withdraw_collateral(vault_id).await)
+    check_postcondition(
+        run_once(
+            "withdraw_collateral",
+            request_id,
+            rumi_protocol_backend::vault::withdraw_collateral(vault_id),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(update)]
 #[update]
-async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
+async fn withdraw_and_close_vault(
+    vault_id: u64,
+    request_id: Option<String>,
+) -> Result<Option<u64>, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::vault::withdraw_and_close_vault(vault_id).await)
+    check_postcondition(
+        run_once(
+            "withdraw_and_close_vault",
+            request_id,
+            rumi_protocol_backend::vault::withdraw_and_close_vault(vault_id),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 // Add the new liquidate vault endpoints
 #[update]
 #[candid_method(update)]
-async fn liquidate_vault(vault_id: u64) -> Result<SuccessWithFee, ProtocolError> {
-    check_postcondition(rumi_protocol_backend::vault::liquidate_vault(vault_id).await)
+async fn liquidate_vault(
+    vault_id: u64,
+    request_id: Option<String>,
+) -> Result<SuccessWithFee, ProtocolError> {
+    check_postcondition(
+        run_once(
+            "liquidate_vault",
+            request_id,
+            rumi_protocol_backend::vault::liquidate_vault(vault_id),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[update]
 #[candid_method(update)]
-async fn liquidate_vault_partial(vault_id: u64, icusd_amount: u64) -> Result<SuccessWithFee, ProtocolError> {
-    check_postcondition(rumi_protocol_backend::vault::liquidate_vault_partial(vault_id, icusd_amount).await)
+async fn liquidate_vaults(
+    vault_ids: Vec<u64>,
+    max_total_debt: u64,
+    request_id: Option<String>,
+) -> Result<LiquidateVaultsSuccess, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        run_once(
+            "liquidate_vaults",
+            request_id,
+            rumi_protocol_backend::vault::liquidate_vaults(vault_ids, max_total_debt),
+        )
+        .await,
+    )
+    .map_err(for_caller)
+}
+
+#[candid_method(update)]
+#[update]
+async fn liquidate_vault_partial(
+    vault_id: u64,
+    icusd_amount: u64,
+    request_id: Option<String>,
+) -> Result<SuccessWithFee, ProtocolError> {
+    check_postcondition(
+        run_once(
+            "liquidate_vault_partial",
+            request_id,
+            rumi_protocol_backend::vault::liquidate_vault_partial(vault_id, icusd_amount),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 // Stability Pool Integration - allows stability pool to execute liquidations
@@ -368,16 +698,16 @@ async fn stability_pool_liquidate(vault_id: u64, max_debt_to_liquidate: u64) ->
     let (vault, icp_rate, liquidatable_debt, collateral_available) = read_state(|s| {
         match s.vault_id_to_vaults.get(&vault_id) {
             Some(vault) => {
-                let icp_rate = s.last_icp_rate.ok_or("No ICP rate available")?;
+                let icp_rate = s.last_icp_rate.ok_or(ProtocolError::TemporarilyUnavailable(
+                    "No ICP rate available".to_string(),
+                ))?;
                 let ratio = rumi_protocol_backend::compute_collateral_ratio(vault, icp_rate);
                 
                 if ratio >= s.mode.get_minimum_liquidation_collateral_ratio() {
-                    return Err(format!(
-                        "Vault #{} is not liquidatable. Current ratio: {:.2}%, minimum: {:.2}%",
-                        vault_id, 
-                        ratio.to_f64() * 100.0, 
-                        s.mode.get_minimum_liquidation_collateral_ratio().to_f64() * 100.0
-                    ));
+                    return Err(ProtocolError::NotLiquidatable {
+                        ratio: ratio.to_f64(),
+                        minimum: s.mode.get_minimum_liquidation_collateral_ratio().to_f64(),
+                    });
                 }
                 
                 // Calculate how much can be liquidated
@@ -393,16 +723,19 @@ async fn stability_pool_liquidate(vault_id: u64, max_debt_to_liquidate: u64) ->
                 
                 Ok((vault.clone(), icp_rate, actual_liquidatable_debt, collateral_to_seize))
             },
-            None => Err(format!("Vault #{} not found", vault_id)),
+            None => Err(ProtocolError::VaultNotFound { vault_id }),
         }
-    }).map_err(|e| ProtocolError::GenericError(e))?;
+    })
+    .map_err(for_caller)?;
     
     if liquidatable_debt == ICUSD::new(0) {
         return Err(ProtocolError::GenericError("No liquidatable debt available".to_string()));
     }
     
     // Execute the liquidation using existing logic
-    let result = rumi_protocol_backend::vault::liquidate_vault_partial(vault_id, liquidatable_debt.to_u64()).await?;
+    let result = rumi_protocol_backend::vault::liquidate_vault_partial(vault_id, liquidatable_debt.to_u64())
+        .await
+        .map_err(for_caller)?;
     
     // Return structured result for stability pool
     Ok(StabilityPoolLiquidationResult {
@@ -440,6 +773,289 @@ fn set_stability_pool_canister(canister_id: Principal) -> Result<(), ProtocolErr
     })
 }
 
+#[candid_method(query)]
+#[query]
+fn get_debt_ceilings() -> DebtCeilingStatus {
+    read_state(|s| DebtCeilingStatus {
+        global_ceiling: s.global_debt_ceiling.map(|ceiling| ceiling.to_u64()),
+        total_debt: s.total_borrowed_icusd_amount().to_u64(),
+        reserved_debt: s.reserved_debt.to_u64(),
+        collaterals: [CollateralType::ICP]
+            .into_iter()
+            .map(|collateral_type| CollateralDebtCeiling {
+                collateral_type,
+                ceiling: s
+                    .collateral_debt_ceilings
+                    .get(&collateral_type)
+                    .map(|ceiling| ceiling.to_u64()),
+                debt: s.collateral_debt(collateral_type).to_u64(),
+                remaining_capacity: s
+                    .remaining_debt_capacity(collateral_type)
+                    .map(|remaining| remaining.to_u64()),
+            })
+            .collect(),
+    })
+}
+
+// Set the fraction of the liquidation penalty sent to the treasury (developer only)
+#[candid_method(update)]
+#[update]
+fn set_liquidation_protocol_share(share: f64) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can set the liquidation protocol share".to_string()));
+    }
+
+    let share = match Decimal::from_f64(share) {
+        Some(share) if share >= dec!(0) && share <= dec!(1) => Ratio::from(share),
+        _ => return Err(ProtocolError::GenericError("Protocol share must be between 0 and 1".to_string())),
+    };
+
+    mutate_state(|s| {
+        event::record_set_liquidation_protocol_share(s, share);
+    });
+
+    log!(INFO, "[set_liquidation_protocol_share] Liquidation protocol share set to: {}", share.to_f64());
+    Ok(())
+}
+
+// Choose who absorbs bad debt from underwater liquidations (developer only)
+#[candid_method(update)]
+#[update]
+fn set_bad_debt_policy(policy: BadDebtPolicy) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can set the bad debt policy".to_string()));
+    }
+
+    mutate_state(|s| {
+        event::record_set_bad_debt_policy(s, policy);
+    });
+
+    log!(INFO, "[set_bad_debt_policy] Bad debt policy set to: {:?}", policy);
+    Ok(())
+}
+
+#[candid_method(update)]
+#[update]
+async fn cover_bad_debt(amount: u64, request_id: Option<String>) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        run_once(
+            "cover_bad_debt",
+            request_id,
+            rumi_protocol_backend::vault::cover_bad_debt(amount),
+        )
+        .await,
+    )
+    .map_err(for_caller)
+}
+
+// Set the flash mint cap and fee, a zero cap disables flash minting (developer only)
+#[candid_method(update)]
+#[update]
+fn set_flash_mint_params(cap: u64, fee_rate: f64) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can set flash mint parameters".to_string()));
+    }
+
+    let fee = match Decimal::from_f64(fee_rate) {
+        Some(fee) if fee >= dec!(0) && fee < dec!(1) => Ratio::from(fee),
+        _ => return Err(ProtocolError::GenericError("Fee rate must be between 0 and 1".to_string())),
+    };
+
+    mutate_state(|s| {
+        event::record_set_flash_mint_params(s, ICUSD::from(cap), fee);
+    });
+
+    log!(INFO, "[set_flash_mint_params] Flash mint cap set to {} with fee {}", cap, fee_rate);
+    Ok(())
+}
+
+// Allow or disallow a canister to flash mint (developer only)
+#[candid_method(update)]
+#[update]
+fn set_flash_mint_borrower(borrower: Principal, allowed: bool) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can set flash mint borrowers".to_string()));
+    }
+
+    mutate_state(|s| {
+        event::record_set_flash_mint_borrower(s, borrower, allowed);
+    });
+
+    log!(INFO, "[set_flash_mint_borrower] {} allowed to flash mint: {}", borrower, allowed);
+    Ok(())
+}
+
+// Disable redemptions for a while, at launch or when a collateral type is added (developer only)
+#[candid_method(update)]
+#[update]
+fn start_redemption_bootstrap(duration_secs: u64) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can start a redemption bootstrap period".to_string()));
+    }
+
+    let end = ic_cdk::api::time() + duration_secs * SEC_NANOS;
+    mutate_state(|s| {
+        event::record_set_redemption_bootstrap(s, end);
+    });
+
+    log!(INFO, "[start_redemption_bootstrap] Redemptions disabled for {} seconds", duration_secs);
+    Ok(())
+}
+
+// Set how old a vault must be before it can be redeemed against (developer only)
+#[candid_method(update)]
+#[update]
+fn set_min_vault_age_for_redemption(min_age_secs: u64) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can set the minimum vault age".to_string()));
+    }
+
+    mutate_state(|s| {
+        event::record_set_min_vault_age_for_redemption(s, min_age_secs * SEC_NANOS);
+    });
+
+    log!(INFO, "[set_min_vault_age_for_redemption] Minimum vault age set to {} seconds", min_age_secs);
+    Ok(())
+}
+
+// Set the fraction of redemption fees shared with redeemed vaults (developer only)
+#[candid_method(update)]
+#[update]
+fn set_redemption_rebate_share(share: f64) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can set the redemption rebate share".to_string()));
+    }
+
+    let share = match Decimal::from_f64(share) {
+        Some(share) if share >= dec!(0) && share <= dec!(1) => Ratio::from(share),
+        _ => return Err(ProtocolError::GenericError("Rebate share must be between 0 and 1".to_string())),
+    };
+
+    mutate_state(|s| {
+        event::record_set_redemption_rebate_share(s, share);
+    });
+
+    log!(INFO, "[set_redemption_rebate_share] Redemption rebate share set to: {}", share.to_f64());
+    Ok(())
+}
+
+#[candid_method(update)]
+#[update]
+async fn claim_redemption_rebate(
+    vault_id: u64,
+    request_id: Option<String>,
+) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        run_once(
+            "claim_redemption_rebate",
+            request_id,
+            rumi_protocol_backend::vault::claim_redemption_rebate(vault_id),
+        )
+        .await,
+    )
+    .map_err(for_caller)
+}
+
+#[candid_method(update)]
+#[update]
+async fn claim_liquidation_refund(request_id: Option<String>) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        run_once(
+            "claim_liquidation_refund",
+            request_id,
+            rumi_protocol_backend::vault::claim_liquidation_refund(),
+        )
+        .await,
+    )
+    .map_err(for_caller)
+}
+
+#[candid_method(query)]
+#[query]
+fn get_liquidation_refund(owner: Principal) -> u64 {
+    read_state(|s| s.get_liquidation_refund_of(owner).to_u64())
+}
+
+#[candid_method(query)]
+#[query]
+fn get_redemption_rebates(owner: Principal) -> Vec<RedemptionRebateEntry> {
+    read_state(|s| s.get_redemption_rebates_of(owner))
+}
+
+#[candid_method(query)]
+#[query]
+fn get_pending_transfers(owner: Principal) -> Vec<PendingTransfer> {
+    read_state(|s| s.get_pending_transfers_of(owner))
+}
+
+#[candid_method(query)]
+#[query]
+fn get_account_overview(owner: Principal) -> AccountOverview {
+    if ic_cdk::api::data_certificate().is_none() {
+        ic_cdk::trap("update call rejected");
+    }
+
+    read_state(|s| s.get_account_overview(owner, latest_events(MAX_ACCOUNT_EVENTS_SCANNED)))
+}
+
+#[candid_method(query)]
+#[query]
+fn get_saga(saga_id: u64) -> Option<Saga> {
+    read_state(|s| s.sagas.get(&saga_id).cloned())
+}
+
+#[candid_method(query)]
+#[query]
+fn get_sagas(owner: Principal) -> Vec<Saga> {
+    read_state(|s| s.get_sagas_of(owner))
+}
+
+// Set the global debt ceiling, or the one of a collateral type (developer only)
+#[candid_method(update)]
+#[update]
+fn set_debt_ceiling(
+    collateral_type: Option<CollateralType>,
+    ceiling: Option<u64>,
+) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can set debt ceilings".to_string()));
+    }
+
+    mutate_state(|s| {
+        event::record_set_debt_ceiling(s, collateral_type, ceiling.map(ICUSD::from));
+    });
+
+    log!(INFO, "[set_debt_ceiling] Debt ceiling of {:?} set to: {:?}", collateral_type, ceiling);
+    Ok(())
+}
+
 // Add the new get liquidatable vaults endpoint
 #[candid_method(query)]
 #[query]
@@ -457,40 +1073,67 @@ fn get_liquidatable_vaults() -> Vec<CandidVault> {
                 let ratio = rumi_protocol_backend::compute_collateral_ratio(vault, current_icp_rate);
                 ratio < s.mode.get_minimum_liquidation_collateral_ratio()
             })
-            .map(|vault| {
-                let collateral_ratio = rumi_protocol_backend::compute_collateral_ratio(vault, current_icp_rate);
-                CandidVault {
-                    owner: vault.owner,
-                    borrowed_icusd_amount: vault.borrowed_icusd_amount.to_u64(),
-                    icp_margin_amount: vault.icp_margin_amount.to_u64(),
-                    vault_id: vault.vault_id,
-                }
-            })
+            .map(|vault| CandidVault::with_health(vault.clone(), s))
             .collect::<Vec<CandidVault>>()
     })
 }
 
 
+// Opt into the typed ProtocolError variants, which older clients can't decode
+#[candid_method(update)]
+#[update]
+fn set_typed_errors(enabled: bool) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(ProtocolError::AnonymousCallerNotAllowed);
+    }
+    mutate_state(|s| s.set_typed_errors(caller, enabled))
+}
+
 // Liquidity related operations
 #[candid_method(update)]
 #[update]
-async fn provide_liquidity(amount: u64) -> Result<u64, ProtocolError> {
+async fn provide_liquidity(amount: u64, request_id: Option<String>) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::liquidity_pool::provide_liquidity(amount).await)
+    check_postcondition(
+        run_once(
+            "provide_liquidity",
+            request_id,
+            rumi_protocol_backend::liquidity_pool::provide_liquidity(amount),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(update)]
 #[update]
-async fn withdraw_liquidity(amount: u64) -> Result<u64, ProtocolError> {
+async fn withdraw_liquidity(amount: u64, request_id: Option<String>) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::liquidity_pool::withdraw_liquidity(amount).await)
+    check_postcondition(
+        run_once(
+            "withdraw_liquidity",
+            request_id,
+            rumi_protocol_backend::liquidity_pool::withdraw_liquidity(amount),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[candid_method(update)]
 #[update]
-async fn claim_liquidity_returns() -> Result<u64, ProtocolError> {
+async fn claim_liquidity_returns(request_id: Option<String>) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(rumi_protocol_backend::liquidity_pool::claim_liquidity_returns().await)
+    check_postcondition(
+        run_once(
+            "claim_liquidity_returns",
+            request_id,
+            rumi_protocol_backend::liquidity_pool::claim_liquidity_returns(),
+        )
+        .await,
+    )
+    .map_err(for_caller)
 }
 
 #[query]
@@ -537,7 +1180,7 @@ fn http_request(req: HttpRequest) -> HttpResponse {
 
                 w.encode_gauge(
                     "rumi_pending_margin_transfer_count",
-                    s.pending_margin_transfers.len() as f64,
+                    s.pending_transfers.len() as f64,
                     "Pending margin transfers count.",
                 )?;
 
@@ -549,7 +1192,7 @@ fn http_request(req: HttpRequest) -> HttpResponse {
 
                 w.encode_gauge(
                     "rumi_pending_margin_transfers_count",
-                    s.pending_margin_transfers.len() as f64,
+                    s.pending_transfers.len() as f64,
                     "Pending margin transfers count.",
                 )?;
 
@@ -559,6 +1202,18 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                     "Pending redemption transfers count.",
                 )?;
 
+                w.encode_gauge(
+                    "rumi_cumulative_bad_debt",
+                    s.cumulative_bad_debt.to_u64() as f64,
+                    "Debt left uncovered by liquidations since launch.",
+                )?;
+
+                w.encode_gauge(
+                    "rumi_outstanding_bad_debt",
+                    s.outstanding_bad_debt.to_u64() as f64,
+                    "Bad debt waiting on the treasury backstop.",
+                )?;
+
                 w.encode_gauge(
                     "rumi_icp_rate",
                     s.last_icp_rate.unwrap_or(UsdIcp::from(dec!(0))).to_f64(),
@@ -679,19 +1334,19 @@ fn heartbeat() {
     log!(INFO, "[heartbeat] Running scheduled cleanup tasks");
     
     // Clean up any stale operations
-    mutate_state(|s| s.clean_stale_operations());
+    mutate_state(|s| s.clean_stale_operations(ic_cdk::api::time()));
 }
 
 #[candid_method(update)]
 #[update]
-async fn recover_pending_transfer(vault_id: u64) -> Result<bool, ProtocolError> {
+async fn recover_pending_transfer(transfer_id: u64) -> Result<bool, ProtocolError> {
     let caller = ic_cdk::caller();
     
-    // Validate the caller is the owner of this pending transfer
+    // Validate the caller is the destination of this pending transfer
     let is_owner = read_state(|s| {
-        s.pending_margin_transfers
-            .get(&vault_id)
-            .map(|transfer| transfer.owner == caller)
+        s.pending_transfers
+            .get(&transfer_id)
+            .map(|transfer| transfer.destination == caller)
             .unwrap_or(false)
     });
     
@@ -701,38 +1356,124 @@ async fn recover_pending_transfer(vault_id: u64) -> Result<bool, ProtocolError>
     
     // Process the pending transfer immediately
     let transfer_opt = read_state(|s| {
-        s.pending_margin_transfers.get(&vault_id).cloned()
+        s.pending_transfers.get(&transfer_id).cloned()
     });
     
     if let Some(transfer) = transfer_opt {
-        let icp_transfer_fee = read_state(|s| s.icp_ledger_fee);
+        if rumi_protocol_backend::drop_transfer_if_dust(&transfer) {
+            return Ok(false);
+        }
         
-        match crate::management::transfer_icp(
-            transfer.margin - icp_transfer_fee,
-            transfer.owner,
-        )
-        .await
-        {
+        match rumi_protocol_backend::execute_pending_transfer(&transfer).await {
             Ok(block_index) => {
-                mutate_state(|s| crate::event::record_margin_transfer(s, vault_id, block_index));
+                mutate_state(|s| crate::event::record_margin_transfer(s, transfer_id, block_index));
+                rumi_protocol_backend::vault::notify_treasury_of_transfer(&transfer, block_index);
                 Ok(true)
             }
             Err(error) => {
                 log!(
                     DEBUG,
                     "[recover_pending_transfer] failed to transfer margin: {}, with error: {}",
-                    transfer.margin,
+                    transfer.amount,
                     error
                 );
+                mutate_state(|s| s.record_transfer_failure(transfer_id, error.to_string()));
                 Err(ProtocolError::TransferError(error))
             }
         }
     } else {
-        // No pending transfer found for this vault
-        Err(ProtocolError::GenericError("No pending transfer found for this vault".to_string()))
+        // No pending transfer found with this id
+        Err(ProtocolError::GenericError("No pending transfer found with this id".to_string()))
     }
 }
 
+// Resolve a pending transfer the ledger no longer deduplicates (developer only).
+// Pass the ICP block index if the ledger shows it was made, or none to queue
+// it again as a new transfer.
+#[candid_method(update)]
+#[update]
+fn resolve_pending_transfer(transfer_id: u64, block_index: Option<u64>) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can resolve pending transfers".to_string()));
+    }
+
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        let transfer = match s.pending_transfers.get(&transfer_id) {
+            Some(transfer) => transfer.clone(),
+            None => return Err(ProtocolError::GenericError("No pending transfer found with this id".to_string())),
+        };
+        if !rumi_protocol_backend::dedup_window_expired(transfer.created_at, now) {
+            return Err(ProtocolError::TemporarilyUnavailable(
+                "the transfer is still being retried".to_string(),
+            ));
+        }
+        match block_index {
+            Some(block_index) => event::record_margin_transfer(s, transfer_id, block_index),
+            None => event::record_transfer_requeued(s, transfer_id, now),
+        }
+        Ok(())
+    })?;
+
+    log!(INFO, "[resolve_pending_transfer] Transfer {} resolved with block {:?}", transfer_id, block_index);
+    if block_index.is_none() {
+        ic_cdk_timers::set_timer(std::time::Duration::from_secs(0), || {
+            ic_cdk::spawn(rumi_protocol_backend::process_pending_transfer())
+        });
+    }
+    Ok(())
+}
+
+// Resolve a redemption transfer the ledger no longer deduplicates (developer only),
+// see resolve_pending_transfer.
+#[candid_method(update)]
+#[update]
+fn resolve_pending_redemption_transfer(
+    icusd_block_index: u64,
+    block_index: Option<u64>,
+) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can resolve pending transfers".to_string()));
+    }
+
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        let transfer = match s.pending_redemption_transfer.get(&icusd_block_index) {
+            Some(transfer) => *transfer,
+            None => return Err(ProtocolError::GenericError("No pending redemption transfer found for this block".to_string())),
+        };
+        if !rumi_protocol_backend::dedup_window_expired(transfer.created_at, now) {
+            return Err(ProtocolError::TemporarilyUnavailable(
+                "the transfer is still being retried".to_string(),
+            ));
+        }
+        match block_index {
+            Some(block_index) => event::record_redemption_transfered(s, icusd_block_index, block_index),
+            None => event::record_redemption_transfer_requeued(s, icusd_block_index, now),
+        }
+        Ok(())
+    })?;
+
+    log!(
+        INFO,
+        "[resolve_pending_redemption_transfer] Redemption transfer {} resolved with block {:?}",
+        icusd_block_index,
+        block_index
+    );
+    if block_index.is_none() {
+        ic_cdk_timers::set_timer(std::time::Duration::from_secs(0), || {
+            ic_cdk::spawn(rumi_protocol_backend::process_pending_transfer())
+        });
+    }
+    Ok(())
+}
+
 // Add treasury configuration endpoint (developer only)
 #[candid_method(update)]
 #[update]
@@ -797,73 +1538,45 @@ async fn clear_stuck_operations(principal_id: Option<Principal>) -> Result<u64,
         return Err(ProtocolError::GenericError("Only developer can clear stuck operations".to_string()));
     }
     
+    let now = ic_cdk::api::time();
     let cleared_count = mutate_state(|s| {
-        use ic_cdk::api::time;
-        let current_time = time();
-        let mut operations_to_remove = Vec::new();
-        let mut count = 0u64;
-        
-        // If specific principal provided, only clear their operations
-        if let Some(target_principal) = principal_id {
-            for op_key in s.operation_guards.iter() {
-                if let Some((op_principal, op_name)) = s.operation_details.get(op_key) {
-                    if *op_principal == target_principal {
-                        operations_to_remove.push(op_key.clone());
-                        log!(INFO, 
-                            "[clear_stuck_operations] Clearing operation '{}' for principal: {}", 
-                            op_name, op_principal.to_string()
-                        );
-                        count += 1;
-                    }
-                }
-            }
-        } else {
-            // Clear all operations older than 2 minutes or marked as failed
-            for op_key in s.operation_guards.iter() {
-                let mut should_remove = false;
-                
-                if let Some(timestamp) = s.operation_guard_timestamps.get(op_key) {
-                    let age_seconds = (current_time - timestamp) / 1_000_000_000;
-                    if age_seconds > 120 { // 2 minutes
-                        should_remove = true;
-                    }
-                }
-                
-                // Commented out to avoid import issues - not needed for basic functionality
-                // if let Some(state) = s.operation_states.get(op_key) {
-                //     if *state == crate::guard::OperationState::Failed {
-                //         should_remove = true;
-                //     }
-                // }
-                
-                if should_remove {
-                    operations_to_remove.push(op_key.clone());
-                    if let Some((op_principal, op_name)) = s.operation_details.get(op_key) {
-                        log!(INFO, 
-                            "[clear_stuck_operations] Clearing stale operation '{}' for principal: {}", 
-                            op_name, op_principal.to_string()
-                        );
-                    }
-                    count += 1;
-                }
+        let before = s.locks.len();
+        s.locks.retain(|_, lock| {
+            // If specific principal provided, only clear their locks, otherwise
+            // clear all locks older than 2 minutes
+            let clear = match principal_id {
+                Some(target_principal) => lock.holder == target_principal,
+                None => now.saturating_sub(lock.acquired_at) > 120 * 1_000_000_000,
+            };
+            if clear {
+                log!(INFO,
+                    "[clear_stuck_operations] Clearing lock {:?} of '{}' held by {}",
+                    lock.key, lock.operation, lock.holder
+                );
             }
-        }
-        
-        // Remove the identified operations
-        for op_key in operations_to_remove {
-            s.operation_guards.remove(&op_key);
-            s.operation_guard_timestamps.remove(&op_key);
-            s.operation_states.remove(&op_key);
-            s.operation_details.remove(&op_key);
-        }
-        
-        count
+            !clear
+        });
+        (before - s.locks.len()) as u64
     });
     
     log!(INFO, "[clear_stuck_operations] Cleared {} stuck operations", cleared_count);
     Ok(cleared_count)
 }
 
+#[candid_method(query)]
+#[query]
+fn get_held_locks() -> Result<Vec<Lock>, ProtocolError> {
+    let caller = ic_cdk::caller();
+    
+    // Only developer can inspect the locks
+    let is_developer = read_state(|s| s.developer_principal == caller);
+    if !is_developer {
+        return Err(ProtocolError::GenericError("Only developer can list held locks".to_string()));
+    }
+    
+    Ok(read_state(|s| s.get_held_locks(ic_cdk::api::time())))
+}
+
 // Checks the real candid interface against the one declared in the did file
 #[test]
 fn check_candid_interface_compatibility() {
@@ -3,7 +3,7 @@ use crate::state::read_state;
 use candid::{Nat, Principal};
 use ic_xrc_types::{Asset, AssetClass, GetExchangeRateRequest, GetExchangeRateResult};
 use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
 use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 use icrc_ledger_client_cdk::{CdkRuntime, ICRC1Client};
 use num_traits::ToPrimitive;
@@ -209,6 +209,48 @@ pub async fn transfer_icp_from(amount: ICP, caller: Principal) -> Result<u64, Tr
         Ok(block_index.unwrap().0.to_u64().unwrap())
 }
 
+/// Like [transfer_icp_from], but with a fixed `created_at_time` and memo so that
+/// the ledger deduplicates retries. A duplicate returns the original block index.
+pub async fn transfer_icp_from_once(
+    amount: ICP,
+    caller: Principal,
+    created_at_time: Option<u64>,
+    memo: Vec<u8>,
+) -> Result<u64, TransferFromError> {
+    let client = ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id: read_state(|s| s.icp_ledger_principal),
+    };
+    let protocol_id = ic_cdk::id();
+    let result = client
+        .transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            to: Account {
+                owner: protocol_id,
+                subaccount: None,
+            },
+            amount: amount.to_nat(),
+            fee: None,
+            created_at_time,
+            memo: Some(Memo::from(memo)),
+        })
+        .await
+        .map_err(|e| TransferFromError::GenericError {
+            error_code: Nat::from(e.0.max(0) as u64),
+            message: e.1,
+        })?;
+
+    match result {
+        Ok(block_index) => Ok(block_index.0.to_u64().unwrap()),
+        Err(TransferFromError::Duplicate { duplicate_of }) => Ok(duplicate_of.0.to_u64().unwrap()),
+        Err(error) => Err(error),
+    }
+}
+
 pub async fn transfer_icp(amount: ICP, to: Principal) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
@@ -235,6 +277,44 @@ pub async fn transfer_icp(amount: ICP, to: Principal) -> Result<u64, TransferErr
     Ok(block_index.0.to_u64().unwrap())
 }
 
+/// Transfers ICP with a fixed `created_at_time` and memo, so that the ledger
+/// deduplicates retries of the same transfer. A duplicate returns the block
+/// index of the original transfer.
+pub async fn transfer_icp_once(
+    amount: ICP,
+    to: Principal,
+    created_at_time: Option<u64>,
+    memo: Vec<u8>,
+) -> Result<u64, TransferError> {
+    let client = ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id: read_state(|s| s.icp_ledger_principal),
+    };
+    let result = client
+        .transfer(TransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: to,
+                subaccount: None,
+            },
+            fee: None,
+            created_at_time,
+            memo: Some(Memo::from(memo)),
+            amount: amount.to_nat(),
+        })
+        .await
+        .map_err(|e| TransferError::GenericError {
+            error_code: Nat::from(e.0.max(0) as u64),
+            message: e.1,
+        })?;
+
+    match result {
+        Ok(block_index) => Ok(block_index.0.to_u64().unwrap()),
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of.0.to_u64().unwrap()),
+        Err(error) => Err(error),
+    }
+}
+
 pub async fn transfer_icusd(amount: ICUSD, to: Principal) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
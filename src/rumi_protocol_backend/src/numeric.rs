@@ -120,6 +120,18 @@ impl<T> Token<T> {
     }
 }
 
+impl<T> Default for Amount<T> {
+    fn default() -> Self {
+        Amount(Decimal::ZERO, PhantomData)
+    }
+}
+
+impl<T> Default for Token<T> {
+    fn default() -> Self {
+        Token(0, PhantomData)
+    }
+}
+
 impl<T> PartialOrd<u64> for Token<T> {
     fn partial_cmp(&self, &other: &u64) -> Option<Ordering> {
         self.0.partial_cmp(&other)
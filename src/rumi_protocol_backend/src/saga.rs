@@ -0,0 +1,386 @@
+//! Journal of operations that move funds across several messages.
+//!
+//! A saga is recorded before its ledger transfer and finished in the same
+//! message that records the transfer's outcome. A trap or upgrade in between
+//! leaves the saga pending; [resume_sagas] retries its transfer, which the ledger
+//! deduplicates, and then finishes or compensates it.
+
+use crate::event::{
+    record_collateral_withdrawn, record_open_vault, record_saga_updated, record_transfer_queued,
+    record_withdraw_and_close_vault,
+};
+use crate::logs::INFO;
+use crate::management::{transfer_icp_from_once, transfer_icp_once};
+use crate::numeric::ICP;
+use crate::state::{mutate_state, read_state, State, TransferPurpose};
+use crate::vault::Vault;
+use crate::{dedup_created_at_time, transfer_memo, SEC_NANOS, TRANSFER_DEDUP_WINDOW_NANOS};
+use crate::MIN_INTEREST_RATE;
+use candid::{CandidType, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::transfer::TransferError;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
+use serde::{Deserialize, Serialize};
+
+pub type SagaId = u64;
+
+// Give the call that started a saga time to finish before resuming it
+pub const SAGA_RESUME_DELAY_NANOS: u64 = 2 * 60 * SEC_NANOS;
+pub const SAGA_RESUME_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+pub const MAX_FINISHED_SAGAS: usize = 1_000;
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SagaKind {
+    /// Collect the margin from the owner, then open the vault.
+    OpenVault { owner: Principal, icp_margin: ICP },
+    /// Send the margin of the vault to its owner, then close the vault. The
+    /// margin is held out of the vault while the saga is pending.
+    WithdrawAndCloseVault {
+        owner: Principal,
+        vault_id: u64,
+        amount: ICP,
+    },
+    /// Send the margin of the vault to its owner and keep the vault open. The
+    /// margin is held out of the vault while the saga is pending.
+    WithdrawCollateral {
+        owner: Principal,
+        vault_id: u64,
+        amount: ICP,
+    },
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SagaStatus {
+    /// Waiting on the outcome of the ledger transfer.
+    Pending,
+    Completed { block_index: u64, vault_id: u64 },
+    /// The transfer failed and the held margin went back to the vault.
+    Compensated { reason: String },
+    /// The transfer failed before any funds moved, or could not be confirmed
+    /// before the ledger stopped deduplicating it, see [expire_saga].
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Saga {
+    pub id: SagaId,
+    pub kind: SagaKind,
+    pub status: SagaStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Saga {
+    pub fn owner(&self) -> Principal {
+        match &self.kind {
+            SagaKind::OpenVault { owner, .. } => *owner,
+            SagaKind::WithdrawAndCloseVault { owner, .. } => *owner,
+            SagaKind::WithdrawCollateral { owner, .. } => *owner,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == SagaStatus::Pending
+    }
+
+    fn memo(&self) -> Vec<u8> {
+        transfer_memo(b"rumi/saga/", self.id)
+    }
+}
+
+/// Errors after which the transfer may still go through, so the saga stays pending.
+pub fn is_transient_transfer_from_error(error: &TransferFromError) -> bool {
+    matches!(
+        error,
+        TransferFromError::TemporarilyUnavailable | TransferFromError::GenericError { .. }
+    )
+}
+
+pub fn is_transient_transfer_error(error: &TransferError) -> bool {
+    matches!(
+        error,
+        TransferError::TemporarilyUnavailable | TransferError::GenericError { .. }
+    )
+}
+
+/// Collects the margin of an open vault saga, once.
+pub async fn collect_margin(saga: &Saga) -> Result<u64, TransferFromError> {
+    match saga.kind {
+        SagaKind::OpenVault { owner, icp_margin } => {
+            transfer_icp_from_once(
+                icp_margin,
+                owner,
+                dedup_created_at_time(saga.created_at),
+                saga.memo(),
+            )
+            .await
+        }
+        _ => panic!("BUG: saga #{} does not collect margin", saga.id),
+    }
+}
+
+/// Sends the margin of a withdraw saga to the owner, once.
+pub async fn send_margin(saga: &Saga, fee: ICP) -> Result<u64, TransferError> {
+    match saga.kind {
+        SagaKind::WithdrawAndCloseVault { owner, amount, .. }
+        | SagaKind::WithdrawCollateral { owner, amount, .. } => {
+            transfer_icp_once(
+                amount - fee,
+                owner,
+                dedup_created_at_time(saga.created_at),
+                saga.memo(),
+            )
+            .await
+        }
+        _ => panic!("BUG: saga #{} does not send margin", saga.id),
+    }
+}
+
+/// Opens the vault of a pending open vault saga. Returns `None` if the saga
+/// was already finished.
+pub fn finish_open_vault(state: &mut State, saga_id: SagaId, block_index: u64, now: u64) -> Option<u64> {
+    let (owner, icp_margin) = match state.sagas.get(&saga_id) {
+        Some(Saga {
+            kind: SagaKind::OpenVault { owner, icp_margin },
+            status: SagaStatus::Pending,
+            ..
+        }) => (*owner, *icp_margin),
+        _ => return None,
+    };
+    let vault_id = state.increment_vault_id();
+    record_open_vault(
+        state,
+        Vault {
+            owner,
+            borrowed_icusd_amount: 0.into(),
+            icp_margin_amount: icp_margin,
+            vault_id,
+            liquidation_reserve: 0.into(),
+            interest_rate: MIN_INTEREST_RATE,
+            last_interest_rate_adjustment: now,
+            opened_at: now,
+        },
+        block_index,
+    );
+    record_saga_updated(
+        state,
+        saga_id,
+        SagaStatus::Completed {
+            block_index,
+            vault_id,
+        },
+        now,
+    );
+    Some(vault_id)
+}
+
+/// Closes the vault of a pending withdraw and close saga. Returns false if
+/// the saga was already finished.
+pub fn finish_withdraw_and_close(
+    state: &mut State,
+    saga_id: SagaId,
+    block_index: u64,
+    now: u64,
+) -> bool {
+    let (vault_id, amount) = match state.sagas.get(&saga_id) {
+        Some(Saga {
+            kind: SagaKind::WithdrawAndCloseVault {
+                vault_id, amount, ..
+            },
+            status: SagaStatus::Pending,
+            ..
+        }) => (*vault_id, *amount),
+        _ => return false,
+    };
+    record_collateral_withdrawn(state, vault_id, amount, block_index);
+    if state.vault_id_to_vaults.contains_key(&vault_id) {
+        record_withdraw_and_close_vault(state, vault_id, amount, Some(block_index), now);
+    }
+    record_saga_updated(
+        state,
+        saga_id,
+        SagaStatus::Completed {
+            block_index,
+            vault_id,
+        },
+        now,
+    );
+    true
+}
+
+/// Completes a pending withdraw collateral saga, whose margin was already
+/// taken out of the vault when it started. Returns false if the saga was
+/// already finished.
+pub fn finish_withdraw_collateral(
+    state: &mut State,
+    saga_id: SagaId,
+    block_index: u64,
+    now: u64,
+) -> bool {
+    let vault_id = match state.sagas.get(&saga_id) {
+        Some(Saga {
+            kind: SagaKind::WithdrawCollateral { vault_id, .. },
+            status: SagaStatus::Pending,
+            ..
+        }) => *vault_id,
+        _ => return false,
+    };
+    record_saga_updated(
+        state,
+        saga_id,
+        SagaStatus::Completed {
+            block_index,
+            vault_id,
+        },
+        now,
+    );
+    true
+}
+
+/// Ends a pending saga whose transfer failed, undoing what it held.
+pub fn abort_saga(state: &mut State, saga_id: SagaId, reason: String, now: u64) {
+    let status = match state.sagas.get(&saga_id) {
+        Some(saga) if saga.is_pending() => match saga.kind {
+            SagaKind::OpenVault { .. } => SagaStatus::Failed { reason },
+            SagaKind::WithdrawAndCloseVault { .. } | SagaKind::WithdrawCollateral { .. } => {
+                SagaStatus::Compensated { reason }
+            }
+        },
+        _ => return,
+    };
+    record_saga_updated(state, saga_id, status, now);
+}
+
+/// Ends a pending saga that could not be confirmed before the ledger stopped
+/// deduplicating its transfer. The margin held by a withdraw saga is queued
+/// as a pending transfer to the owner. The transfer may already
+/// have gone through, so the queued one is left for the developer to resolve.
+pub fn expire_saga(state: &mut State, saga_id: SagaId, now: u64) {
+    let saga = match state.sagas.get(&saga_id) {
+        Some(saga) if saga.is_pending() => saga.clone(),
+        _ => return,
+    };
+    let reason = match saga.kind {
+        SagaKind::OpenVault { .. } => "the transfer could not be confirmed in time".to_string(),
+        SagaKind::WithdrawAndCloseVault {
+            owner,
+            vault_id,
+            amount,
+        }
+        | SagaKind::WithdrawCollateral {
+            owner,
+            vault_id,
+            amount,
+        } => {
+            let purpose = match saga.kind {
+                SagaKind::WithdrawCollateral { .. } => TransferPurpose::CollateralWithdrawn,
+                _ => TransferPurpose::VaultClosed,
+            };
+            let transfer_id = record_transfer_queued(
+                state,
+                vault_id,
+                purpose,
+                owner,
+                amount,
+                saga.created_at,
+            );
+            format!(
+                "the transfer could not be confirmed in time, the margin is held in pending transfer {}",
+                transfer_id
+            )
+        }
+    };
+    record_saga_updated(state, saga_id, SagaStatus::Failed { reason }, now);
+}
+
+fn is_still_pending(saga_id: SagaId) -> bool {
+    read_state(|s| s.sagas.get(&saga_id).map_or(false, Saga::is_pending))
+}
+
+/// Resumes the pending sagas started at least `min_age` nanoseconds ago.
+pub async fn resume_sagas(min_age: u64) {
+    let _guard = match crate::guard::TimerLogicGuard::new() {
+        Some(guard) => guard,
+        None => {
+            log!(INFO, "[resume_sagas] double entry.");
+            return;
+        }
+    };
+
+    let now = ic_cdk::api::time();
+    let pending: Vec<Saga> = read_state(|s| {
+        s.sagas
+            .values()
+            .filter(|saga| saga.is_pending() && now.saturating_sub(saga.created_at) >= min_age)
+            .cloned()
+            .collect()
+    });
+    for saga in pending {
+        // The call that started it, or an earlier run, may have finished it
+        if is_still_pending(saga.id) {
+            resume_saga(saga).await;
+        }
+    }
+}
+
+async fn resume_saga(saga: Saga) {
+    log!(INFO, "[resume_saga] Resuming saga #{}: {:?}", saga.id, saga.kind);
+
+    // Past the deduplication window a retry could move the funds twice.
+    if ic_cdk::api::time().saturating_sub(saga.created_at) >= TRANSFER_DEDUP_WINDOW_NANOS {
+        log!(INFO, "[resume_saga] Saga #{} expired before it could be confirmed", saga.id);
+        mutate_state(|s| expire_saga(s, saga.id, ic_cdk::api::time()));
+        return;
+    }
+
+    match saga.kind {
+        SagaKind::OpenVault { .. } => match collect_margin(&saga).await {
+            Ok(block_index) => {
+                let vault_id = mutate_state(|s| {
+                    finish_open_vault(s, saga.id, block_index, ic_cdk::api::time())
+                });
+                log!(INFO, "[resume_saga] Saga #{} opened vault {:?}", saga.id, vault_id);
+            }
+            Err(error) if is_transient_transfer_from_error(&error) => {
+                log!(INFO, "[resume_saga] Saga #{} will be retried: {:?}", saga.id, error);
+            }
+            Err(error) => mutate_state(|s| {
+                abort_saga(s, saga.id, format!("{:?}", error), ic_cdk::api::time())
+            }),
+        },
+        SagaKind::WithdrawAndCloseVault { .. } => {
+            let fee = read_state(|s| s.icp_ledger_fee);
+            match send_margin(&saga, fee).await {
+                Ok(block_index) => {
+                    mutate_state(|s| {
+                        finish_withdraw_and_close(s, saga.id, block_index, ic_cdk::api::time())
+                    });
+                    log!(INFO, "[resume_saga] Saga #{} closed its vault", saga.id);
+                }
+                Err(error) if is_transient_transfer_error(&error) => {
+                    log!(INFO, "[resume_saga] Saga #{} will be retried: {}", saga.id, error);
+                }
+                Err(error) => mutate_state(|s| {
+                    abort_saga(s, saga.id, error.to_string(), ic_cdk::api::time())
+                }),
+            }
+        }
+        SagaKind::WithdrawCollateral { .. } => {
+            let fee = read_state(|s| s.icp_ledger_fee);
+            match send_margin(&saga, fee).await {
+                Ok(block_index) => {
+                    mutate_state(|s| {
+                        finish_withdraw_collateral(s, saga.id, block_index, ic_cdk::api::time())
+                    });
+                    log!(INFO, "[resume_saga] Saga #{} withdrew its collateral", saga.id);
+                }
+                Err(error) if is_transient_transfer_error(&error) => {
+                    log!(INFO, "[resume_saga] Saga #{} will be retried: {}", saga.id, error);
+                }
+                Err(error) => mutate_state(|s| {
+                    abort_saga(s, saga.id, error.to_string(), ic_cdk::api::time())
+                }),
+            }
+        }
+    }
+}
@@ -1,8 +1,13 @@
 use crate::numeric::{Ratio, UsdIcp, ICUSD, ICP};
-use crate::vault::Vault;
+use crate::event::Event;
+use crate::vault::{CandidVault, Vault};
 use crate::{
-    compute_collateral_ratio, InitArg, ProtocolError, UpgradeArg, MINIMUM_COLLATERAL_RATIO,
-    RECOVERY_COLLATERAL_RATIO, INFO, SEC_NANOS,
+    compute_collateral_ratio, AccountOverview, InitArg, LiquidityStatus,
+    PendingRedemptionTransfer, ProtocolError, RecoveryModeViolation, RedemptionRebateEntry,
+    StressTestResult, UpgradeArg,
+    MINIMUM_COLLATERAL_RATIO, MIN_ICP_RATE, MIN_INTEREST_RATE, READ_ONLY_COLLATERAL_RATIO,
+    READ_ONLY_EXIT_COLLATERAL_RATIO, RECOVERY_COLLATERAL_RATIO, RECOVERY_EXIT_COLLATERAL_RATIO,
+    INFO, YEAR_NANOS,
 };
 use candid::Principal;
 use ic_canister_log::log;
@@ -14,7 +19,12 @@ use std::cell::RefCell;
 use std::collections::btree_map::Entry::{Occupied, Vacant};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
-use crate::guard::OperationState;
+use crate::guard::{GuardError, Lock, LockKey, MAX_CONCURRENT};
+use crate::saga::{Saga, SagaId, SagaKind, SagaStatus, MAX_FINISHED_SAGAS};
+use crate::idempotency::{
+    RequestEntry, RequestKey, RequestStart, IN_FLIGHT_TIMEOUT_NANOS, MAX_REMEMBERED_REQUESTS,
+    REQUEST_ID_TTL_NANOS,
+};
 
 // Like assert_eq, but returns an error instead of panicking.
 macro_rules! ensure_eq {
@@ -41,6 +51,8 @@ macro_rules! ensure {
 pub const ICP_TRANSFER_FEE: ICP = ICP::new(10);
 pub type VaultId = u64;
 pub const DEFAULT_BORROW_FEE: Ratio = Ratio::new(dec!(0.005));
+pub const MAX_BORROWING_FEE: Ratio = Ratio::new(dec!(0.05));
+pub const DEFAULT_FLASH_MINT_FEE: Ratio = Ratio::new(dec!(0.0009));
 
 /// Controls which operations the protocol can perform.
 #[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize, Copy)]
@@ -89,12 +101,129 @@ impl Default for Mode {
     }
 }
 
+/// Why the protocol switched modes.
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize, Copy)]
+pub enum ModeChangeReason {
+    /// The total collateral ratio crossed an entry or exit threshold.
+    TotalCollateralRatio,
+    /// The ICP price fell below the minimum supported rate.
+    IcpRateTooLow,
+    /// The mode was set through an upgrade argument.
+    Upgrade,
+}
+
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize)]
+pub struct ModeTransition {
+    pub from: Mode,
+    pub to: Mode,
+    pub total_collateral_ratio: Ratio,
+    pub reason: ModeChangeReason,
+    pub timestamp: u64,
+}
+
+/// Only the most recent transitions are kept in memory, the event log has all of them.
+pub const MAX_MODE_HISTORY: usize = 500;
+
+pub const MAX_TYPED_ERROR_CALLERS: usize = 10_000;
+
+/// Number of events returned by `get_account_overview`.
+pub const MAX_ACCOUNT_EVENTS: usize = 50;
+
+/// Number of the most recent events of the log searched by `get_account_overview`.
+pub const MAX_ACCOUNT_EVENTS_SCANNED: u64 = 10000;
+
+/// Largest price increase accepted by `stress_test`, in percent.
+pub const MAX_STRESS_PRICE_CHANGE_PCT: Decimal = dec!(10000);
+
+/// Mode state machine driven by the total collateral ratio.
+///
+/// A mode is entered when the ratio falls below its entry threshold and only
+/// left once the ratio climbs above a higher exit threshold.
+pub fn next_mode(current: Mode, total_collateral_ratio: Ratio) -> Mode {
+    if total_collateral_ratio < READ_ONLY_COLLATERAL_RATIO {
+        return Mode::ReadOnly;
+    }
+    match current {
+        Mode::ReadOnly if total_collateral_ratio < READ_ONLY_EXIT_COLLATERAL_RATIO => Mode::ReadOnly,
+        Mode::ReadOnly | Mode::Recovery => {
+            if total_collateral_ratio >= RECOVERY_EXIT_COLLATERAL_RATIO {
+                Mode::GeneralAvailability
+            } else {
+                Mode::Recovery
+            }
+        }
+        Mode::GeneralAvailability => {
+            if total_collateral_ratio < RECOVERY_COLLATERAL_RATIO {
+                Mode::Recovery
+            } else {
+                Mode::GeneralAvailability
+            }
+        }
+    }
+}
+
+
 
+/// Assets that can back icUSD debt.
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, Serialize,
+)]
+pub enum CollateralType {
+    ICP,
+}
+
+/// Who absorbs the debt left uncovered by an underwater vault.
+#[derive(candid::CandidType, Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, Serialize)]
+pub enum BadDebtPolicy {
+    /// Kept as outstanding bad debt until the treasury burns icUSD to cover it.
+    TreasuryBackstop,
+    /// Added to the debt of the remaining vaults, in proportion to their collateral.
+    Redistribution,
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize, Copy)]
 pub struct PendingMarginTransfer {
     pub owner: Principal,
     pub margin: ICP,
+    /// Ledger fee when the transfer was queued, deducted from `margin`.
+    pub fee: ICP,
+    pub created_at: u64,
+}
+
+pub type TransferId = u64;
+
+/// Why the protocol owes an outgoing ICP transfer.
+#[derive(candid::CandidType, Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, Serialize)]
+pub enum TransferPurpose {
+    /// Collateral left in a closed vault, owed to its owner.
+    VaultClosed,
+    /// Collateral seized by a liquidator.
+    LiquidationReward,
+    /// Collateral left after a full liquidation, owed to the vault owner.
+    LiquidationExcess,
+    /// The protocol's share of a liquidation penalty, owed to the treasury.
+    LiquidationPenalty,
+    /// Collateral withdrawn from an open vault, owed to its owner.
+    CollateralWithdrawn,
+}
+
+/// An outgoing ICP transfer waiting to be made.
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize)]
+pub struct PendingTransfer {
+    pub id: TransferId,
+    pub vault_id: VaultId,
+    pub purpose: TransferPurpose,
+    pub destination: Principal,
+    /// Amount owed, before the ledger fee is deducted.
+    pub amount: ICP,
+    /// Ledger fee when the transfer was queued. Every attempt sends
+    /// `amount - fee`, so that the ledger can deduplicate retries.
+    pub fee: ICP,
+    /// Failed attempts since the canister was last upgraded. Failures are
+    /// not in the event log, so this and `last_error` reset on upgrade.
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64,
 }
 
 thread_local! {
@@ -126,12 +255,26 @@ pub struct DepositArgs {
     pub memo: Option<String>,
 }
 
+/// Share of a redemption fee owed to the owner of a redeemed vault.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RedemptionRebate {
+    pub owner: Principal,
+    pub amount: ICUSD,
+}
+
 pub struct State {
     pub vault_id_to_vaults: BTreeMap<u64, Vault>,
     pub principal_to_vault_ids: BTreeMap<Principal, BTreeSet<u64>>,
-    pub pending_margin_transfers: BTreeMap<VaultId, PendingMarginTransfer>,
+    /// Every vault a principal has opened, including the closed ones.
+    pub principal_to_opened_vault_ids: BTreeMap<Principal, BTreeSet<u64>>,
+    pub pending_transfers: BTreeMap<TransferId, PendingTransfer>,
+    pub next_transfer_id: TransferId,
+    /// Pending sagas and the most recently finished ones.
+    pub sagas: BTreeMap<SagaId, Saga>,
+    pub next_saga_id: SagaId,
     pub pending_redemption_transfer: BTreeMap<u64, PendingMarginTransfer>,
     pub mode: Mode,
+    pub mode_history: Vec<ModeTransition>,
     pub fee: Ratio,
     pub developer_principal: Principal,
     pub next_available_vault_id: u64,
@@ -146,14 +289,46 @@ pub struct State {
     pub icp_ledger_fee: ICP,
     pub last_icp_rate: Option<UsdIcp>,
     pub last_icp_timestamp: Option<u64>,
-    pub operation_guards: BTreeSet<String>, // Changed to use operation keys instead of just principals
-    pub operation_guard_timestamps: BTreeMap<String, u64>, // Changed to use operation keys
-    pub operation_states: BTreeMap<String, OperationState>, // Changed to use operation keys
-    pub operation_details: BTreeMap<String, (Principal, String)>, // Store principal and operation name for each key
+    /// Locks held by operations in flight, see [crate::guard::GuardPrincipal].
+    pub locks: BTreeMap<LockKey, Lock>,
+    /// Client request ids seen recently, with the result of completed calls.
+    pub recent_requests: BTreeMap<RequestKey, RequestEntry>,
     pub is_timer_running: bool,
     pub is_fetching_rate: bool,
     pub treasury_principal: Option<Principal>, // Add treasury principal
     pub stability_pool_canister: Option<Principal>, // Add stability pool canister
+    pub global_debt_ceiling: Option<ICUSD>,
+    pub collateral_debt_ceilings: BTreeMap<CollateralType, ICUSD>,
+    /// Debt of borrows that passed the ceiling check and are waiting on the mint.
+    pub reserved_debt: ICUSD,
+    pub last_interest_accrual: u64,
+    /// Fraction of each redemption fee credited to the redeemed vaults.
+    pub redemption_rebate_share: Ratio,
+    pub redemption_rebates: BTreeMap<VaultId, RedemptionRebate>,
+    /// Redemptions are disabled until this time, in nanoseconds.
+    pub redemption_bootstrap_end: u64,
+    /// Vaults younger than this, in nanoseconds, can't be redeemed against.
+    pub min_vault_age_for_redemption: u64,
+    /// Most icUSD that can be flash minted at once, zero when disabled.
+    pub flash_mint_cap: ICUSD,
+    pub flash_mint_fee: Ratio,
+    pub flash_minted_in_flight: ICUSD,
+    /// Flash mints that were not returned, by borrower.
+    pub flash_mint_defaults: BTreeMap<Principal, ICUSD>,
+    /// Canisters allowed to flash mint.
+    pub flash_mint_borrowers: BTreeSet<Principal>,
+    /// Callers that get the typed `ProtocolError` variants. Kept out of the
+    /// event log and saved in stable memory across upgrades.
+    pub typed_error_callers: BTreeSet<Principal>,
+    /// icUSD of skipped vaults that batch liquidations could not refund.
+    pub liquidation_refunds: BTreeMap<Principal, ICUSD>,
+    pub bad_debt_policy: BadDebtPolicy,
+    /// All the debt ever left uncovered by liquidations.
+    pub cumulative_bad_debt: ICUSD,
+    /// Bad debt waiting on the treasury backstop.
+    pub outstanding_bad_debt: ICUSD,
+    /// Fraction of the liquidation penalty sent to the treasury instead of the liquidator.
+    pub liquidation_protocol_share: Ratio,
 }
 
 impl From<InitArg> for State {
@@ -165,6 +340,7 @@ impl From<InitArg> for State {
             fee: Ratio::from(fee),
             developer_principal: args.developer_principal,
             principal_to_vault_ids: BTreeMap::new(),
+            principal_to_opened_vault_ids: BTreeMap::new(),
             pending_redemption_transfer: BTreeMap::new(),
             vault_id_to_vaults: BTreeMap::new(),
             xrc_principal: args.xrc_principal,
@@ -172,21 +348,42 @@ impl From<InitArg> for State {
             icp_ledger_principal: args.icp_ledger_principal,
             icp_ledger_fee: ICP_TRANSFER_FEE,
             mode: Mode::GeneralAvailability,
+            mode_history: vec![],
             total_collateral_ratio: Ratio::from(Decimal::MAX),
             last_icp_timestamp: None,
             last_icp_rate: None,
             next_available_vault_id: 1,
-            operation_guards: BTreeSet::new(),
-            operation_guard_timestamps: BTreeMap::new(), // Initialize empty timestamps map
-            operation_states: BTreeMap::new(),
-            operation_details: BTreeMap::new(),
+            locks: BTreeMap::new(),
+            recent_requests: BTreeMap::new(),
             liquidity_pool: BTreeMap::new(),
             liquidity_returns: BTreeMap::new(),
-            pending_margin_transfers: BTreeMap::new(),
+            pending_transfers: BTreeMap::new(),
+            next_transfer_id: 0,
+            sagas: BTreeMap::new(),
+            next_saga_id: 0,
             is_timer_running: false,
             is_fetching_rate: false,
             treasury_principal: args.treasury_principal, // Initialize treasury principal from args
             stability_pool_canister: args.stability_pool_principal, // Initialize stability pool canister from args
+            global_debt_ceiling: None,
+            collateral_debt_ceilings: BTreeMap::new(),
+            reserved_debt: ICUSD::new(0),
+            last_interest_accrual: 0,
+            redemption_rebate_share: Ratio::from(Decimal::ZERO),
+            redemption_rebates: BTreeMap::new(),
+            redemption_bootstrap_end: 0,
+            min_vault_age_for_redemption: 0,
+            flash_mint_cap: ICUSD::new(0),
+            flash_mint_fee: DEFAULT_FLASH_MINT_FEE,
+            flash_minted_in_flight: ICUSD::new(0),
+            flash_mint_defaults: BTreeMap::new(),
+            flash_mint_borrowers: BTreeSet::new(),
+            typed_error_callers: BTreeSet::new(),
+            liquidation_refunds: BTreeMap::new(),
+            bad_debt_policy: BadDebtPolicy::TreasuryBackstop,
+            cumulative_bad_debt: ICUSD::new(0),
+            outstanding_bad_debt: ICUSD::new(0),
+            liquidation_protocol_share: Ratio::from(Decimal::ZERO),
         }
     }
 }
@@ -204,10 +401,11 @@ impl State {
                 ))
             }
         };
-        if current_time.saturating_sub(last_icp_timestamp) > TEN_MINS_NANOS {
-            return Err(ProtocolError::TemporarilyUnavailable(
-                "Last known ICP price too old".to_string(),
-            ));
+        let age = current_time.saturating_sub(last_icp_timestamp);
+        if age > TEN_MINS_NANOS {
+            return Err(ProtocolError::StalePrice {
+                age: age / crate::SEC_NANOS,
+            });
         }
         Ok(())
     }
@@ -224,6 +422,28 @@ impl State {
         }
     }
 
+    /// Whether some vault still has the zero rate of the vaults opened before
+    /// interest rates existed.
+    pub fn has_legacy_interest_rates(&self) -> bool {
+        self.vault_id_to_vaults
+            .values()
+            .any(|vault| vault.interest_rate < MIN_INTEREST_RATE)
+    }
+
+    /// Vaults opened before interest rates existed have a rate of zero, which
+    /// puts them first in the redemption order. Move them to the minimum, which
+    /// starts charging their owners interest.
+    pub fn migrate_legacy_interest_rates(&mut self) {
+        if !self.has_interest_bearing_debt() {
+            self.last_interest_accrual = 0;
+        }
+        for vault in self.vault_id_to_vaults.values_mut() {
+            if vault.interest_rate < MIN_INTEREST_RATE {
+                vault.interest_rate = MIN_INTEREST_RATE;
+            }
+        }
+    }
+
     pub fn total_borrowed_icusd_amount(&self) -> ICUSD {
         self.vault_id_to_vaults
             .values()
@@ -238,6 +458,239 @@ impl State {
             .sum()
     }
 
+    pub fn collateral_debt(&self, collateral_type: CollateralType) -> ICUSD {
+        match collateral_type {
+            CollateralType::ICP => self.total_borrowed_icusd_amount(),
+        }
+    }
+
+    /// icUSD minted without collateral: flash mints in flight and the ones
+    /// that were never returned.
+    pub fn flash_mint_exposure(&self) -> ICUSD {
+        self.flash_minted_in_flight + self.flash_mint_defaults.values().cloned().sum()
+    }
+
+    /// icUSD that can still be minted against `collateral_type` before hitting
+    /// the global or the collateral's debt ceiling, `None` if neither is set.
+    /// Borrows in flight and flash mints count against both ceilings.
+    pub fn remaining_debt_capacity(&self, collateral_type: CollateralType) -> Option<ICUSD> {
+        let pending = self.reserved_debt + self.flash_mint_exposure();
+        let global_remaining = self.global_debt_ceiling.map(|ceiling| {
+            ceiling.saturating_sub(self.total_borrowed_icusd_amount() + pending)
+        });
+        let collateral_remaining = self
+            .collateral_debt_ceilings
+            .get(&collateral_type)
+            .map(|ceiling| ceiling.saturating_sub(self.collateral_debt(collateral_type) + pending));
+        match (global_remaining, collateral_remaining) {
+            (Some(global), Some(collateral)) => Some(global.min(collateral)),
+            (global, collateral) => global.or(collateral),
+        }
+    }
+
+    pub fn check_debt_capacity(
+        &self,
+        collateral_type: CollateralType,
+        amount: ICUSD,
+    ) -> Result<(), ProtocolError> {
+        if let Some(remaining) = self.remaining_debt_capacity(collateral_type) {
+            if amount > remaining {
+                return Err(ProtocolError::DebtCeilingReached {
+                    remaining_capacity: remaining.to_u64(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves `amount` of debt capacity for a borrow in flight, so that
+    /// concurrent borrows cannot overshoot the ceilings while awaiting the mint.
+    pub fn reserve_debt(
+        &mut self,
+        collateral_type: CollateralType,
+        amount: ICUSD,
+    ) -> Result<(), ProtocolError> {
+        self.check_debt_capacity(collateral_type, amount)?;
+        self.reserved_debt += amount;
+        Ok(())
+    }
+
+    pub fn release_debt_reservation(&mut self, amount: ICUSD) {
+        self.reserved_debt = self.reserved_debt.saturating_sub(amount);
+    }
+
+    /// Sets the ceiling of `collateral_type`, or the global one if `None`.
+    /// A `None` ceiling removes the limit.
+    pub fn set_debt_ceiling(
+        &mut self,
+        collateral_type: Option<CollateralType>,
+        ceiling: Option<ICUSD>,
+    ) {
+        match (collateral_type, ceiling) {
+            (None, ceiling) => self.global_debt_ceiling = ceiling,
+            (Some(collateral_type), Some(ceiling)) => {
+                self.collateral_debt_ceilings.insert(collateral_type, ceiling);
+            }
+            (Some(collateral_type), None) => {
+                self.collateral_debt_ceilings.remove(&collateral_type);
+            }
+        }
+    }
+
+    pub fn set_liquidation_protocol_share(&mut self, share: Ratio) {
+        self.liquidation_protocol_share = share;
+    }
+
+    /// The share of liquidation penalties withheld from liquidators. Nothing
+    /// is withheld while there is no treasury to send it to.
+    pub fn effective_liquidation_protocol_share(&self) -> Ratio {
+        match self.treasury_principal {
+            Some(_) => self.liquidation_protocol_share,
+            None => Ratio::from(Decimal::ZERO),
+        }
+    }
+
+    pub fn set_bad_debt_policy(&mut self, policy: BadDebtPolicy) {
+        self.bad_debt_policy = policy;
+    }
+
+    /// Accounts for `amount` of debt that a liquidation couldn't cover.
+    /// Redistribution falls back to the treasury when no vault has collateral left.
+    pub fn absorb_bad_debt(&mut self, amount: ICUSD) {
+        self.cumulative_bad_debt += amount;
+        let total_icp_margin = self.total_icp_margin_amount();
+        if self.bad_debt_policy == BadDebtPolicy::Redistribution && total_icp_margin > ICP::new(0) {
+            // Only vaults with collateral take a share, and the rounding
+            // remainder goes to the one with the most collateral.
+            let mut largest: Option<(ICP, VaultId)> = None;
+            let mut distributed = ICUSD::new(0);
+            for vault in self.vault_id_to_vaults.values_mut() {
+                if vault.icp_margin_amount == ICP::new(0) {
+                    continue;
+                }
+                let share = amount * (vault.icp_margin_amount / total_icp_margin);
+                vault.borrowed_icusd_amount += share;
+                distributed += share;
+                if largest.map_or(true, |(margin, _)| vault.icp_margin_amount > margin) {
+                    largest = Some((vault.icp_margin_amount, vault.vault_id));
+                }
+            }
+            if let Some((_, vault_id)) = largest {
+                if let Some(vault) = self.vault_id_to_vaults.get_mut(&vault_id) {
+                    vault.borrowed_icusd_amount += amount - distributed;
+                }
+            }
+        } else {
+            self.outstanding_bad_debt += amount;
+        }
+    }
+
+    pub fn cover_bad_debt(&mut self, amount: ICUSD) {
+        self.outstanding_bad_debt = self.outstanding_bad_debt.saturating_sub(amount);
+    }
+
+    pub fn set_flash_mint_params(&mut self, cap: ICUSD, fee: Ratio) {
+        self.flash_mint_cap = cap;
+        self.flash_mint_fee = fee;
+    }
+
+    pub fn set_flash_mint_borrower(&mut self, borrower: Principal, allowed: bool) {
+        if allowed {
+            self.flash_mint_borrowers.insert(borrower);
+        } else {
+            self.flash_mint_borrowers.remove(&borrower);
+        }
+    }
+
+    pub fn set_typed_errors(
+        &mut self,
+        caller: Principal,
+        enabled: bool,
+    ) -> Result<(), ProtocolError> {
+        if !enabled {
+            self.typed_error_callers.remove(&caller);
+            return Ok(());
+        }
+        if !self.typed_error_callers.contains(&caller)
+            && self.typed_error_callers.len() >= MAX_TYPED_ERROR_CALLERS
+        {
+            return Err(ProtocolError::GenericError(
+                "too many callers have enabled typed errors".to_string(),
+            ));
+        }
+        self.typed_error_callers.insert(caller);
+        Ok(())
+    }
+
+    /// Books `amount` against the flash mint cap and returns the fee owed on it.
+    /// Flash mints that were not returned keep counting against the cap.
+    pub fn reserve_flash_mint(
+        &mut self,
+        borrower: Principal,
+        amount: ICUSD,
+    ) -> Result<ICUSD, ProtocolError> {
+        if self.flash_mint_cap == 0 {
+            return Err(ProtocolError::OperationPaused {
+                reason: "flash minting is disabled".to_string(),
+            });
+        }
+        if !self.flash_mint_borrowers.contains(&borrower) {
+            return Err(ProtocolError::GenericError(format!(
+                "{borrower} is not allowed to flash mint"
+            )));
+        }
+        if self.flash_mint_defaults.contains_key(&borrower) {
+            return Err(ProtocolError::GenericError(
+                "a previous flash mint was not returned".to_string(),
+            ));
+        }
+        if amount < crate::MIN_ICUSD_AMOUNT {
+            return Err(ProtocolError::AmountTooLow {
+                minimum_amount: crate::MIN_ICUSD_AMOUNT.to_u64(),
+            });
+        }
+        let available = self.flash_mint_cap.saturating_sub(self.flash_mint_exposure());
+        if amount > available {
+            return Err(ProtocolError::GenericError(format!(
+                "flash mint of {amount} exceeds the available {available}"
+            )));
+        }
+        self.check_debt_capacity(CollateralType::ICP, amount)?;
+        self.flash_minted_in_flight += amount;
+        Ok(amount * self.flash_mint_fee)
+    }
+
+    pub fn release_flash_mint(&mut self, amount: ICUSD) {
+        self.flash_minted_in_flight = self.flash_minted_in_flight.saturating_sub(amount);
+    }
+
+    /// Books an unreturned flash mint of `minted` icUSD, owing `owed` with the
+    /// fee, as a default. The amount moves from the in-flight booking, which
+    /// is lost on upgrade, to the defaults, so the cap is not freed.
+    pub fn default_flash_mint(&mut self, borrower: Principal, minted: ICUSD, owed: ICUSD) {
+        self.release_flash_mint(minted);
+        self.add_flash_mint_default(borrower, owed);
+    }
+
+    pub fn add_flash_mint_default(&mut self, borrower: Principal, amount: ICUSD) {
+        self.flash_mint_defaults
+            .entry(borrower)
+            .and_modify(|owed| *owed += amount)
+            .or_insert(amount);
+    }
+
+    pub fn set_redemption_bootstrap_end(&mut self, end: u64) {
+        self.redemption_bootstrap_end = end;
+    }
+
+    pub fn set_min_vault_age_for_redemption(&mut self, min_age: u64) {
+        self.min_vault_age_for_redemption = min_age;
+    }
+
+    pub fn set_redemption_rebate_share(&mut self, share: Ratio) {
+        self.redemption_rebate_share = share;
+    }
+
     pub fn compute_total_collateral_ratio(&self, icp_rate: UsdIcp) -> Ratio {
         if self.total_borrowed_icusd_amount() == ICUSD::new(0) {
             return Ratio::from(Decimal::MAX);
@@ -245,49 +698,189 @@ impl State {
         (self.total_icp_margin_amount() * icp_rate) / self.total_borrowed_icusd_amount()
     }
 
+    /// In Recovery mode, an operation leaving the vault as `vault_after` must keep
+    /// it above the recovery collateral ratio and must not lower the total
+    /// collateral ratio. Outside of Recovery mode this always succeeds, even
+    /// without a known ICP rate.
+    pub fn check_recovery_mode_rules(&self, vault_after: &Vault) -> Result<(), ProtocolError> {
+        if self.mode != Mode::Recovery {
+            return Ok(());
+        }
+        let icp_rate = self.last_icp_rate.ok_or_else(|| {
+            ProtocolError::TemporarilyUnavailable("no ICP rate available".to_string())
+        })?;
+
+        let vault_ratio = compute_collateral_ratio(vault_after, icp_rate);
+        if vault_ratio < RECOVERY_COLLATERAL_RATIO {
+            return Err(ProtocolError::RecoveryModeViolation(
+                RecoveryModeViolation::VaultBelowRecoveryRatio {
+                    vault_ratio: vault_ratio.to_f64(),
+                    required_ratio: RECOVERY_COLLATERAL_RATIO.to_f64(),
+                },
+            ));
+        }
+
+        let (margin_before, debt_before) = self
+            .vault_id_to_vaults
+            .get(&vault_after.vault_id)
+            .map(|vault| (vault.icp_margin_amount, vault.borrowed_icusd_amount))
+            .unwrap_or((ICP::new(0), ICUSD::new(0)));
+        let total_margin_after =
+            self.total_icp_margin_amount() - margin_before + vault_after.icp_margin_amount;
+        let total_debt_after =
+            self.total_borrowed_icusd_amount() - debt_before + vault_after.borrowed_icusd_amount;
+
+        let current_ratio = self.compute_total_collateral_ratio(icp_rate);
+        let resulting_ratio = if total_debt_after == ICUSD::new(0) {
+            Ratio::from(Decimal::MAX)
+        } else {
+            (total_margin_after * icp_rate) / total_debt_after
+        };
+        if resulting_ratio < current_ratio {
+            return Err(ProtocolError::RecoveryModeViolation(
+                RecoveryModeViolation::TotalCollateralRatioDecreased {
+                    current_ratio: current_ratio.to_f64(),
+                    resulting_ratio: resulting_ratio.to_f64(),
+                },
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn hours_since_last_redemption(&self) -> u64 {
+        self.hours_since_last_redemption_at(ic_cdk::api::time())
+    }
+
+    fn hours_since_last_redemption_at(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_redemption_time) / 1_000_000_000 / 3600
+    }
+
     pub fn get_redemption_fee(&self, redeemed_amount: ICUSD) -> Ratio {
-        let current_time = ic_cdk::api::time();
-        let last_redemption_time = self.last_redemption_time;
-        let elapsed_hours = (current_time - last_redemption_time) / 1_000_000_000 / 3600;
+        self.get_redemption_fee_at(redeemed_amount, ic_cdk::api::time())
+    }
+
+    pub fn get_redemption_fee_at(&self, redeemed_amount: ICUSD, now: u64) -> Ratio {
         compute_redemption_fee(
-            elapsed_hours,
+            self.hours_since_last_redemption_at(now),
             redeemed_amount,
             self.total_borrowed_icusd_amount(),
             self.current_base_rate,
         )
     }
 
+    /// The redemption base rate, decayed to the current time.
+    pub fn get_decayed_base_rate(&self) -> Ratio {
+        decay_base_rate(self.current_base_rate, self.hours_since_last_redemption())
+    }
+
+    /// Borrowing fee: the configured fee as a floor plus the decayed redemption
+    /// base rate, so that heavy redemption periods make minting more expensive.
     pub fn get_borrowing_fee(&self) -> Ratio {
         match self.mode {
             Mode::Recovery => Ratio::from(Decimal::ZERO),
-            Mode::GeneralAvailability => self.fee,
-            Mode::ReadOnly => self.fee,
+            Mode::GeneralAvailability | Mode::ReadOnly => compute_borrowing_fee(
+                self.hours_since_last_redemption(),
+                self.current_base_rate,
+                self.fee,
+            ),
         }
     }
 
     pub fn update_total_collateral_ratio_and_mode(&mut self, icp_rate: UsdIcp) {
-        let previous_mode = self.mode;
-        let new_total_collateral_ratio = self.compute_total_collateral_ratio(icp_rate);
-        self.total_collateral_ratio = new_total_collateral_ratio;
-        
-        if new_total_collateral_ratio < crate::RECOVERY_COLLATERAL_RATIO {
-            self.mode = Mode::Recovery;
+        self.total_collateral_ratio = self.compute_total_collateral_ratio(icp_rate);
+
+        if let Some((mode, reason)) = self.next_mode_transition(icp_rate) {
+            crate::event::record_mode_changed(self, mode, reason, ic_cdk::api::time());
+        }
+    }
+
+    /// Returns the mode the protocol should switch to at `icp_rate`, if any.
+    pub fn next_mode_transition(&self, icp_rate: UsdIcp) -> Option<(Mode, ModeChangeReason)> {
+        // A read-only mode set by an upgrade is only lifted by another upgrade.
+        if self.mode == Mode::ReadOnly
+            && self.mode_history.last().map(|transition| transition.reason)
+                == Some(ModeChangeReason::Upgrade)
+        {
+            return None;
+        }
+
+        let (mode, reason) = if icp_rate < MIN_ICP_RATE {
+            (Mode::ReadOnly, ModeChangeReason::IcpRateTooLow)
+        } else {
+            (
+                next_mode(self.mode, self.compute_total_collateral_ratio(icp_rate)),
+                ModeChangeReason::TotalCollateralRatio,
+            )
+        };
+
+        if mode == self.mode {
+            None
         } else {
-            self.mode = Mode::GeneralAvailability;
+            Some((mode, reason))
         }
-        
-        if new_total_collateral_ratio < Ratio::from(dec!(1.0)) {
-            self.mode = Mode::ReadOnly;
+    }
+
+    /// Recomputes the vaults at the last ICP rate moved by `price_change_pct`
+    /// percent. The stability pool deposits are left for the caller to fill in.
+    pub fn stress_test(&self, price_change_pct: f64) -> Result<StressTestResult, ProtocolError> {
+        let icp_rate = self.last_icp_rate.ok_or(ProtocolError::TemporarilyUnavailable(
+            "no ICP rate available".to_string(),
+        ))?;
+        // Bounded so that the collateral values at the stressed rate fit as well.
+        let change = Decimal::from_f64(price_change_pct)
+            .filter(|change| *change > dec!(-100) && *change <= MAX_STRESS_PRICE_CHANGE_PCT)
+            .ok_or(ProtocolError::GenericError(format!(
+                "price change must be above -100% and at most {MAX_STRESS_PRICE_CHANGE_PCT}%"
+            )))?;
+        let stressed_rate = icp_rate
+            .0
+            .checked_mul(Decimal::ONE + change / dec!(100))
+            .map(UsdIcp::from)
+            .ok_or(ProtocolError::GenericError(
+                "price change is out of range".to_string(),
+            ))?;
+        let mode = self
+            .next_mode_transition(stressed_rate)
+            .map(|(mode, _)| mode)
+            .unwrap_or(self.mode);
+        let minimum_ratio = mode.get_minimum_liquidation_collateral_ratio();
+
+        let mut liquidatable_vault_count = 0;
+        let mut liquidatable_debt = ICUSD::new(0);
+        for vault in self.vault_id_to_vaults.values() {
+            if compute_collateral_ratio(vault, stressed_rate) < minimum_ratio {
+                liquidatable_vault_count += 1;
+                liquidatable_debt += vault.borrowed_icusd_amount;
+            }
         }
-        
-        if previous_mode != self.mode {
-            log!(
-                crate::DEBUG,
-                "[update_mode] switched to {}, ratio: {}, min ratio: {:?}",
-                self.mode,
-                new_total_collateral_ratio.to_f64(),
-                self.mode.get_minimum_liquidation_collateral_ratio().to_f64()
-            );
+
+        Ok(StressTestResult {
+            price_change_pct,
+            icp_rate: stressed_rate.to_f64(),
+            liquidatable_vault_count,
+            liquidatable_debt: liquidatable_debt.to_u64(),
+            total_collateral_ratio: self.compute_total_collateral_ratio(stressed_rate).to_f64(),
+            mode,
+            stability_pool_deposits: None,
+            covered_by_stability_pool: None,
+        })
+    }
+
+    pub fn change_mode(&mut self, transition: ModeTransition) {
+        log!(
+            INFO,
+            "[change_mode] switched from {} to {} ({:?}), ratio: {}, min ratio: {:?}",
+            transition.from,
+            transition.to,
+            transition.reason,
+            transition.total_collateral_ratio.to_f64(),
+            transition.to.get_minimum_liquidation_collateral_ratio().to_f64()
+        );
+        self.mode = transition.to;
+        self.mode_history.push(transition);
+        if self.mode_history.len() > MAX_MODE_HISTORY {
+            self.mode_history.remove(0);
         }
     }
 
@@ -304,18 +897,24 @@ impl State {
                 self.principal_to_vault_ids.insert(vault.owner, vault_ids);
             }
         }
+        self.principal_to_opened_vault_ids
+            .entry(vault.owner)
+            .or_default()
+            .insert(vault_id);
     }
 
-    pub fn close_vault(&mut self, vault_id: u64) {
+    pub fn close_vault(&mut self, vault_id: u64, timestamp: u64) {
         if let Some(vault) = self.vault_id_to_vaults.remove(&vault_id) {
             let owner = vault.owner;
-            self.pending_margin_transfers.insert(
-                vault_id,
-                PendingMarginTransfer {
+            if vault.icp_margin_amount > ICP::new(0) {
+                self.enqueue_transfer(
+                    vault_id,
+                    TransferPurpose::VaultClosed,
                     owner,
-                    margin: vault.icp_margin_amount,
-                },
-            );
+                    vault.icp_margin_amount,
+                    timestamp,
+                );
+            }
             if let Some(vault_ids) = self.principal_to_vault_ids.get_mut(&owner) {
                 vault_ids.remove(&vault_id);
             } else {
@@ -326,10 +925,192 @@ impl State {
         }
     }
 
-    pub fn borrow_from_vault(&mut self, vault_id: u64, borrowed_amount: ICUSD) {
+    pub fn enqueue_transfer(
+        &mut self,
+        vault_id: VaultId,
+        purpose: TransferPurpose,
+        destination: Principal,
+        amount: ICP,
+        created_at: u64,
+    ) -> TransferId {
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        self.pending_transfers.insert(
+            id,
+            PendingTransfer {
+                id,
+                vault_id,
+                purpose,
+                destination,
+                amount,
+                fee: self.icp_ledger_fee,
+                attempts: 0,
+                last_error: None,
+                created_at,
+            },
+        );
+        id
+    }
+
+    /// Restarts a transfer as a new one, with a fresh deduplication window and
+    /// the current ledger fee.
+    pub fn requeue_transfer(&mut self, transfer_id: TransferId, timestamp: u64) {
+        let fee = self.icp_ledger_fee;
+        if let Some(transfer) = self.pending_transfers.get_mut(&transfer_id) {
+            transfer.fee = fee;
+            transfer.attempts = 0;
+            transfer.last_error = None;
+            transfer.created_at = timestamp;
+        }
+    }
+
+    pub fn queue_redemption_transfer(
+        &mut self,
+        icusd_block_index: u64,
+        owner: Principal,
+        margin: ICP,
+        created_at: u64,
+    ) {
+        self.pending_redemption_transfer.insert(
+            icusd_block_index,
+            PendingMarginTransfer {
+                owner,
+                margin,
+                fee: self.icp_ledger_fee,
+                created_at,
+            },
+        );
+    }
+
+    /// Restarts a redemption transfer as a new one, see `requeue_transfer`.
+    pub fn requeue_redemption_transfer(&mut self, icusd_block_index: u64, timestamp: u64) {
+        let fee = self.icp_ledger_fee;
+        if let Some(transfer) = self.pending_redemption_transfer.get_mut(&icusd_block_index) {
+            transfer.fee = fee;
+            transfer.created_at = timestamp;
+        }
+    }
+
+    pub fn set_icp_ledger_fee(&mut self, fee: ICP) {
+        self.icp_ledger_fee = fee;
+    }
+
+    pub fn complete_transfer(&mut self, transfer_id: TransferId) {
+        self.pending_transfers.remove(&transfer_id);
+    }
+
+    /// Completes the oldest pending transfer of a vault, for margin transfers
+    /// recorded before transfers had their own id.
+    pub fn complete_vault_transfer(&mut self, vault_id: VaultId) {
+        if let Some(id) = self
+            .pending_transfers
+            .values()
+            .find(|transfer| transfer.vault_id == vault_id)
+            .map(|transfer| transfer.id)
+        {
+            self.pending_transfers.remove(&id);
+        }
+    }
+
+    pub fn record_transfer_failure(&mut self, transfer_id: TransferId, error: String) {
+        if let Some(transfer) = self.pending_transfers.get_mut(&transfer_id) {
+            transfer.attempts += 1;
+            transfer.last_error = Some(error);
+        }
+    }
+
+    pub fn get_pending_transfers_of(&self, owner: Principal) -> Vec<PendingTransfer> {
+        self.pending_transfers
+            .values()
+            .filter(|transfer| transfer.destination == owner)
+            .cloned()
+            .collect()
+    }
+
+    pub fn start_saga(&mut self, kind: SagaKind, timestamp: u64) -> SagaId {
+        if let SagaKind::WithdrawAndCloseVault {
+            vault_id, amount, ..
+        }
+        | SagaKind::WithdrawCollateral {
+            vault_id, amount, ..
+        } = &kind
+        {
+            if let Some(vault) = self.vault_id_to_vaults.get_mut(vault_id) {
+                vault.icp_margin_amount = vault.icp_margin_amount.saturating_sub(*amount);
+            }
+        }
+        let id = self.next_saga_id;
+        self.next_saga_id += 1;
+        self.sagas.insert(
+            id,
+            Saga {
+                id,
+                kind,
+                status: SagaStatus::Pending,
+                created_at: timestamp,
+                updated_at: timestamp,
+            },
+        );
+        id
+    }
+
+    pub fn update_saga(&mut self, saga_id: SagaId, status: SagaStatus, timestamp: u64) {
+        let saga = match self.sagas.get_mut(&saga_id) {
+            Some(saga) => saga,
+            None => ic_cdk::trap("BUG: tried to update unknown saga"),
+        };
+        if let (
+            SagaKind::WithdrawAndCloseVault {
+                vault_id, amount, ..
+            }
+            | SagaKind::WithdrawCollateral {
+                vault_id, amount, ..
+            },
+            SagaStatus::Compensated { .. },
+        ) = (&saga.kind, &status)
+        {
+            if let Some(vault) = self.vault_id_to_vaults.get_mut(vault_id) {
+                vault.icp_margin_amount += *amount;
+            }
+        }
+        saga.status = status;
+        saga.updated_at = timestamp;
+
+        let finished: Vec<SagaId> = self
+            .sagas
+            .values()
+            .filter(|saga| !saga.is_pending())
+            .map(|saga| saga.id)
+            .collect();
+        if finished.len() > MAX_FINISHED_SAGAS {
+            for id in &finished[..finished.len() - MAX_FINISHED_SAGAS] {
+                self.sagas.remove(id);
+            }
+        }
+    }
+
+    pub fn get_sagas_of(&self, owner: Principal) -> Vec<Saga> {
+        self.sagas
+            .values()
+            .filter(|saga| saga.owner() == owner)
+            .cloned()
+            .collect()
+    }
+
+    pub fn borrow_from_vault(
+        &mut self,
+        vault_id: u64,
+        borrowed_amount: ICUSD,
+        liquidation_reserve: ICUSD,
+    ) {
+        if !self.has_interest_bearing_debt() {
+            // Interest is only accrued while there is debt, restart the clock.
+            self.last_interest_accrual = 0;
+        }
         match self.vault_id_to_vaults.get_mut(&vault_id) {
             Some(vault) => {
-                vault.borrowed_icusd_amount += borrowed_amount;
+                vault.borrowed_icusd_amount += borrowed_amount + liquidation_reserve;
+                vault.liquidation_reserve += liquidation_reserve;
             }
             None => ic_cdk::trap("borrowing from unknown vault"),
         }
@@ -349,6 +1130,7 @@ impl State {
             Some(vault) => {
                 assert!(repayed_amount <= vault.borrowed_icusd_amount);
                 vault.borrowed_icusd_amount -= repayed_amount;
+                vault.settle_liquidation_reserve();
             }
             None => ic_cdk::trap("repaying to unknown vault"),
         }
@@ -406,6 +1188,86 @@ impl State {
         *self.liquidity_pool.get(&principal).unwrap_or(&ICUSD::from(0))
     }
 
+    pub fn get_liquidity_status_of(&self, owner: Principal) -> LiquidityStatus {
+        let total_liquidity_provided = self.total_provided_liquidity_amount();
+        let liquidity_pool_share = if total_liquidity_provided == 0 {
+            0.0
+        } else {
+            (self.get_provided_liquidity(owner) / total_liquidity_provided).to_f64()
+        };
+        LiquidityStatus {
+            liquidity_provided: self.get_provided_liquidity(owner).to_u64(),
+            total_liquidity_provided: total_liquidity_provided.to_u64(),
+            liquidity_pool_share,
+            available_liquidity_reward: self.get_liquidity_returns_of(owner).to_u64(),
+            total_available_returns: self.total_available_returns().to_u64(),
+        }
+    }
+
+    pub fn get_redemption_rebates_of(&self, owner: Principal) -> Vec<RedemptionRebateEntry> {
+        self.redemption_rebates
+            .iter()
+            .filter(|(_, rebate)| rebate.owner == owner)
+            .map(|(&vault_id, rebate)| RedemptionRebateEntry {
+                vault_id,
+                amount: rebate.amount.to_u64(),
+            })
+            .collect()
+    }
+
+    pub fn get_pending_redemption_transfers_of(
+        &self,
+        owner: Principal,
+    ) -> Vec<PendingRedemptionTransfer> {
+        self.pending_redemption_transfer
+            .iter()
+            .filter(|(_, transfer)| transfer.owner == owner)
+            .map(|(&icusd_block_index, transfer)| PendingRedemptionTransfer {
+                icusd_block_index,
+                margin: transfer.margin.to_u64(),
+                created_at: transfer.created_at,
+            })
+            .collect()
+    }
+
+    /// Aggregates the vaults, liquidity, claimable rebates and pending payouts
+    /// of `owner`, along with their last `MAX_ACCOUNT_EVENTS` events among
+    /// `events`, which are expected newest first.
+    pub fn get_account_overview(
+        &self,
+        owner: Principal,
+        events: impl Iterator<Item = Event>,
+    ) -> AccountOverview {
+        let vaults = self
+            .principal_to_vault_ids
+            .get(&owner)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.vault_id_to_vaults.get(id))
+            .map(|vault| CandidVault::with_health(vault.clone(), self))
+            .collect();
+
+        let opened_vault_ids = self
+            .principal_to_opened_vault_ids
+            .get(&owner)
+            .cloned()
+            .unwrap_or_default();
+        let mut recent_events: Vec<Event> = events
+            .filter(|event| event.is_principal_related(&owner, &opened_vault_ids))
+            .take(MAX_ACCOUNT_EVENTS)
+            .collect();
+        recent_events.reverse();
+
+        AccountOverview {
+            vaults,
+            liquidity: self.get_liquidity_status_of(owner),
+            redemption_rebates: self.get_redemption_rebates_of(owner),
+            pending_transfers: self.get_pending_transfers_of(owner),
+            pending_redemption_transfers: self.get_pending_redemption_transfers_of(owner),
+            recent_events,
+        }
+    }
+
     pub fn liquidate_vault_partial(&mut self, vault_id: u64, debt_to_liquidate: ICUSD, collateral_to_seize: ICP, _icp_rate: UsdIcp) {
         let should_remove_vault = match self.vault_id_to_vaults.get_mut(&vault_id) {
             Some(vault) => {
@@ -414,6 +1276,7 @@ impl State {
                 
                 // Reduce collateral by the seized amount
                 vault.icp_margin_amount = vault.icp_margin_amount.saturating_sub(collateral_to_seize);
+                vault.settle_liquidation_reserve();
                 
                 // Check if vault should be removed (all debt paid off or collateral exhausted)
                 vault.borrowed_icusd_amount == ICUSD::new(0) || vault.icp_margin_amount == ICP::new(0)
@@ -497,48 +1360,265 @@ impl State {
         }
     }
     
-    pub fn redeem_on_vaults(&mut self, icusd_amount: ICUSD, current_icp_rate: UsdIcp) {
-        let mut icusd_amount_to_convert = icusd_amount;
-        let mut vaults: BTreeSet<(Ratio, VaultId)> = BTreeSet::new();
-    
-        for vault in self.vault_id_to_vaults.values() {
-            vaults.insert((
-                crate::compute_collateral_ratio(vault, current_icp_rate),
-                vault.vault_id,
-            ));
+    pub fn has_interest_bearing_debt(&self) -> bool {
+        self.vault_id_to_vaults
+            .values()
+            .any(|vault| vault.net_debt() > 0 && vault.interest_rate > Ratio::default())
+    }
+
+    /// Interest of each vault accrued between the previous accrual and `timestamp`.
+    fn accrued_interest(&self, timestamp: u64) -> Vec<(VaultId, ICUSD)> {
+        let previous_accrual = self.last_interest_accrual;
+        if previous_accrual == 0 || timestamp <= previous_accrual {
+            return vec![];
         }
+        let year_fraction = Ratio::from(
+            Decimal::from(timestamp - previous_accrual) / Decimal::from(YEAR_NANOS),
+        );
+        self.vault_id_to_vaults
+            .values()
+            .map(|vault| (vault.vault_id, vault.net_debt() * (vault.interest_rate * year_fraction)))
+            .filter(|(_, interest)| *interest > 0)
+            .collect()
+    }
+
+    /// Whether an accrual at `timestamp` would change any debt or has to
+    /// start the accrual clock. Accruals that change nothing are not recorded,
+    /// so the interest of a period too short to round up keeps accumulating.
+    pub fn needs_interest_accrual(&self, timestamp: u64) -> bool {
+        if self.last_interest_accrual == 0 {
+            return self.has_interest_bearing_debt();
+        }
+        !self.accrued_interest(timestamp).is_empty()
+    }
+
+    /// Adds the interest accrued since the previous accrual to the debt of
+    /// every vault and credits it to the developer like the other fees.
+    pub fn accrue_interest(&mut self, timestamp: u64) -> ICUSD {
+        let accrued = self.accrued_interest(timestamp);
+        self.last_interest_accrual = timestamp;
+        let mut total_interest = ICUSD::new(0);
+        for (vault_id, interest) in accrued {
+            if let Some(vault) = self.vault_id_to_vaults.get_mut(&vault_id) {
+                vault.borrowed_icusd_amount += interest;
+                total_interest += interest;
+            }
+        }
+        self.provide_liquidity(total_interest, self.developer_principal);
+        total_interest
+    }
+
+    pub fn adjust_interest_rate(
+        &mut self,
+        vault_id: u64,
+        interest_rate: Ratio,
+        fee_amount: ICUSD,
+        timestamp: u64,
+    ) {
+        match self.vault_id_to_vaults.get_mut(&vault_id) {
+            Some(vault) => {
+                vault.interest_rate = interest_rate;
+                vault.last_interest_rate_adjustment = timestamp;
+                vault.borrowed_icusd_amount += fee_amount;
+            }
+            None => ic_cdk::trap("adjusting the interest rate of unknown vault"),
+        }
+        self.provide_liquidity(fee_amount, self.developer_principal);
+    }
+
+    pub fn check_redemptions_enabled(&self, now: u64) -> Result<(), ProtocolError> {
+        if now < self.redemption_bootstrap_end {
+            return Err(ProtocolError::OperationPaused {
+                reason: format!(
+                    "redemptions are disabled for another {} seconds",
+                    (self.redemption_bootstrap_end - now) / crate::SEC_NANOS
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_vault_redeemable(&self, vault: &Vault, now: u64) -> bool {
+        self.min_vault_age_for_redemption == 0
+            || now.saturating_sub(vault.opened_at) >= self.min_vault_age_for_redemption
+    }
+
+    /// Returns how a redemption of `icusd_amount` at `now` would be spread over
+    /// the vaults as `(vault_id, icusd, icp)`. Vaults paying the lowest interest
+    /// rate are redeemed first, ties going to the lowest collateral ratio.
+    /// Vaults younger than the minimum redemption age are skipped.
+    pub fn plan_redemption(
+        &self,
+        icusd_amount: ICUSD,
+        current_icp_rate: UsdIcp,
+        now: u64,
+    ) -> Vec<(VaultId, ICUSD, ICP)> {
+        let mut icusd_amount_to_convert = icusd_amount;
+        let mut vaults: BTreeSet<(Ratio, Ratio, VaultId)> = BTreeSet::new();
     
-        let vault_ids: Vec<VaultId> = vaults.iter().map(|(_cr, vault_id)| *vault_id).collect();
-        let mut index: usize = 0;
-    
-        while icusd_amount_to_convert > 0 && index < vault_ids.len() {
-            let vault = self.vault_id_to_vaults.get(&vault_ids[index]).unwrap();
+        for vault in self
+            .vault_id_to_vaults
+            .values()
+            .filter(|vault| self.is_vault_redeemable(vault, now))
+        {
+            vaults.insert((
+                vault.interest_rate,
+                crate::compute_collateral_ratio(vault, current_icp_rate),
+                vault.vault_id,
+            ));
+        }
     
-            if vault.borrowed_icusd_amount >= icusd_amount_to_convert {
-                // Convert everything on this vault
-                let redeemable_icp_amount: ICP = icusd_amount_to_convert / current_icp_rate;
-                self.deduct_amount_from_vault(
-                    redeemable_icp_amount,
-                    icusd_amount_to_convert,
-                    vault_ids[index],
-                );
+        let mut plan = vec![];
+        for (_rate, _cr, vault_id) in vaults {
+            if icusd_amount_to_convert == 0 {
                 break;
-            } else {
-                // Convert what we can on this vault
-                let redeemable_icusd_amount = vault.borrowed_icusd_amount;
-                let redeemable_icp_amount: ICP = redeemable_icusd_amount / current_icp_rate;
-                self.deduct_amount_from_vault(
-                    redeemable_icp_amount,
-                    redeemable_icusd_amount,
-                    vault_ids[index],
-                );
-                icusd_amount_to_convert -= redeemable_icusd_amount;
-                index += 1;
             }
+            let vault = self.vault_id_to_vaults.get(&vault_id).unwrap();
+            // The liquidation reserve can't be redeemed, only the net debt
+            let redeemable_icusd_amount = vault.net_debt().min(icusd_amount_to_convert);
+            if redeemable_icusd_amount == 0 {
+                continue;
+            }
+            let redeemable_icp_amount: ICP = redeemable_icusd_amount / current_icp_rate;
+            plan.push((vault_id, redeemable_icusd_amount, redeemable_icp_amount));
+            icusd_amount_to_convert -= redeemable_icusd_amount;
+        }
+        plan
+    }
+
+    /// Previews a redemption of `icusd_amount` at `now`, failing where
+    /// `redeem_icp` would. The ICP amount is the sum of the redemption plan.
+    pub fn preview_redemption(
+        &self,
+        icusd_amount: ICUSD,
+        current_icp_rate: UsdIcp,
+        now: u64,
+    ) -> Result<crate::RedemptionPreview, ProtocolError> {
+        self.check_redemptions_enabled(now)?;
+        let fee_rate = self.get_redemption_fee_at(icusd_amount, now);
+        let fee_amount = icusd_amount * fee_rate;
+        let redeemed_amount = icusd_amount - fee_amount;
+        let plan = self.plan_redemption(redeemed_amount, current_icp_rate, now);
+        let redeemable_amount: ICUSD = plan.iter().map(|(_, icusd, _)| *icusd).sum();
+        if redeemable_amount < redeemed_amount {
+            return Err(ProtocolError::GenericError(format!(
+                "only {redeemable_amount} icUSD can currently be redeemed"
+            )));
+        }
+        let icp_amount: ICP = plan.iter().map(|(_, _, icp)| *icp).sum();
+        let vaults = plan
+            .into_iter()
+            .map(|(vault_id, icusd_amount, icp_amount)| crate::RedeemedVault {
+                vault_id,
+                icusd_amount: icusd_amount.to_u64(),
+                icp_amount: icp_amount.to_u64(),
+            })
+            .collect();
+        Ok(crate::RedemptionPreview {
+            vaults,
+            icp_amount: icp_amount.to_u64(),
+            fee_amount: fee_amount.to_u64(),
+            fee_rate: fee_rate.to_f64(),
+            // redeem_icp stores the applied fee rate as the new base rate
+            resulting_base_rate: fee_rate.to_f64(),
+        })
+    }
+
+    pub fn redeem_on_vaults(
+        &mut self,
+        icusd_amount: ICUSD,
+        current_icp_rate: UsdIcp,
+        now: u64,
+    ) -> Vec<(VaultId, ICUSD, ICP)> {
+        let plan = self.plan_redemption(icusd_amount, current_icp_rate, now);
+        debug_assert_eq!(
+            plan.iter().map(|(_, icusd, _)| *icusd).sum::<ICUSD>(),
+            icusd_amount
+        );
+        for &(vault_id, icusd_amount, icp_amount) in &plan {
+            self.deduct_amount_from_vault(icp_amount, icusd_amount, vault_id);
+        }
+        plan
+    }
+
+    /// Redeems `icusd_amount` on the vaults and splits `fee_amount` between the
+    /// redeemed vaults and the developer. Returns the protocol's share of the fee.
+    pub fn settle_redemption(
+        &mut self,
+        icusd_amount: ICUSD,
+        fee_amount: ICUSD,
+        current_icp_rate: UsdIcp,
+        now: u64,
+    ) -> ICUSD {
+        let plan = self.redeem_on_vaults(icusd_amount, current_icp_rate, now);
+        let rebate_pool = fee_amount * self.redemption_rebate_share;
+        let mut distributed = ICUSD::new(0);
+        if rebate_pool > 0 && icusd_amount > 0 {
+            for (vault_id, redeemed_amount, _) in plan {
+                let rebate = rebate_pool * (redeemed_amount / icusd_amount);
+                if rebate == 0 {
+                    continue;
+                }
+                let owner = self.vault_id_to_vaults[&vault_id].owner;
+                self.redemption_rebates
+                    .entry(vault_id)
+                    .and_modify(|entry| entry.amount += rebate)
+                    .or_insert(RedemptionRebate {
+                        owner,
+                        amount: rebate,
+                    });
+                distributed += rebate;
+            }
+        }
+        let protocol_fee = fee_amount - distributed;
+        self.provide_liquidity(protocol_fee, self.developer_principal);
+        protocol_fee
+    }
+
+    /// Deducts a claimed `amount` from the rebate of `vault_id`. Rebates added
+    /// while the claim was being minted stay claimable.
+    pub fn claim_redemption_rebate(&mut self, vault_id: VaultId, amount: ICUSD) {
+        match self.redemption_rebates.entry(vault_id) {
+            Occupied(mut entry) => {
+                let remaining = entry.get().amount.saturating_sub(amount);
+                if remaining == 0 {
+                    entry.remove();
+                } else {
+                    entry.get_mut().amount = remaining;
+                }
+            }
+            Vacant(_) => ic_cdk::trap("claiming unknown redemption rebate"),
         }
-        debug_assert!(icusd_amount_to_convert == 0);
     }
     
+    pub fn add_liquidation_refund(&mut self, liquidator: Principal, amount: ICUSD) {
+        *self.liquidation_refunds.entry(liquidator).or_insert(ICUSD::new(0)) += amount;
+    }
+
+    pub fn claim_liquidation_refund(&mut self, liquidator: Principal, amount: ICUSD) {
+        match self.liquidation_refunds.entry(liquidator) {
+            Occupied(mut entry) => {
+                if amount > *entry.get() {
+                    ic_cdk::trap("BUG: claiming more liquidation refund than owed");
+                }
+                let remaining = *entry.get() - amount;
+                if remaining == 0 {
+                    entry.remove();
+                } else {
+                    *entry.get_mut() = remaining;
+                }
+            }
+            Vacant(_) => ic_cdk::trap("claiming unknown liquidation refund"),
+        }
+    }
+
+    pub fn get_liquidation_refund_of(&self, liquidator: Principal) -> ICUSD {
+        self.liquidation_refunds
+            .get(&liquidator)
+            .cloned()
+            .unwrap_or(ICUSD::new(0))
+    }
+
     fn deduct_amount_from_vault(
         &mut self,
         icp_amount_to_deduct: ICP,
@@ -551,6 +1631,7 @@ impl State {
                 vault.borrowed_icusd_amount -= icusd_amount_to_deduct;
                 assert!(vault.icp_margin_amount >= icp_amount_to_deduct);
                 vault.icp_margin_amount -= icp_amount_to_deduct;
+                vault.settle_liquidation_reserve();
             }
             None => ic_cdk::trap("cannot deduct from unknown vault"),
         }
@@ -562,16 +1643,34 @@ impl State {
             other.vault_id_to_vaults,
             "vault_id_to_vaults does not match"
         );
+        // Attempts and last errors are not part of the event log.
+        let queued = |state: &Self| {
+            state
+                .pending_transfers
+                .values()
+                .map(|transfer| PendingTransfer {
+                    attempts: 0,
+                    last_error: None,
+                    ..transfer.clone()
+                })
+                .collect::<Vec<_>>()
+        };
         ensure_eq!(
-            self.pending_margin_transfers,
-            other.pending_margin_transfers,
-            "pending_margin_transfers does not match"
+            queued(self),
+            queued(other),
+            "pending_transfers does not match"
         );
+        ensure_eq!(self.sagas, other.sagas, "sagas does not match");
         ensure_eq!(
             self.principal_to_vault_ids,
             other.principal_to_vault_ids,
             "principal_to_vault_ids does not match"
         );
+        ensure_eq!(
+            self.principal_to_opened_vault_ids,
+            other.principal_to_opened_vault_ids,
+            "principal_to_opened_vault_ids does not match"
+        );
         ensure_eq!(
             self.xrc_principal,
             other.xrc_principal,
@@ -618,35 +1717,150 @@ impl State {
         Ok(())
     }
 
-    pub fn mark_operation_failed(&mut self, operation_key: &str) {
-        if let Some(state) = self.operation_states.get_mut(operation_key) {
-            *state = OperationState::Failed;
+    pub fn begin_request(&mut self, key: RequestKey, now: u64) -> RequestStart {
+        self.recent_requests.retain(|_, entry| match entry {
+            RequestEntry::InFlight { started_at } => {
+                now.saturating_sub(*started_at) < REQUEST_ID_TTL_NANOS
+            }
+            RequestEntry::Completed { completed_at, .. } => {
+                now.saturating_sub(*completed_at) < REQUEST_ID_TTL_NANOS
+            }
+        });
+
+        match self.recent_requests.get(&key) {
+            // The call trapped after an await, it may have partly gone through
+            Some(RequestEntry::InFlight { started_at })
+                if now.saturating_sub(*started_at) >= IN_FLIGHT_TIMEOUT_NANOS =>
+            {
+                RequestStart::Unknown
+            }
+            Some(RequestEntry::InFlight { .. }) => RequestStart::InFlight,
+            Some(RequestEntry::Completed { result, .. }) => RequestStart::Completed(result.clone()),
+            None => {
+                if self.recent_requests.len() >= MAX_REMEMBERED_REQUESTS {
+                    let oldest = self
+                        .recent_requests
+                        .iter()
+                        .filter_map(|(key, entry)| match entry {
+                            RequestEntry::Completed { completed_at, .. } => {
+                                Some((*completed_at, key.clone()))
+                            }
+                            RequestEntry::InFlight { .. } => None,
+                        })
+                        .min();
+                    if let Some((_, oldest)) = oldest {
+                        self.recent_requests.remove(&oldest);
+                    }
+                }
+                self.recent_requests
+                    .insert(key, RequestEntry::InFlight { started_at: now });
+                RequestStart::New
+            }
+        }
+    }
+
+    pub fn complete_request(&mut self, key: RequestKey, result: Vec<u8>, now: u64) {
+        self.recent_requests.insert(
+            key,
+            RequestEntry::Completed {
+                result,
+                completed_at: now,
+            },
+        );
+    }
+
+    pub fn forget_request(&mut self, key: &RequestKey) {
+        self.recent_requests.remove(key);
+    }
+
+    /// Takes all of `keys` for `holder`, or none of them if any is held.
+    pub fn acquire_locks(
+        &mut self,
+        keys: &[LockKey],
+        holder: Principal,
+        operation: &str,
+        now: u64,
+        lease: u64,
+    ) -> Result<(), GuardError> {
+        self.release_expired_locks(now);
+        if self.locks.len() >= MAX_CONCURRENT {
+            return Err(GuardError::TooManyConcurrentRequests);
         }
+        self.extend_locks(keys, holder, operation, now, now, lease)
     }
-    
-    // Add method to clean up stale operations regularly
-    pub fn clean_stale_operations(&mut self) {
-        // Get the current time
-        let now = ic_cdk::api::time();
-        
-        // Find any operations that are stale (older than 3 minutes)
-        const STALE_OPERATION_NANOS: u64 = 3 * 60 * SEC_NANOS;
-        
-        // Check for stale processing state based on actual Mode variants
-        // Mode is likely either GeneralAvailability, Recovery, or ReadOnly
-        if let Mode::Recovery = self.mode {
-            // If in recovery mode for too long, consider resetting
-            if let Some(last_timestamp) = self.last_icp_timestamp {
-                let age = now - last_timestamp;
-                
-                // If operation has been in processing mode for too long, reset it
-                if age > STALE_OPERATION_NANOS {
-                    log!(INFO, "[clean_stale_operations] Found stale recovery state, resetting mode to GeneralAvailability");
-                    self.mode = Mode::GeneralAvailability;
+
+    /// Adds `keys` to the locks that `holder` acquired at `acquired_at`.
+    pub fn extend_locks(
+        &mut self,
+        keys: &[LockKey],
+        holder: Principal,
+        operation: &str,
+        acquired_at: u64,
+        now: u64,
+        lease: u64,
+    ) -> Result<(), GuardError> {
+        self.release_expired_locks(now);
+        if let Some(key) = keys.iter().find(|key| self.locks.contains_key(key)) {
+            return Err(match key {
+                LockKey::Caller { .. } => GuardError::AlreadyProcessing,
+                LockKey::Vault(vault_id) => GuardError::VaultLocked(*vault_id),
+            });
+        }
+        for key in keys {
+            self.locks.insert(
+                key.clone(),
+                Lock {
+                    key: key.clone(),
+                    holder,
+                    operation: operation.to_string(),
+                    acquired_at,
+                    expires_at: now.saturating_add(lease),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Releases the locks in `keys` that are still held by the guard of
+    /// `holder` acquired at `acquired_at`, and not by someone that took them
+    /// over after their lease ran out.
+    pub fn release_locks(&mut self, keys: &[LockKey], holder: Principal, acquired_at: u64) {
+        for key in keys {
+            if let Occupied(entry) = self.locks.entry(key.clone()) {
+                if entry.get().holder == holder && entry.get().acquired_at == acquired_at {
+                    entry.remove();
                 }
             }
         }
-        
+    }
+
+    pub fn release_expired_locks(&mut self, now: u64) -> usize {
+        let before = self.locks.len();
+        self.locks.retain(|_, lock| !lock.is_expired(now));
+        before - self.locks.len()
+    }
+
+    pub fn is_locked(&self, key: &LockKey, now: u64) -> bool {
+        self.locks
+            .get(key)
+            .map(|lock| !lock.is_expired(now))
+            .unwrap_or(false)
+    }
+
+    pub fn get_held_locks(&self, now: u64) -> Vec<Lock> {
+        self.locks
+            .values()
+            .filter(|lock| !lock.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    // Add method to clean up stale operations regularly
+    pub fn clean_stale_operations(&mut self, now: u64) {
+        let released = self.release_expired_locks(now);
+        if released > 0 {
+            log!(INFO, "[clean_stale_operations] Released {} expired locks", released);
+        }
         // Clean up inconsistent vault ID mappings to prevent panics
         self.clean_inconsistent_vault_mappings();
     }
@@ -758,6 +1972,11 @@ pub(crate) fn distribute_across_vaults(
 }
 
 
+fn decay_base_rate(current_base_rate: Ratio, elapsed_hours: u64) -> Ratio {
+    const DECAY_FACTOR: Ratio = Ratio::new(dec!(0.94));
+    current_base_rate * DECAY_FACTOR.pow(elapsed_hours)
+}
+
 fn compute_redemption_fee(
     elapsed_hours: u64,
     redeemed_amount: ICUSD,
@@ -768,14 +1987,13 @@ fn compute_redemption_fee(
         return Ratio::from(Decimal::ZERO);
     }
     const REEDEMED_PROPORTION: Ratio = Ratio::new(dec!(0.5)); // 0.5
-    const DECAY_FACTOR: Ratio = Ratio::new(dec!(0.94));
 
     log!(
         crate::INFO,
         "current_base_rate: {current_base_rate}, elapsed_hours: {elapsed_hours}"
     );
 
-    let rate = current_base_rate * DECAY_FACTOR.pow(elapsed_hours);
+    let rate = decay_base_rate(current_base_rate, elapsed_hours);
     let total_rate = rate + redeemed_amount / total_borrowed_icusd_amount * REEDEMED_PROPORTION;
     debug_assert!(total_rate < Ratio::from(dec!(1.0)));
     total_rate
@@ -783,6 +2001,10 @@ fn compute_redemption_fee(
         .min(Ratio::from(dec!(0.05)))
 }
 
+fn compute_borrowing_fee(elapsed_hours: u64, current_base_rate: Ratio, floor: Ratio) -> Ratio {
+    (floor + decay_base_rate(current_base_rate, elapsed_hours)).min(MAX_BORROWING_FEE)
+}
+
 
 
 pub fn mutate_state<F, R>(f: F) -> R
@@ -823,6 +2045,10 @@ mod tests {
             vault_id: 1,
             icp_margin_amount: ICP::new(500_000),
             borrowed_icusd_amount: ICUSD::new(300_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         };
         
         let vault2 = Vault {
@@ -830,6 +2056,10 @@ mod tests {
             vault_id: 2, 
             icp_margin_amount: ICP::new(300_000),
             borrowed_icusd_amount: ICUSD::new(200_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         };
 
         vaults.insert(1, vault1);
@@ -840,6 +2070,10 @@ mod tests {
             vault_id: 3,
             icp_margin_amount: ICP::new(700_000),
             borrowed_icusd_amount: ICUSD::new(400_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         };
 
         let result = distribute_across_vaults(&vaults, target_vault);
@@ -850,4 +2084,1301 @@ mod tests {
         assert_eq!(result[1].icp_share_amount, ICP::new(262_500));
         assert_eq!(result[1].icusd_share_amount, ICUSD::new(150_000));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mode_hysteresis() {
+        let ratio = |r| Ratio::from(r);
+
+        assert_eq!(next_mode(Mode::GeneralAvailability, ratio(dec!(1.6))), Mode::GeneralAvailability);
+        assert_eq!(next_mode(Mode::GeneralAvailability, ratio(dec!(1.49))), Mode::Recovery);
+        // Back above the entry threshold but still inside the band.
+        assert_eq!(next_mode(Mode::Recovery, ratio(dec!(1.52))), Mode::Recovery);
+        assert_eq!(next_mode(Mode::Recovery, ratio(dec!(1.55))), Mode::GeneralAvailability);
+
+        assert_eq!(next_mode(Mode::Recovery, ratio(dec!(0.99))), Mode::ReadOnly);
+        assert_eq!(next_mode(Mode::ReadOnly, ratio(dec!(1.02))), Mode::ReadOnly);
+        assert_eq!(next_mode(Mode::ReadOnly, ratio(dec!(1.1))), Mode::Recovery);
+        assert_eq!(next_mode(Mode::ReadOnly, ratio(dec!(2.0))), Mode::GeneralAvailability);
+    }
+
+    #[test]
+    fn test_borrowing_fee_follows_base_rate() {
+        let floor = Ratio::from(dec!(0.005));
+
+        assert_eq!(compute_borrowing_fee(0, Ratio::from(dec!(0)), floor), floor);
+        assert_eq!(
+            compute_borrowing_fee(0, Ratio::from(dec!(0.01)), floor),
+            Ratio::from(dec!(0.015))
+        );
+        // Decays with the same factor as the redemption fee.
+        assert_eq!(
+            compute_borrowing_fee(1, Ratio::from(dec!(0.01)), floor),
+            Ratio::from(dec!(0.0144))
+        );
+        assert_eq!(
+            compute_borrowing_fee(0, Ratio::from(dec!(0.2)), floor),
+            MAX_BORROWING_FEE
+        );
+    }
+
+    fn test_state() -> State {
+        State::from(InitArg {
+            xrc_principal: Principal::anonymous(),
+            icusd_ledger_principal: Principal::anonymous(),
+            icp_ledger_principal: Principal::anonymous(),
+            fee_e8s: 500_000,
+            developer_principal: Principal::anonymous(),
+            treasury_principal: None,
+            stability_pool_principal: None,
+        })
+    }
+
+    #[test]
+    fn test_recovery_mode_rules() {
+        let mut state = test_state();
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        // 10 ICP at $10 backing 80 icUSD: 125%.
+        let vault = Vault {
+            owner: Principal::anonymous(),
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(8_000_000_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        };
+        state.open_vault(vault.clone());
+        state.mode = Mode::Recovery;
+
+        let fresh_vault = Vault {
+            owner: Principal::anonymous(),
+            vault_id: 2,
+            icp_margin_amount: ICP::new(100_000_000),
+            borrowed_icusd_amount: ICUSD::new(0),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        };
+        assert!(state.check_recovery_mode_rules(&fresh_vault).is_ok());
+
+        let borrowed_more = Vault {
+            borrowed_icusd_amount: ICUSD::new(9_000_000_000),
+            ..vault.clone()
+        };
+        assert!(matches!(
+            state.check_recovery_mode_rules(&borrowed_more),
+            Err(ProtocolError::RecoveryModeViolation(
+                RecoveryModeViolation::VaultBelowRecoveryRatio { .. }
+            ))
+        ));
+
+        state.open_vault(fresh_vault.clone());
+        let withdrawn = Vault {
+            icp_margin_amount: ICP::new(0),
+            ..fresh_vault
+        };
+        assert!(matches!(
+            state.check_recovery_mode_rules(&withdrawn),
+            Err(ProtocolError::RecoveryModeViolation(
+                RecoveryModeViolation::TotalCollateralRatioDecreased { .. }
+            ))
+        ));
+
+        state.mode = Mode::GeneralAvailability;
+        assert!(state.check_recovery_mode_rules(&borrowed_more).is_ok());
+
+        // Outside of Recovery mode no rate is needed.
+        state.last_icp_rate = None;
+        assert!(state.check_recovery_mode_rules(&borrowed_more).is_ok());
+        state.mode = Mode::Recovery;
+        assert!(matches!(
+            state.check_recovery_mode_rules(&borrowed_more),
+            Err(ProtocolError::TemporarilyUnavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_liquidation_reserve_settled_on_repay_and_redemption() {
+        let mut state = test_state();
+        let icp_rate = UsdIcp::from(dec!(10.0));
+        for vault_id in [1, 2] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(0),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+            state.borrow_from_vault(vault_id, ICUSD::new(2_000_000_000), crate::LIQUIDATION_RESERVE);
+        }
+        let vault = state.vault_id_to_vaults.get(&1).unwrap();
+        assert_eq!(vault.borrowed_icusd_amount, ICUSD::new(2_200_000_000));
+        assert_eq!(vault.net_debt(), ICUSD::new(2_000_000_000));
+
+        state.repay_to_vault(1, ICUSD::new(2_000_000_000));
+        let vault = state.vault_id_to_vaults.get(&1).unwrap();
+        assert_eq!(vault.borrowed_icusd_amount, ICUSD::new(0));
+        assert_eq!(vault.liquidation_reserve, ICUSD::new(0));
+
+        // Redemptions only take the net debt and release the reserve with it.
+        state.redeem_on_vaults(ICUSD::new(2_000_000_000), icp_rate, 0);
+        let vault = state.vault_id_to_vaults.get(&2).unwrap();
+        assert_eq!(vault.borrowed_icusd_amount, ICUSD::new(0));
+        assert_eq!(vault.icp_margin_amount, ICP::new(800_000_000));
+    }
+
+    #[test]
+    fn test_plan_redemption_matches_redeem_on_vaults() {
+        let mut state = test_state();
+        let icp_rate = UsdIcp::from(dec!(10.0));
+        for (vault_id, debt) in [(1, 3_000_000_000), (2, 5_000_000_000), (3, 1_000_000_000)] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(debt),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+
+        let plan = state.plan_redemption(ICUSD::new(6_000_000_000), icp_rate, 0);
+        assert_eq!(
+            plan,
+            vec![
+                (2, ICUSD::new(5_000_000_000), ICP::new(500_000_000)),
+                (1, ICUSD::new(1_000_000_000), ICP::new(100_000_000)),
+            ]
+        );
+
+        state.redeem_on_vaults(ICUSD::new(6_000_000_000), icp_rate, 0);
+        let debts: Vec<(ICUSD, ICP)> = state
+            .vault_id_to_vaults
+            .values()
+            .map(|vault| (vault.borrowed_icusd_amount, vault.icp_margin_amount))
+            .collect();
+        assert_eq!(
+            debts,
+            vec![
+                (ICUSD::new(2_000_000_000), ICP::new(900_000_000)),
+                (ICUSD::new(0), ICP::new(500_000_000)),
+                (ICUSD::new(1_000_000_000), ICP::new(1_000_000_000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_interest_rates() {
+        let mut state = test_state();
+        let icp_rate = UsdIcp::from(dec!(10.0));
+        for (vault_id, rate) in [(1, dec!(0.10)), (2, dec!(0.02))] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                // Vault 2 is the better collateralized one
+                icp_margin_amount: ICP::new(vault_id * 1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(5_000_000_000),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::from(rate),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+
+        // The lowest rate is redeemed first, whatever the collateral ratio.
+        let plan = state.plan_redemption(ICUSD::new(1_000_000_000), icp_rate, 0);
+        assert_eq!(plan[0].0, 2);
+
+        // The first accrual only starts the clock.
+        assert!(state.needs_interest_accrual(1));
+        assert_eq!(state.accrue_interest(1), ICUSD::new(0));
+        assert!(!state.needs_interest_accrual(2));
+        assert!(state.needs_interest_accrual(1 + crate::YEAR_NANOS));
+        let interest = state.accrue_interest(1 + crate::YEAR_NANOS);
+        assert_eq!(interest, ICUSD::new(600_000_000));
+        assert_eq!(
+            state.vault_id_to_vaults.get(&1).unwrap().borrowed_icusd_amount,
+            ICUSD::new(5_500_000_000)
+        );
+
+        // Adjusting again within the cooldown costs a week of interest.
+        let vault = state.vault_id_to_vaults.get(&2).unwrap().clone();
+        let now = 2 * crate::YEAR_NANOS;
+        assert_eq!(
+            vault.interest_rate_adjustment_fee(Ratio::from(dec!(0.05)), now),
+            ICUSD::new(0)
+        );
+        state.adjust_interest_rate(2, Ratio::from(dec!(0.05)), ICUSD::new(0), now);
+        let vault = state.vault_id_to_vaults.get(&2).unwrap();
+        assert!(vault.interest_rate_adjustment_fee(Ratio::from(dec!(0.05)), now + 1) > 0);
+
+        // The fee is new debt and needs a price and free debt capacity.
+        use crate::vault::{validate_interest_rate_adjustment, AdjustInterestRateArg};
+        let arg = AdjustInterestRateArg { vault_id: 2, interest_rate: 0.05 };
+        let owner = Principal::anonymous();
+        state.last_icp_rate = None;
+        assert!(matches!(
+            validate_interest_rate_adjustment(&state, owner, &arg, now + 1),
+            Err(ProtocolError::TemporarilyUnavailable(_))
+        ));
+        state.last_icp_rate = Some(icp_rate);
+        assert!(validate_interest_rate_adjustment(&state, owner, &arg, now + 1).is_ok());
+        state.set_debt_ceiling(None, Some(state.total_borrowed_icusd_amount()));
+        assert!(matches!(
+            validate_interest_rate_adjustment(&state, owner, &arg, now + 1),
+            Err(ProtocolError::DebtCeilingReached { remaining_capacity: 0 })
+        ));
+        // Without a fee nothing is borrowed.
+        assert!(validate_interest_rate_adjustment(&state, owner, &arg, now + crate::YEAR_NANOS).is_ok());
+    }
+
+    #[test]
+    fn test_redemption_bootstrap_and_vault_age() {
+        let mut state = test_state();
+        let icp_rate = UsdIcp::from(dec!(10.0));
+        for (vault_id, opened_at) in [(1, 0), (2, 1_000)] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(3_000_000_000),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at,
+            });
+        }
+
+        state.set_redemption_bootstrap_end(500);
+        assert!(state.check_redemptions_enabled(499).is_err());
+        assert!(state.check_redemptions_enabled(500).is_ok());
+
+        state.set_min_vault_age_for_redemption(600);
+        let plan = state.plan_redemption(ICUSD::new(5_000_000_000), icp_rate, 1_500);
+        assert_eq!(plan, vec![(1, ICUSD::new(3_000_000_000), ICP::new(300_000_000))]);
+        let plan = state.plan_redemption(ICUSD::new(5_000_000_000), icp_rate, 1_600);
+        assert_eq!(plan.len(), 2);
+    }
+
+    #[test]
+    fn test_redemption_rebates() {
+        let mut state = test_state();
+        let icp_rate = UsdIcp::from(dec!(10.0));
+        for vault_id in [1, 2] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(3_000_000_000),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+
+        // Disabled by default: the whole fee goes to the protocol.
+        let protocol_fee =
+            state.settle_redemption(ICUSD::new(1_000_000_000), ICUSD::new(10_000_000), icp_rate, 0);
+        assert_eq!(protocol_fee, ICUSD::new(10_000_000));
+        assert!(state.redemption_rebates.is_empty());
+
+        state.redemption_rebate_share = Ratio::from(dec!(0.5));
+        let protocol_fee =
+            state.settle_redemption(ICUSD::new(4_000_000_000), ICUSD::new(40_000_000), icp_rate, 0);
+        assert_eq!(protocol_fee, ICUSD::new(20_000_000));
+        // Vault 2 is redeemed for 3 000 000 000 and vault 1 for the rest.
+        assert_eq!(state.redemption_rebates[&1].amount, ICUSD::new(5_000_000));
+        assert_eq!(state.redemption_rebates[&2].amount, ICUSD::new(15_000_000));
+
+        // A rebate added while a claim is in flight stays claimable.
+        let claimed = state.redemption_rebates[&2].amount;
+        state.redemption_rebates.get_mut(&2).unwrap().amount += ICUSD::new(1_000_000);
+        state.claim_redemption_rebate(2, claimed);
+        assert_eq!(state.redemption_rebates[&2].amount, ICUSD::new(1_000_000));
+
+        state.claim_redemption_rebate(1, ICUSD::new(5_000_000));
+        assert!(!state.redemption_rebates.contains_key(&1));
+    }
+
+    #[test]
+    fn test_flash_mint_cap() {
+        let mut state = test_state();
+        let borrower = Principal::anonymous();
+        assert!(state.reserve_flash_mint(borrower, ICUSD::new(100_000_000)).is_err());
+
+        state.set_flash_mint_params(ICUSD::new(1_000_000_000), Ratio::from(dec!(0.001)));
+        // Only allowlisted canisters can flash mint.
+        assert!(state.reserve_flash_mint(borrower, ICUSD::new(100_000_000)).is_err());
+        state.set_flash_mint_borrower(borrower, true);
+        assert_eq!(
+            state.reserve_flash_mint(borrower, ICUSD::new(600_000_000)).unwrap(),
+            ICUSD::new(600_000)
+        );
+        // The in-flight mint counts against the cap.
+        assert!(state.reserve_flash_mint(borrower, ICUSD::new(600_000_000)).is_err());
+        state.release_flash_mint(ICUSD::new(600_000_000));
+        assert!(state.reserve_flash_mint(borrower, ICUSD::new(600_000_000)).is_ok());
+        state.release_flash_mint(ICUSD::new(600_000_000));
+
+        assert!(state.reserve_flash_mint(borrower, ICUSD::new(600_000_000)).is_ok());
+        state.default_flash_mint(borrower, ICUSD::new(600_000_000), ICUSD::new(600_600_000));
+        assert!(state.reserve_flash_mint(borrower, ICUSD::new(100_000_000)).is_err());
+
+        // The default still counts against the cap and the debt ceilings,
+        // whoever borrows next.
+        let other = Principal::from_slice(&[1]);
+        state.set_flash_mint_borrower(other, true);
+        assert!(state.reserve_flash_mint(other, ICUSD::new(600_000_000)).is_err());
+        assert_eq!(state.flash_mint_exposure(), ICUSD::new(600_600_000));
+        state.set_debt_ceiling(None, Some(ICUSD::new(700_000_000)));
+        assert!(matches!(
+            state.reserve_flash_mint(other, ICUSD::new(200_000_000)),
+            Err(ProtocolError::DebtCeilingReached { remaining_capacity: 99_400_000 })
+        ));
+        assert!(state.reserve_flash_mint(other, ICUSD::new(99_000_000)).is_ok());
+    }
+
+    #[test]
+    fn test_bad_debt() {
+        let mut state = test_state();
+        for (vault_id, margin) in [(1, 1_000_000_000), (2, 2_000_000_000), (3, 0)] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(margin),
+                borrowed_icusd_amount: ICUSD::new(0),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+
+        state.absorb_bad_debt(ICUSD::new(400_000_000));
+        assert_eq!(state.outstanding_bad_debt, ICUSD::new(400_000_000));
+        state.cover_bad_debt(ICUSD::new(100_000_000));
+        assert_eq!(state.outstanding_bad_debt, ICUSD::new(300_000_000));
+
+        state.set_bad_debt_policy(BadDebtPolicy::Redistribution);
+        state.absorb_bad_debt(ICUSD::new(400_000_000));
+        assert_eq!(state.outstanding_bad_debt, ICUSD::new(300_000_000));
+        assert_eq!(state.cumulative_bad_debt, ICUSD::new(800_000_000));
+        // A third and two thirds, the remainder going to the largest vault
+        // and nothing to the vault without collateral.
+        assert_eq!(state.vault_id_to_vaults[&1].borrowed_icusd_amount, ICUSD::new(133_333_333));
+        assert_eq!(state.vault_id_to_vaults[&2].borrowed_icusd_amount, ICUSD::new(266_666_667));
+        assert_eq!(state.vault_id_to_vaults[&3].borrowed_icusd_amount, ICUSD::new(0));
+    }
+
+    #[test]
+    fn test_debt_ceilings() {
+        let mut state = test_state();
+        state.open_vault(Vault {
+            owner: Principal::anonymous(),
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(4_000_000_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        });
+        assert_eq!(state.remaining_debt_capacity(CollateralType::ICP), None);
+
+        state.set_debt_ceiling(None, Some(ICUSD::new(10_000_000_000)));
+        state.set_debt_ceiling(Some(CollateralType::ICP), Some(ICUSD::new(5_000_000_000)));
+        assert_eq!(
+            state.remaining_debt_capacity(CollateralType::ICP),
+            Some(ICUSD::new(1_000_000_000))
+        );
+
+        assert!(state.reserve_debt(CollateralType::ICP, ICUSD::new(600_000_000)).is_ok());
+        // The in-flight reservation counts against the ceiling.
+        assert!(matches!(
+            state.reserve_debt(CollateralType::ICP, ICUSD::new(600_000_000)),
+            Err(ProtocolError::DebtCeilingReached { remaining_capacity: 400_000_000 })
+        ));
+        state.release_debt_reservation(ICUSD::new(600_000_000));
+        assert_eq!(
+            state.remaining_debt_capacity(CollateralType::ICP),
+            Some(ICUSD::new(1_000_000_000))
+        );
+
+        state.set_debt_ceiling(Some(CollateralType::ICP), None);
+        assert_eq!(
+            state.remaining_debt_capacity(CollateralType::ICP),
+            Some(ICUSD::new(6_000_000_000))
+        );
+    }
+
+    #[test]
+    fn test_pending_transfer_queue() {
+        let mut state = test_state();
+        let owner = Principal::from_slice(&[1]);
+        let liquidator = Principal::from_slice(&[2]);
+        state.open_vault(Vault {
+            owner,
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(0),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        });
+
+        // Two transfers for the same vault no longer overwrite each other.
+        let reward = state.enqueue_transfer(
+            1,
+            TransferPurpose::LiquidationReward,
+            liquidator,
+            ICP::new(300_000_000),
+            10,
+        );
+        let excess = state.enqueue_transfer(
+            1,
+            TransferPurpose::LiquidationExcess,
+            owner,
+            ICP::new(100_000_000),
+            10,
+        );
+        assert_ne!(reward, excess);
+        state.close_vault(1, 20);
+        assert_eq!(state.pending_transfers.len(), 3);
+        assert_eq!(state.get_pending_transfers_of(owner).len(), 2);
+
+        state.record_transfer_failure(reward, "ledger unavailable".to_string());
+        let pending = &state.get_pending_transfers_of(liquidator)[0];
+        assert_eq!(pending.attempts, 1);
+        assert_eq!(pending.last_error.as_deref(), Some("ledger unavailable"));
+
+        state.complete_transfer(reward);
+        assert!(state.get_pending_transfers_of(liquidator).is_empty());
+        // Legacy margin transfers complete the oldest transfer of the vault.
+        state.complete_vault_transfer(1);
+        let remaining = state.get_pending_transfers_of(owner);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].purpose, TransferPurpose::VaultClosed);
+        assert_eq!(remaining[0].amount, ICP::new(1_000_000_000));
+        assert_eq!(remaining[0].created_at, 20);
+    }
+
+    #[test]
+    fn test_transfer_dedup_window() {
+        use crate::{dedup_window_expired, TRANSFER_DEDUP_WINDOW_NANOS};
+
+        assert!(!dedup_window_expired(10, 10 + TRANSFER_DEDUP_WINDOW_NANOS - 1));
+        assert!(dedup_window_expired(10, 10 + TRANSFER_DEDUP_WINDOW_NANOS));
+        // Transfers queued without a creation time can't be deduplicated.
+        assert!(dedup_window_expired(0, 1));
+
+        let mut state = test_state();
+        let owner = Principal::from_slice(&[1]);
+        let transfer_id = state.enqueue_transfer(
+            1,
+            TransferPurpose::VaultClosed,
+            owner,
+            ICP::new(100_000_000),
+            10,
+        );
+        let redemption_owner = Principal::from_slice(&[2]);
+        state.queue_redemption_transfer(7, redemption_owner, ICP::new(50_000_000), 10);
+
+        // A new ledger fee doesn't change the amount of queued transfers.
+        state.set_icp_ledger_fee(ICP::new(20_000));
+        assert_eq!(state.pending_transfers[&transfer_id].fee, ICP_TRANSFER_FEE);
+        assert_eq!(state.pending_redemption_transfer[&7].fee, ICP_TRANSFER_FEE);
+
+        // Queuing them again starts a new transfer at the current fee.
+        state.record_transfer_failure(transfer_id, "bad fee".to_string());
+        state.requeue_transfer(transfer_id, 50);
+        let transfer = &state.pending_transfers[&transfer_id];
+        assert_eq!(transfer.fee, ICP::new(20_000));
+        assert_eq!(transfer.created_at, 50);
+        assert_eq!(transfer.attempts, 0);
+        assert_eq!(transfer.last_error, None);
+        state.requeue_redemption_transfer(7, 50);
+        assert_eq!(state.pending_redemption_transfer[&7].fee, ICP::new(20_000));
+        assert_eq!(state.pending_redemption_transfer[&7].created_at, 50);
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let mut state = test_state();
+        let key = (
+            Principal::anonymous(),
+            "borrow_from_vault".to_string(),
+            "request-1".to_string(),
+        );
+
+        assert_eq!(state.begin_request(key.clone(), 0), RequestStart::New);
+        assert_eq!(state.begin_request(key.clone(), 1), RequestStart::InFlight);
+        state.complete_request(key.clone(), vec![1, 2, 3], 2);
+        assert_eq!(
+            state.begin_request(key.clone(), 3),
+            RequestStart::Completed(vec![1, 2, 3])
+        );
+        // The same id on another endpoint is a different request.
+        let other_method = (key.0, "add_margin_to_vault".to_string(), key.2.clone());
+        assert_eq!(state.begin_request(other_method, 3), RequestStart::New);
+
+        // Completed requests are forgotten after the TTL.
+        assert_eq!(
+            state.begin_request(key.clone(), 2 + REQUEST_ID_TTL_NANOS),
+            RequestStart::New
+        );
+        // A failed call can be retried with the same id.
+        state.forget_request(&key);
+        assert_eq!(
+            state.begin_request(key.clone(), 3 + REQUEST_ID_TTL_NANOS),
+            RequestStart::New
+        );
+        // A call that trapped while in flight is not run again.
+        assert_eq!(
+            state.begin_request(key.clone(), 3 + REQUEST_ID_TTL_NANOS + IN_FLIGHT_TIMEOUT_NANOS),
+            RequestStart::Unknown
+        );
+        assert_eq!(
+            state.begin_request(key.clone(), 3 + 2 * REQUEST_ID_TTL_NANOS - 1),
+            RequestStart::Unknown
+        );
+        assert_eq!(
+            state.begin_request(key, 3 + 2 * REQUEST_ID_TTL_NANOS),
+            RequestStart::New
+        );
+    }
+
+    #[test]
+    fn test_withdraw_saga_holds_and_restores_margin() {
+        let mut state = test_state();
+        let owner = Principal::from_slice(&[1]);
+        state.open_vault(Vault {
+            owner,
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(0),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        });
+
+        let saga_id = state.start_saga(
+            SagaKind::WithdrawAndCloseVault {
+                owner,
+                vault_id: 1,
+                amount: ICP::new(1_000_000_000),
+            },
+            10,
+        );
+        assert_eq!(state.vault_id_to_vaults[&1].icp_margin_amount, ICP::new(0));
+        assert!(state.get_sagas_of(owner)[0].is_pending());
+
+        state.update_saga(
+            saga_id,
+            SagaStatus::Compensated {
+                reason: "insufficient funds".to_string(),
+            },
+            20,
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&1].icp_margin_amount,
+            ICP::new(1_000_000_000)
+        );
+        assert_eq!(state.sagas[&saga_id].updated_at, 20);
+
+        // Withdrawing the collateral holds it the same way but keeps the vault.
+        let saga_id = state.start_saga(
+            SagaKind::WithdrawCollateral {
+                owner,
+                vault_id: 1,
+                amount: ICP::new(1_000_000_000),
+            },
+            30,
+        );
+        assert_eq!(state.vault_id_to_vaults[&1].icp_margin_amount, ICP::new(0));
+        state.update_saga(
+            saga_id,
+            SagaStatus::Compensated {
+                reason: "insufficient funds".to_string(),
+            },
+            40,
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&1].icp_margin_amount,
+            ICP::new(1_000_000_000)
+        );
+
+        // Only the most recent finished sagas are kept.
+        for _ in 0..MAX_FINISHED_SAGAS {
+            let saga_id = state.start_saga(
+                SagaKind::OpenVault {
+                    owner,
+                    icp_margin: ICP::new(100_000_000),
+                },
+                30,
+            );
+            state.update_saga(
+                saga_id,
+                SagaStatus::Failed {
+                    reason: "insufficient allowance".to_string(),
+                },
+                30,
+            );
+        }
+        assert_eq!(state.sagas.len(), MAX_FINISHED_SAGAS);
+        assert!(!state.sagas.contains_key(&saga_id));
+    }
+
+    #[test]
+    fn test_vault_locks() {
+        let mut state = test_state();
+        let owner = Principal::from_slice(&[1]);
+        let liquidator = Principal::from_slice(&[2]);
+        let vault = [LockKey::Vault(1)];
+        let lease = 100;
+
+        state.acquire_locks(&vault, owner, "borrow_from_vault", 0, lease).unwrap();
+        // The vault is locked for everyone, not only for its owner.
+        assert_eq!(
+            state.acquire_locks(&vault, liquidator, "liquidate_vault", 10, lease),
+            Err(GuardError::VaultLocked(1))
+        );
+        assert!(state.is_locked(&LockKey::Vault(1), 10));
+        assert_eq!(state.get_held_locks(10).len(), 1);
+
+        // An expired lease frees the vault, and releasing the old guard
+        // leaves the new holder's lock in place.
+        state.acquire_locks(&vault, liquidator, "liquidate_vault", lease, lease).unwrap();
+        state.release_locks(&vault, owner, 0);
+        assert_eq!(state.get_held_locks(lease)[0].holder, liquidator);
+        state.release_locks(&vault, liquidator, lease);
+        assert!(!state.is_locked(&LockKey::Vault(1), lease));
+
+        // Taking several locks is all or nothing.
+        let caller = LockKey::Caller {
+            principal: owner,
+            operation: "liquidate_vaults".to_string(),
+        };
+        state.acquire_locks(&[LockKey::Vault(2)], liquidator, "close_vault", 0, lease).unwrap();
+        assert_eq!(
+            state.acquire_locks(
+                &[caller.clone(), LockKey::Vault(3), LockKey::Vault(2)],
+                owner,
+                "liquidate_vaults",
+                1,
+                lease
+            ),
+            Err(GuardError::VaultLocked(2))
+        );
+        assert!(!state.is_locked(&caller, 1));
+        assert!(!state.is_locked(&LockKey::Vault(3), 1));
+        state.acquire_locks(&[caller.clone()], owner, "liquidate_vaults", 1, lease).unwrap();
+        assert_eq!(
+            state.acquire_locks(&[caller], owner, "liquidate_vaults", 2, lease),
+            Err(GuardError::AlreadyProcessing)
+        );
+    }
+
+    #[test]
+    fn test_simulations_use_update_validation() {
+        use crate::vault::{
+            simulate_borrow, simulate_liquidation, simulate_partial_liquidation, simulate_repay,
+            simulate_withdraw, VaultArg,
+        };
+
+        let mut state = test_state();
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        let owner = Principal::from_slice(&[1]);
+        // 10 ICP at $10 backing 80 icUSD: 125%.
+        state.open_vault(Vault {
+            owner,
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(8_000_000_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        });
+
+        let repay = simulate_repay(&state, owner, VaultArg { vault_id: 1, amount: 4_000_000_000 }).unwrap();
+        assert_eq!(repay.vault.borrowed_icusd_amount, 4_000_000_000);
+        assert_eq!(repay.collateral_ratio, 2.5);
+        // 40 icUSD at the 133% minimum over 10 ICP.
+        let minimum = MINIMUM_COLLATERAL_RATIO.to_f64();
+        assert!((repay.liquidation_price.unwrap() - 4.0 * minimum).abs() < 1e-6);
+        // The state is left untouched.
+        assert_eq!(state.vault_id_to_vaults[&1].borrowed_icusd_amount, ICUSD::new(8_000_000_000));
+
+        assert!(matches!(
+            simulate_repay(&state, Principal::anonymous(), VaultArg { vault_id: 1, amount: 4_000_000_000 }),
+            Err(ProtocolError::CallerNotOwner)
+        ));
+        assert!(matches!(
+            simulate_borrow(&state, owner, VaultArg { vault_id: 1, amount: 1_000_000_000 }),
+            Err(ProtocolError::CollateralRatioTooLow { .. })
+        ));
+        assert!(matches!(
+            simulate_withdraw(&state, owner, 2),
+            Err(ProtocolError::VaultNotFound { vault_id: 2 })
+        ));
+        assert!(matches!(simulate_withdraw(&state, owner, 1), Err(ProtocolError::GenericError(_))));
+
+        let liquidation = simulate_liquidation(&state, 1).unwrap();
+        assert_eq!(liquidation.debt_amount, 8_000_000_000);
+        assert_eq!(liquidation.icp_to_liquidator + liquidation.excess_collateral, 1_000_000_000);
+        // At most half of the debt, for its value in ICP plus 10%.
+        let partial = simulate_partial_liquidation(&state, 1, 6_000_000_000).unwrap();
+        assert_eq!(partial.debt_amount, 4_000_000_000);
+        assert_eq!(partial.icp_to_liquidator, 440_000_000);
+        assert_eq!(partial.fee_amount, 400_000_000);
+        state.last_icp_rate = Some(UsdIcp::from(dec!(20.0)));
+        assert!(matches!(
+            simulate_liquidation(&state, 1),
+            Err(ProtocolError::NotLiquidatable { .. })
+        ));
+        assert!(matches!(
+            simulate_partial_liquidation(&state, 1, 6_000_000_000),
+            Err(ProtocolError::NotLiquidatable { .. })
+        ));
+
+        // Without a price the simulations fail rather than trap.
+        state.last_icp_rate = None;
+        assert!(matches!(
+            simulate_repay(&state, owner, VaultArg { vault_id: 1, amount: 4_000_000_000 }),
+            Err(ProtocolError::TemporarilyUnavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_vault_health() {
+        use crate::vault::compute_vault_health;
+
+        let mut state = test_state();
+        let owner = Principal::from_slice(&[1]);
+        let vault = |vault_id, borrowed| Vault {
+            owner,
+            vault_id,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(borrowed),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        };
+        state.open_vault(vault(1, 5_000_000_000));
+        state.open_vault(vault(2, 0));
+        assert!(compute_vault_health(&state, &state.vault_id_to_vaults[&1]).is_none());
+
+        // 10 ICP at $10 backing 50 icUSD: 200%.
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        let health = compute_vault_health(&state, &state.vault_id_to_vaults[&1]).unwrap();
+        assert_eq!(health.collateral_ratio, 2.0);
+        assert!((health.liquidation_price.unwrap() - 6.65).abs() < 1e-6);
+        // 100 / 1.33 = 75.18796992 icUSD can be borrowed in total.
+        assert_eq!(health.max_borrowable, 7_518_796_992 - 5_000_000_000);
+        assert_eq!(health.max_withdrawable, 0);
+        assert!(!health.is_liquidatable);
+
+        let health = compute_vault_health(&state, &state.vault_id_to_vaults[&2]).unwrap();
+        assert_eq!(health.liquidation_price, None);
+        assert_eq!(health.max_borrowable, 7_518_796_992 - crate::LIQUIDATION_RESERVE.to_u64());
+        assert_eq!(health.max_withdrawable, 1_000_000_000);
+
+        state.last_icp_rate = Some(UsdIcp::from(dec!(6.0)));
+        let health = compute_vault_health(&state, &state.vault_id_to_vaults[&1]).unwrap();
+        assert_eq!(health.max_borrowable, 0);
+        assert!(health.is_liquidatable);
+    }
+
+    #[test]
+    fn test_list_vaults_pages() {
+        use crate::vault::{list_vaults, ListVaultsArg, VaultSortKey};
+
+        let mut state = test_state();
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        // Collateral ratios of 200%, 500% and 250%.
+        for (vault_id, owner, borrowed) in [
+            (1, alice, 5_000_000_000),
+            (2, bob, 2_000_000_000),
+            (3, alice, 4_000_000_000),
+        ] {
+            state.open_vault(Vault {
+                owner,
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(borrowed),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+        let ids = |response: &crate::vault::ListVaultsResponse| {
+            response.vaults.iter().map(|vault| vault.vault_id).collect::<Vec<_>>()
+        };
+
+        let mut arg = ListVaultsArg {
+            sort_by: Some(VaultSortKey::CollateralRatio),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = list_vaults(&state, arg.clone()).unwrap();
+        assert_eq!(ids(&page), vec![1, 3]);
+        arg.cursor = page.next_cursor;
+        let page = list_vaults(&state, arg.clone()).unwrap();
+        assert_eq!(ids(&page), vec![2]);
+        assert!(page.next_cursor.is_none());
+
+        let page = list_vaults(
+            &state,
+            ListVaultsArg {
+                sort_by: Some(VaultSortKey::Debt),
+                descending: Some(true),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ids(&page), vec![1, 3, 2]);
+
+        let page = list_vaults(
+            &state,
+            ListVaultsArg {
+                owner: Some(alice),
+                min_debt: Some(4_500_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ids(&page), vec![1]);
+
+        let page = list_vaults(
+            &state,
+            ListVaultsArg {
+                min_collateral_ratio: Some(2.1),
+                max_collateral_ratio: Some(3.0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ids(&page), vec![3]);
+    }
+
+    #[test]
+    fn test_stress_test() {
+        let mut state = test_state();
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        // Collateral ratios of 250% and 166%.
+        for (vault_id, borrowed) in [(1, 4_000_000_000), (2, 6_000_000_000)] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(borrowed),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+
+        let result = state.stress_test(-25.0).unwrap();
+        assert_eq!(result.icp_rate, 7.5);
+        assert_eq!(result.liquidatable_vault_count, 1);
+        assert_eq!(result.liquidatable_debt, 6_000_000_000);
+        assert_eq!(result.total_collateral_ratio, 1.5);
+        assert_eq!(result.mode, Mode::GeneralAvailability);
+
+        // Recovery mode raises the liquidation threshold to 150%.
+        let result = state.stress_test(-45.0).unwrap();
+        assert_eq!(result.liquidatable_vault_count, 2);
+        assert_eq!(result.liquidatable_debt, 10_000_000_000);
+        assert_eq!(result.mode, Mode::Recovery);
+        assert_eq!(result.covered_by_stability_pool, None);
+
+        assert!(state.stress_test(-100.0).is_err());
+        assert!(state.stress_test(10_000.0).is_ok());
+        assert!(state.stress_test(1e30).is_err());
+        // Live state is unchanged.
+        assert_eq!(state.mode, Mode::GeneralAvailability);
+    }
+
+    #[test]
+    fn test_account_overview() {
+        let mut state = test_state();
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        let owner = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let vault = |owner, vault_id| Vault {
+            owner,
+            vault_id,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(5_000_000_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        };
+        state.open_vault(vault(owner, 1));
+        state.open_vault(vault(other, 2));
+        state.open_vault(vault(owner, 3));
+        state.close_vault(3, 0);
+        state.provide_liquidity(ICUSD::new(300_000_000), owner);
+        state.provide_liquidity(ICUSD::new(100_000_000), other);
+        state.redemption_rebates.insert(
+            1,
+            RedemptionRebate { owner, amount: ICUSD::new(5_000_000) },
+        );
+        state.pending_redemption_transfer.insert(
+            7,
+            PendingMarginTransfer {
+                owner,
+                margin: ICP::new(20_000_000),
+                fee: ICP_TRANSFER_FEE,
+                created_at: 3,
+            },
+        );
+
+        let mut events = vec![
+            Event::OpenVault { vault: vault(owner, 1), block_index: 0 },
+            Event::OpenVault { vault: vault(other, 2), block_index: 1 },
+            Event::RedemptionOnVaults {
+                owner: other,
+                current_icp_rate: UsdIcp::from(dec!(10.0)),
+                icusd_amount: ICUSD::new(100_000_000),
+                fee_amount: ICUSD::new(0),
+                icusd_block_index: 2,
+                timestamp: 0,
+            },
+        ];
+        events.extend((0..MAX_ACCOUNT_EVENTS as u64 - 1).map(|block_index| {
+            Event::ProvideLiquidity { amount: ICUSD::new(1), block_index, caller: owner }
+        }));
+        events.push(Event::CloseVault { vault_id: 3, block_index: None, timestamp: 0 });
+
+        let overview = state.get_account_overview(owner, events.into_iter().rev());
+        assert_eq!(overview.vaults.len(), 1);
+        assert_eq!(overview.vaults[0].vault_id, 1);
+        assert!(overview.vaults[0].health.is_some());
+        assert_eq!(overview.liquidity.liquidity_provided, 300_000_000);
+        assert_eq!(overview.liquidity.liquidity_pool_share, 0.75);
+        assert_eq!(overview.redemption_rebates.len(), 1);
+        assert_eq!(overview.pending_redemption_transfers.len(), 1);
+        assert_eq!(overview.pending_redemption_transfers[0].icusd_block_index, 7);
+        // Only the most recent events are kept, oldest first.
+        assert_eq!(overview.recent_events.len(), MAX_ACCOUNT_EVENTS);
+        assert_eq!(
+            overview.recent_events[0],
+            Event::ProvideLiquidity { amount: ICUSD::new(1), block_index: 0, caller: owner }
+        );
+        // Events of closed vaults are included.
+        assert_eq!(
+            overview.recent_events.last(),
+            Some(&Event::CloseVault { vault_id: 3, block_index: None, timestamp: 0 })
+        );
+
+        let overview = state.get_account_overview(other, std::iter::empty());
+        assert!(overview.redemption_rebates.is_empty());
+        assert!(overview.pending_redemption_transfers.is_empty());
+    }
+
+    #[test]
+    fn test_redemption_limits() {
+        use crate::vault::{check_redemption_limits, RedeemWithLimitsArg};
+
+        let icusd_amount = ICUSD::new(10_000_000_000);
+        let icp_rate = UsdIcp::from(dec!(10.0));
+        let fee_rate = Ratio::from(dec!(0.005));
+        // 99.5 icUSD at $10: 9.95 ICP.
+        let limits = RedeemWithLimitsArg {
+            icusd_amount: icusd_amount.to_u64(),
+            max_fee_rate: 0.01,
+            min_icp_out: 995_000_000,
+            deadline: Some(100),
+        };
+        assert!(check_redemption_limits(&limits, icusd_amount, fee_rate, icp_rate, 100).is_ok());
+
+        assert!(matches!(
+            check_redemption_limits(&limits, icusd_amount, Ratio::from(dec!(0.02)), icp_rate, 100),
+            Err(ProtocolError::RedemptionLimitExceeded { icp_out: 980_000_000, .. })
+        ));
+        assert!(matches!(
+            check_redemption_limits(&limits, icusd_amount, fee_rate, UsdIcp::from(dec!(10.1)), 100),
+            Err(ProtocolError::RedemptionLimitExceeded { .. })
+        ));
+        assert!(matches!(
+            check_redemption_limits(&limits, icusd_amount, fee_rate, icp_rate, 101),
+            Err(ProtocolError::DeadlineExpired)
+        ));
+
+        let no_deadline = RedeemWithLimitsArg { deadline: None, ..limits };
+        assert!(check_redemption_limits(&no_deadline, icusd_amount, fee_rate, icp_rate, u64::MAX).is_ok());
+
+        for max_fee_rate in [f64::NAN, f64::INFINITY, -0.1, 1.5] {
+            let invalid = RedeemWithLimitsArg { max_fee_rate, ..no_deadline.clone() };
+            assert!(matches!(
+                check_redemption_limits(&invalid, icusd_amount, fee_rate, icp_rate, 0),
+                Err(ProtocolError::GenericError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_preview_redemption() {
+        let mut state = test_state();
+        let icp_rate = UsdIcp::from(dec!(10.0));
+        let now = 1_000 * crate::SEC_NANOS;
+        for (vault_id, borrowed) in [(1, 5_000_000_000), (2, 3_000_000_000)] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(borrowed),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+
+        let preview = state.preview_redemption(ICUSD::new(6_000_000_000), icp_rate, now).unwrap();
+        assert_eq!(preview.vaults.len(), 2);
+        assert_eq!(
+            preview.icp_amount,
+            preview.vaults.iter().map(|vault| vault.icp_amount).sum::<u64>()
+        );
+        assert_eq!(
+            preview.fee_amount + preview.vaults.iter().map(|vault| vault.icusd_amount).sum::<u64>(),
+            6_000_000_000
+        );
+
+        // The vaults can't cover the amount.
+        assert!(state.preview_redemption(ICUSD::new(9_000_000_000), icp_rate, now).is_err());
+
+        state.min_vault_age_for_redemption = 2 * now;
+        assert!(state.preview_redemption(ICUSD::new(1_000_000_000), icp_rate, now).is_err());
+
+        state.min_vault_age_for_redemption = 0;
+        state.redemption_bootstrap_end = now + 1;
+        assert!(matches!(
+            state.preview_redemption(ICUSD::new(1_000_000_000), icp_rate, now),
+            Err(ProtocolError::OperationPaused { .. })
+        ));
+    }
+
+    #[test]
+    fn test_legacy_interest_rates_migrated() {
+        let mut state = test_state();
+        state.open_vault(Vault {
+            owner: Principal::anonymous(),
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(5_000_000_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        });
+        // Without interest-bearing debt nothing is recorded.
+        state.last_interest_accrual = 1;
+        assert!(!state.needs_interest_accrual(crate::YEAR_NANOS));
+
+        // Replaying an upgrade leaves the rates alone.
+        state.upgrade(UpgradeArg { mode: None, migrate_legacy_interest_rates: Some(true) });
+        assert_eq!(state.vault_id_to_vaults[&1].interest_rate, Ratio::default());
+        assert!(state.has_legacy_interest_rates());
+
+        state.migrate_legacy_interest_rates();
+        assert!(!state.has_legacy_interest_rates());
+        assert_eq!(state.vault_id_to_vaults[&1].interest_rate, MIN_INTEREST_RATE);
+        // The idle period is not charged: the clock starts again.
+        assert_eq!(state.last_interest_accrual, 0);
+        assert!(state.needs_interest_accrual(crate::YEAR_NANOS));
+        assert_eq!(state.accrue_interest(crate::YEAR_NANOS), ICUSD::new(0));
+    }
+
+    #[test]
+    fn test_batch_liquidation() {
+        use crate::vault::select_vaults_to_liquidate;
+
+        let mut state = test_state();
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        // Collateral ratios of 125%, 142%, 111% and 117%.
+        for (vault_id, borrowed) in [
+            (1, 8_000_000_000),
+            (2, 7_000_000_000),
+            (3, 9_000_000_000),
+            (4, 8_500_000_000),
+        ] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                icp_margin_amount: ICP::new(1_000_000_000),
+                borrowed_icusd_amount: ICUSD::new(borrowed),
+                liquidation_reserve: ICUSD::new(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
+            });
+        }
+        state
+            .acquire_locks(&[LockKey::Vault(4)], Principal::anonymous(), "repay", 1, 10)
+            .unwrap();
+
+        // The lowest ratio goes first; healthy and locked vaults are skipped.
+        let (selected, _, _) =
+            select_vaults_to_liquidate(&state, &[1, 2, 3, 4], ICUSD::new(100_000_000_000), 1).unwrap();
+        let ids: Vec<u64> = selected.iter().map(|vault| vault.vault_id).collect();
+        assert_eq!(ids, vec![3, 1]);
+
+        // Vaults whose debt doesn't fit are skipped.
+        let (selected, _, _) =
+            select_vaults_to_liquidate(&state, &[1, 3], ICUSD::new(8_500_000_000), 1).unwrap();
+        assert_eq!(selected[0].vault_id, 1);
+        assert_eq!(selected.len(), 1);
+        assert!(select_vaults_to_liquidate(&state, &[1, 3], ICUSD::new(1_000_000_000), 1).is_err());
+
+        // Refunds for skipped vaults stay claimable until minted.
+        let liquidator = Principal::from_slice(&[1]);
+        state.add_liquidation_refund(liquidator, ICUSD::new(500_000_000));
+        state.add_liquidation_refund(liquidator, ICUSD::new(300_000_000));
+        state.claim_liquidation_refund(liquidator, ICUSD::new(500_000_000));
+        assert_eq!(state.get_liquidation_refund_of(liquidator), ICUSD::new(300_000_000));
+        state.claim_liquidation_refund(liquidator, ICUSD::new(300_000_000));
+        assert!(state.liquidation_refunds.is_empty());
+
+        // A claim cannot start while liquidate_vaults is minting a refund it
+        // already owes, and the other way around.
+        use crate::guard::liquidation_refund_keys;
+        let batch = liquidation_refund_keys(liquidator, "liquidate_vaults");
+        let claim = liquidation_refund_keys(liquidator, "claim_liquidation_refund");
+        state.acquire_locks(&batch, liquidator, "liquidate_vaults", 20, 10).unwrap();
+        state.add_liquidation_refund(liquidator, ICUSD::new(500_000_000));
+        assert_eq!(
+            state.acquire_locks(&claim, liquidator, "claim_liquidation_refund", 21, 10),
+            Err(GuardError::AlreadyProcessing)
+        );
+        state.claim_liquidation_refund(liquidator, ICUSD::new(500_000_000));
+        state.release_locks(&batch, liquidator, 20);
+        assert_eq!(state.get_liquidation_refund_of(liquidator), ICUSD::new(0));
+        state.acquire_locks(&claim, liquidator, "claim_liquidation_refund", 22, 10).unwrap();
+        assert_eq!(
+            state.acquire_locks(&batch, liquidator, "liquidate_vaults", 23, 10),
+            Err(GuardError::AlreadyProcessing)
+        );
+    }
+
+    #[test]
+    fn test_liquidation_bonus_never_grows() {
+        use crate::vault::simulate_liquidation;
+
+        let mut state = test_state();
+        // 10 ICP backing 80 icUSD.
+        state.open_vault(Vault {
+            owner: Principal::anonymous(),
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(8_000_000_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        });
+
+        // At 120%, 105% and 87.5%.
+        let mut previous_fee = u64::MAX;
+        for price in [dec!(9.6), dec!(8.4), dec!(7.0)] {
+            state.last_icp_rate = Some(UsdIcp::from(price));
+            let liquidation = simulate_liquidation(&state, 1).unwrap();
+            assert!(liquidation.fee_amount <= previous_fee);
+            assert!(liquidation.fee_amount <= 800_000_000);
+            previous_fee = liquidation.fee_amount;
+        }
+
+        // Underwater, the liquidator pays what the collateral is worth and
+        // only the shortfall is bad debt.
+        let liquidation = simulate_liquidation(&state, 1).unwrap();
+        assert_eq!(liquidation.debt_amount, 7_000_000_000);
+        assert_eq!(liquidation.icp_to_liquidator, 1_000_000_000);
+        assert_eq!(liquidation.bad_debt, 1_000_000_000);
+        assert_eq!(liquidation.fee_amount, 0);
+    }
+
+    #[test]
+    fn test_liquidation_protocol_share_needs_treasury() {
+        use crate::vault::simulate_liquidation;
+
+        let mut state = test_state();
+        state.last_icp_rate = Some(UsdIcp::from(dec!(10.0)));
+        state.set_liquidation_protocol_share(Ratio::from(dec!(0.5)));
+        // 10 ICP at $10 backing 80 icUSD: 8.8 ICP seized, 0.8 ICP of penalty.
+        state.open_vault(Vault {
+            owner: Principal::anonymous(),
+            vault_id: 1,
+            icp_margin_amount: ICP::new(1_000_000_000),
+            borrowed_icusd_amount: ICUSD::new(8_000_000_000),
+            liquidation_reserve: ICUSD::new(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
+        });
+
+        // Without a treasury the liquidator keeps the whole penalty.
+        assert_eq!(state.effective_liquidation_protocol_share(), Ratio::from(Decimal::ZERO));
+        assert_eq!(simulate_liquidation(&state, 1).unwrap().icp_to_liquidator, 880_000_000);
+
+        state.set_treasury_principal(Principal::from_slice(&[9]));
+        assert_eq!(state.effective_liquidation_protocol_share(), Ratio::from(dec!(0.5)));
+        assert_eq!(simulate_liquidation(&state, 1).unwrap().icp_to_liquidator, 840_000_000);
+    }
+
+    #[test]
+    fn test_legacy_errors() {
+        assert!(matches!(
+            ProtocolError::VaultNotFound { vault_id: 3 }.into_legacy(),
+            ProtocolError::GenericError(message) if message == "Vault #3 not found"
+        ));
+        assert!(matches!(
+            ProtocolError::StalePrice { age: 700 }.into_legacy(),
+            ProtocolError::TemporarilyUnavailable(message) if message == "Last known ICP price too old"
+        ));
+        assert!(matches!(
+            ProtocolError::OperationPaused { reason: "flash minting is disabled".to_string() }
+                .into_legacy(),
+            ProtocolError::TemporarilyUnavailable(message) if message == "flash minting is disabled"
+        ));
+        assert!(matches!(
+            ProtocolError::NotLiquidatable { ratio: 1.5, minimum: 1.33 }.into_legacy(),
+            ProtocolError::GenericError(_)
+        ));
+        assert!(matches!(
+            ProtocolError::DeadlineExpired.into_legacy(),
+            ProtocolError::DeadlineExpired
+        ));
+
+        let mut state = test_state();
+        let caller = Principal::from_slice(&[1]);
+        state.set_typed_errors(caller, true).unwrap();
+        assert!(state.typed_error_callers.contains(&caller));
+        state.set_typed_errors(caller, false).unwrap();
+        assert!(state.typed_error_callers.is_empty());
+
+        // The set is bounded, but callers already in it can still opt in again.
+        state.typed_error_callers = (0..MAX_TYPED_ERROR_CALLERS as u64)
+            .map(|i| Principal::from_slice(&i.to_be_bytes()))
+            .collect();
+        assert!(state.set_typed_errors(caller, true).is_err());
+        state.set_typed_errors(Principal::from_slice(&0u64.to_be_bytes()), true).unwrap();
+    }
+}
@@ -1,13 +1,19 @@
 use crate::event::Event;
+use crate::idempotency::{RequestEntry, RequestKey};
 use ic_stable_structures::{
+    cell::Cell as StableCell,
     log::{Log as StableLog, NoSuchEntry},
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     DefaultMemoryImpl,
 };
 use std::cell::RefCell;
+use candid::Principal;
+use std::collections::{BTreeMap, BTreeSet};
 
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
+const RECENT_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const TYPED_ERROR_CALLERS_MEMORY_ID: MemoryId = MemoryId::new(3);
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
 type EventLog = StableLog<Vec<u8>, VMem, VMem>;
@@ -28,6 +34,24 @@ thread_local! {
               )
         );
 
+    /// The recent request ids, saved across upgrades. They are not in the
+    /// event log since they don't change the protocol state.
+    static RECENT_REQUESTS: RefCell<StableCell<Vec<u8>, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(m.borrow().get(RECENT_REQUESTS_MEMORY_ID), vec![])
+                      .expect("failed to initialize the recent requests cell")
+              )
+        );
+
+    /// The callers that opted into typed errors, saved across upgrades.
+    static TYPED_ERROR_CALLERS: RefCell<StableCell<Vec<u8>, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(m.borrow().get(TYPED_ERROR_CALLERS_MEMORY_ID), vec![])
+                      .expect("failed to initialize the typed error callers cell")
+              )
+        );
 }
 
 pub struct EventIterator {
@@ -85,6 +109,21 @@ pub fn count_events() -> u64 {
     EVENTS.with(|events| events.borrow().len())
 }
 
+/// Returns an iterator over the last `limit` events, newest first.
+pub fn latest_events(limit: u64) -> impl Iterator<Item = Event> {
+    let count = count_events();
+    (count.saturating_sub(limit)..count).rev().map(|pos| {
+        EVENTS.with(|events| {
+            let mut buf = vec![];
+            events
+                .borrow()
+                .read_entry(pos, &mut buf)
+                .expect("BUG: event index out of range");
+            decode_event(&buf)
+        })
+    })
+}
+
 /// Records a new minter event.
 pub fn record_event(event: &Event) {
     let bytes = encode_event(event);
@@ -95,3 +134,56 @@ pub fn record_event(event: &Event) {
             .expect("failed to append an entry to the event log")
     });
 }
+
+/// Saves the recent request ids before an upgrade.
+pub fn save_recent_requests(requests: &BTreeMap<RequestKey, RequestEntry>) {
+    let entries: Vec<(&RequestKey, &RequestEntry)> = requests.iter().collect();
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&entries, &mut buf).expect("failed to encode the recent requests");
+    RECENT_REQUESTS.with(|cell| {
+        cell.borrow_mut()
+            .set(buf)
+            .expect("failed to save the recent requests")
+    });
+}
+
+/// Takes the recent request ids saved before the upgrade. Returns none if
+/// they can't be decoded, rather than failing the upgrade.
+pub fn take_recent_requests() -> BTreeMap<RequestKey, RequestEntry> {
+    let buf = RECENT_REQUESTS.with(|cell| {
+        cell.borrow_mut()
+            .set(vec![])
+            .expect("failed to clear the recent requests")
+    });
+    if buf.is_empty() {
+        return BTreeMap::new();
+    }
+    ciborium::de::from_reader::<Vec<(RequestKey, RequestEntry)>, _>(buf.as_slice())
+        .map(|entries| entries.into_iter().collect())
+        .unwrap_or_default()
+}
+
+/// Saves the callers that opted into typed errors before an upgrade.
+pub fn save_typed_error_callers(callers: &BTreeSet<Principal>) {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(callers, &mut buf).expect("failed to encode the typed error callers");
+    TYPED_ERROR_CALLERS.with(|cell| {
+        cell.borrow_mut()
+            .set(buf)
+            .expect("failed to save the typed error callers")
+    });
+}
+
+/// Takes the callers that opted into typed errors saved before the upgrade.
+/// Returns none if they can't be decoded, rather than failing the upgrade.
+pub fn take_typed_error_callers() -> BTreeSet<Principal> {
+    let buf = TYPED_ERROR_CALLERS.with(|cell| {
+        cell.borrow_mut()
+            .set(vec![])
+            .expect("failed to clear the typed error callers")
+    });
+    if buf.is_empty() {
+        return BTreeSet::new();
+    }
+    ciborium::de::from_reader(buf.as_slice()).unwrap_or_default()
+}
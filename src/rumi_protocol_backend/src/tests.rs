@@ -1,5 +1,5 @@
 use crate::Vault;
-use crate::{ICP, ICUSD};
+use crate::{Ratio, ICP, ICUSD};
 use candid::Principal;
 use ic_base_types::PrincipalId;
 use proptest::prelude::*;
@@ -13,6 +13,10 @@ fn arb_vault() -> impl Strategy<Value = Vault> {
             borrowed_icusd_amount: ICUSD::from(borrowed_icusd),
             icp_margin_amount: ICP::from(icp_margin.max(1_000_000)),
             vault_id: 0,
+            liquidation_reserve: ICUSD::from(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         }
     })
 }
@@ -57,6 +61,10 @@ proptest! {
                 borrowed_icusd_amount: ICUSD::from(target_borrowed_icusd),
                 icp_margin_amount: ICP::from(target_icp_margin),
                 vault_id: vaults.last_key_value().unwrap().1.vault_id + 1,
+                liquidation_reserve: ICUSD::from(0),
+                interest_rate: Ratio::default(),
+                last_interest_rate_adjustment: 0,
+                opened_at: 0,
             };
             
             let result = crate::state::distribute_across_vaults(&vaults, target_vault);
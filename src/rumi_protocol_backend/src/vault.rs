@@ -1,25 +1,31 @@
 use crate::event::{
-    record_add_margin_to_vault, record_borrow_from_vault, record_open_vault,
-    record_redemption_on_vaults, record_repayed_to_vault,
+    record_accrue_interest, record_add_margin_to_vault, record_adjust_interest_rate, record_bad_debt,
+    record_bad_debt_covered, record_borrow_from_vault, record_claim_redemption_rebate,
+    record_liquidation_refund_claimed, record_liquidation_refund_owed, record_redemption_on_vaults, record_repayed_to_vault, record_saga_started,
 };
-use crate::guard::GuardPrincipal;
+use crate::guard::{DebtReservation, GuardPrincipal, LockKey};
 use crate::GuardError;
 use crate::logs::INFO;
 use crate::management::{mint_icusd, transfer_icp_from, transfer_icusd_from};
-use crate::numeric::{ICUSD, ICP};
+use crate::numeric::{UsdIcp, ICUSD, ICP};
+use crate::saga::{self, SagaKind, SagaStatus};
+use crate::state::{CollateralType, Mode, State};
 use crate::{
-    mutate_state, read_state, ProtocolError, SuccessWithFee, MIN_ICP_AMOUNT, MIN_ICUSD_AMOUNT,
+    mutate_state, read_state, ProtocolError, SuccessWithFee, INTEREST_RATE_ADJUSTMENT_COOLDOWN_NANOS,
+    LIQUIDATION_RESERVE, MAX_INTEREST_RATE, MIN_ICP_AMOUNT, MIN_ICUSD_AMOUNT, MIN_INTEREST_RATE,
+    MIN_NET_DEBT, YEAR_NANOS,
 };
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
 use candid::{CandidType, Deserialize, Principal};
 use ic_canister_log::log;
 use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
 use serde::Serialize;
 use crate::DEBUG;
-use crate::management;
-use crate::PendingMarginTransfer;
+use crate::state::{PendingTransfer, TransferId, TransferPurpose};
 use rust_decimal_macros::dec;
 use crate::Ratio;
-use crate::compute_collateral_ratio;
+use crate::{compute_collateral_ratio, compute_liquidation_price};
 
 
 
@@ -35,20 +41,145 @@ pub struct VaultArg {
     pub amount: u64,
 }
 
+/// Redemption with caller-side protection against fee and price movements.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct RedeemWithLimitsArg {
+    pub icusd_amount: u64,
+    /// Highest redemption fee rate the caller accepts, e.g. 0.01 for 1%.
+    pub max_fee_rate: f64,
+    /// Least amount of ICP (e8s) the caller accepts to receive.
+    pub min_icp_out: u64,
+    /// Timestamp in nanoseconds after which the redemption must not execute.
+    pub deadline: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AdjustInterestRateArg {
+    pub vault_id: u64,
+    /// Annual rate, e.g. 0.05 for 5%.
+    pub interest_rate: f64,
+}
+
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, PartialOrd, Ord)]
 pub struct Vault {
     pub owner: Principal,
+    /// Total debt, including the liquidation reserve.
     pub borrowed_icusd_amount: ICUSD,
     pub icp_margin_amount: ICP,
     pub vault_id: u64,
+    /// Part of the debt that is paid to the liquidator, or cancelled once
+    /// the rest of the debt is repaid.
+    #[serde(default)]
+    pub liquidation_reserve: ICUSD,
+    /// Annual interest rate chosen by the owner. Redemptions hit the lowest rates first.
+    #[serde(default)]
+    pub interest_rate: Ratio,
+    /// Time of the last interest rate change, or of the opening of the vault.
+    #[serde(default)]
+    pub last_interest_rate_adjustment: u64,
+    /// Zero for vaults opened before this was tracked.
+    #[serde(default)]
+    pub opened_at: u64,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+impl Vault {
+    /// Debt the owner has to repay, the liquidation reserve excluded.
+    pub fn net_debt(&self) -> ICUSD {
+        self.borrowed_icusd_amount.saturating_sub(self.liquidation_reserve)
+    }
+
+    /// Upfront fee for switching to `new_rate` at `now`: the interest of one
+    /// cooldown period at the new rate if the previous change is too recent.
+    pub fn interest_rate_adjustment_fee(&self, new_rate: Ratio, now: u64) -> ICUSD {
+        if now.saturating_sub(self.last_interest_rate_adjustment)
+            >= INTEREST_RATE_ADJUSTMENT_COOLDOWN_NANOS
+        {
+            return ICUSD::new(0);
+        }
+        let cooldown_fraction = Ratio::from(
+            Decimal::from(INTEREST_RATE_ADJUSTMENT_COOLDOWN_NANOS) / Decimal::from(YEAR_NANOS),
+        );
+        self.net_debt() * (new_rate * cooldown_fraction)
+    }
+
+    /// Cancels the liquidation reserve once it is the only debt left.
+    pub fn settle_liquidation_reserve(&mut self) {
+        if self.borrowed_icusd_amount == self.liquidation_reserve {
+            self.borrowed_icusd_amount = ICUSD::new(0);
+            self.liquidation_reserve = ICUSD::new(0);
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
 pub struct CandidVault {
     pub owner: Principal,
     pub borrowed_icusd_amount: u64,
     pub icp_margin_amount: u64,
     pub vault_id: u64,
+    pub liquidation_reserve: u64,
+    pub interest_rate: f64,
+    pub last_interest_rate_adjustment: u64,
+    pub opened_at: u64,
+    /// Set by the vault queries when an ICP rate is known.
+    pub health: Option<VaultHealth>,
+}
+
+/// The health of a vault at the last known ICP rate.
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct VaultHealth {
+    pub collateral_ratio: f64,
+    /// ICP price in USD below which the vault could be liquidated, if it has debt.
+    pub liquidation_price: Option<f64>,
+    /// icUSD that can still be borrowed before reaching the minimum collateral ratio.
+    pub max_borrowable: u64,
+    /// ICP that can currently be withdrawn.
+    pub max_withdrawable: u64,
+    pub is_liquidatable: bool,
+}
+
+impl CandidVault {
+    pub fn with_health(vault: Vault, s: &State) -> Self {
+        let health = compute_vault_health(s, &vault);
+        Self {
+            health,
+            ..Self::from(vault)
+        }
+    }
+}
+
+/// Returns the health of `vault`, or `None` if no ICP rate is known.
+pub fn compute_vault_health(s: &State, vault: &Vault) -> Option<VaultHealth> {
+    let icp_rate = s.last_icp_rate?;
+    let minimum_ratio = s.mode.get_minimum_liquidation_collateral_ratio();
+    let ratio = compute_collateral_ratio(vault, icp_rate);
+
+    // The first borrow of a vault also takes on the liquidation reserve
+    let liquidation_reserve = if vault.borrowed_icusd_amount == 0 {
+        LIQUIDATION_RESERVE
+    } else {
+        ICUSD::new(0)
+    };
+    let mut max_borrowable = (vault.icp_margin_amount * icp_rate / minimum_ratio)
+        .saturating_sub(vault.borrowed_icusd_amount + liquidation_reserve);
+    if let Some(remaining) = s.remaining_debt_capacity(CollateralType::ICP) {
+        max_borrowable = max_borrowable.min(remaining.saturating_sub(liquidation_reserve));
+    }
+
+    // Collateral can only be withdrawn in full
+    let max_withdrawable = if validate_withdraw(s, vault.owner, vault.vault_id).is_ok() {
+        vault.icp_margin_amount
+    } else {
+        ICP::new(0)
+    };
+
+    Some(VaultHealth {
+        collateral_ratio: ratio.to_f64(),
+        liquidation_price: compute_liquidation_price(vault, minimum_ratio).map(|price| price.to_f64()),
+        max_borrowable: max_borrowable.to_u64(),
+        max_withdrawable: max_withdrawable.to_u64(),
+        is_liquidatable: ratio < minimum_ratio,
+    })
 }
 
 impl From<Vault> for CandidVault {
@@ -58,49 +189,375 @@ impl From<Vault> for CandidVault {
             borrowed_icusd_amount: vault.borrowed_icusd_amount.to_u64(),
             icp_margin_amount: vault.icp_margin_amount.to_u64(),
             vault_id: vault.vault_id,
+            liquidation_reserve: vault.liquidation_reserve.to_u64(),
+            interest_rate: vault.interest_rate.to_f64(),
+            last_interest_rate_adjustment: vault.last_interest_rate_adjustment,
+            opened_at: vault.opened_at,
+            health: None,
         }
     }
 }
 
+/// The outcome of a vault operation, computed without executing it.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct VaultSimulation {
+    /// The vault after the operation.
+    pub vault: CandidVault,
+    pub collateral_ratio: f64,
+    /// ICP price in USD below which the vault could be liquidated, if it has debt.
+    pub liquidation_price: Option<f64>,
+    pub icusd_fee: u64,
+    pub icp_fee: u64,
+}
+
+/// The outcome of liquidating a vault, computed without executing it.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct LiquidationSimulation {
+    pub vault_id: u64,
+    pub collateral_ratio: f64,
+    /// icUSD the liquidator pays.
+    pub debt_amount: u64,
+    pub icp_to_liquidator: u64,
+    /// ICP returned to the vault owner. Always zero for a partial liquidation.
+    pub excess_collateral: u64,
+    /// Always zero for a partial liquidation.
+    pub bad_debt: u64,
+    /// Value the liquidator receives over the debt paid, in icUSD.
+    pub fee_amount: u64,
+}
+
+fn vault_simulation(
+    s: &State,
+    vault: Vault,
+    icusd_fee: ICUSD,
+    icp_fee: ICP,
+) -> Result<VaultSimulation, ProtocolError> {
+    let icp_rate = current_icp_rate(s)?;
+    let minimum_ratio = s.mode.get_minimum_liquidation_collateral_ratio();
+    Ok(VaultSimulation {
+        collateral_ratio: compute_collateral_ratio(&vault, icp_rate).to_f64(),
+        liquidation_price: compute_liquidation_price(&vault, minimum_ratio).map(|price| price.to_f64()),
+        vault: CandidVault::from(vault),
+        icusd_fee: icusd_fee.to_u64(),
+        icp_fee: icp_fee.to_u64(),
+    })
+}
+
+fn current_icp_rate(s: &State) -> Result<UsdIcp, ProtocolError> {
+    s.last_icp_rate.ok_or(ProtocolError::TemporarilyUnavailable(
+        "no ICP rate available".to_string(),
+    ))
+}
+
+pub fn simulate_borrow(s: &State, caller: Principal, arg: VaultArg) -> Result<VaultSimulation, ProtocolError> {
+    let plan = validate_borrow(s, caller, arg.vault_id, arg.amount.into())?;
+    vault_simulation(s, plan.vault_after, plan.fee, ICP::new(0))
+}
+
+pub fn simulate_repay(s: &State, caller: Principal, arg: VaultArg) -> Result<VaultSimulation, ProtocolError> {
+    let vault_after = validate_repay(s, caller, arg.vault_id, arg.amount.into())?;
+    vault_simulation(s, vault_after, ICUSD::new(0), ICP::new(0))
+}
+
+pub fn simulate_withdraw(s: &State, caller: Principal, vault_id: u64) -> Result<VaultSimulation, ProtocolError> {
+    let vault_after = validate_withdraw(s, caller, vault_id)?;
+    vault_simulation(s, vault_after, ICUSD::new(0), s.icp_ledger_fee)
+}
+
+pub fn simulate_liquidation(s: &State, vault_id: u64) -> Result<LiquidationSimulation, ProtocolError> {
+    let (vault, icp_rate, _mode) = validate_liquidation(s, vault_id)?;
+    let LiquidationAmounts {
+        debt_amount,
+        icp_to_liquidator,
+        excess_collateral,
+        bad_debt,
+        ..
+    } = full_liquidation_amounts(&vault, icp_rate, s.effective_liquidation_protocol_share());
+    Ok(LiquidationSimulation {
+        vault_id,
+        collateral_ratio: compute_collateral_ratio(&vault, icp_rate).to_f64(),
+        debt_amount: debt_amount.to_u64(),
+        icp_to_liquidator: icp_to_liquidator.to_u64(),
+        excess_collateral: excess_collateral.to_u64(),
+        bad_debt: bad_debt.to_u64(),
+        fee_amount: (icp_to_liquidator * icp_rate).saturating_sub(debt_amount).to_u64(),
+    })
+}
+
+pub fn simulate_partial_liquidation(
+    s: &State,
+    vault_id: u64,
+    icusd_amount: u64,
+) -> Result<LiquidationSimulation, ProtocolError> {
+    let (vault, icp_rate, debt_amount, icp_to_liquidator) =
+        validate_partial_liquidation(s, vault_id, icusd_amount.into())?;
+    Ok(LiquidationSimulation {
+        vault_id,
+        collateral_ratio: compute_collateral_ratio(&vault, icp_rate).to_f64(),
+        debt_amount: debt_amount.to_u64(),
+        icp_to_liquidator: icp_to_liquidator.to_u64(),
+        excess_collateral: 0,
+        bad_debt: 0,
+        fee_amount: (icp_to_liquidator * icp_rate).saturating_sub(debt_amount).to_u64(),
+    })
+}
+
+pub const DEFAULT_LIST_VAULTS_LIMIT: u64 = 100;
+pub const MAX_LIST_VAULTS_LIMIT: u64 = 500;
+
+#[derive(CandidType, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum VaultSortKey {
+    #[default]
+    VaultId,
+    CollateralRatio,
+    Debt,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct ListVaultsArg {
+    pub owner: Option<Principal>,
+    pub min_collateral_ratio: Option<f64>,
+    pub max_collateral_ratio: Option<f64>,
+    /// Least borrowed icUSD (e8s).
+    pub min_debt: Option<u64>,
+    pub collateral_type: Option<CollateralType>,
+    pub sort_by: Option<VaultSortKey>,
+    pub descending: Option<bool>,
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<VaultCursor>,
+    pub limit: Option<u64>,
+}
+
+/// The position of a vault in a listing, so that the next page starts after
+/// it even if vaults were opened or closed in between.
+#[derive(CandidType, Clone, Debug, PartialEq, Deserialize)]
+pub struct VaultCursor {
+    pub vault_id: u64,
+    pub collateral_ratio: f64,
+    pub debt: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ListVaultsResponse {
+    pub vaults: Vec<CandidVault>,
+    /// Set if more vaults match.
+    pub next_cursor: Option<VaultCursor>,
+}
+
+fn compare_cursors(sort_by: VaultSortKey, a: &VaultCursor, b: &VaultCursor) -> std::cmp::Ordering {
+    let by_id = a.vault_id.cmp(&b.vault_id);
+    match sort_by {
+        VaultSortKey::VaultId => by_id,
+        VaultSortKey::CollateralRatio => a.collateral_ratio.total_cmp(&b.collateral_ratio).then(by_id),
+        VaultSortKey::Debt => a.debt.cmp(&b.debt).then(by_id),
+    }
+}
+
+/// Returns a page of the vaults matching the filters of `arg`.
+pub fn list_vaults(s: &State, arg: ListVaultsArg) -> Result<ListVaultsResponse, ProtocolError> {
+    let sort_by = arg.sort_by.unwrap_or_default();
+    let descending = arg.descending.unwrap_or(false);
+    let limit = arg
+        .limit
+        .unwrap_or(DEFAULT_LIST_VAULTS_LIMIT)
+        .clamp(1, MAX_LIST_VAULTS_LIMIT) as usize;
+    let needs_rate = sort_by == VaultSortKey::CollateralRatio
+        || arg.min_collateral_ratio.is_some()
+        || arg.max_collateral_ratio.is_some();
+    let icp_rate = match s.last_icp_rate {
+        Some(rate) => Some(rate),
+        None if needs_rate => {
+            return Err(ProtocolError::TemporarilyUnavailable(
+                "no ICP rate available".to_string(),
+            ))
+        }
+        None => None,
+    };
+
+    // Every vault is backed by ICP for now
+    if matches!(arg.collateral_type, Some(collateral_type) if collateral_type != CollateralType::ICP) {
+        return Ok(ListVaultsResponse {
+            vaults: vec![],
+            next_cursor: None,
+        });
+    }
+
+    let vaults: Box<dyn Iterator<Item = &Vault>> = match arg.owner {
+        Some(owner) => Box::new(
+            s.principal_to_vault_ids
+                .get(&owner)
+                .into_iter()
+                .flatten()
+                .filter_map(|vault_id| s.vault_id_to_vaults.get(vault_id)),
+        ),
+        None => Box::new(s.vault_id_to_vaults.values()),
+    };
+    let mut matching: Vec<(VaultCursor, &Vault)> = vaults
+        .map(|vault| {
+            let cursor = VaultCursor {
+                vault_id: vault.vault_id,
+                collateral_ratio: icp_rate
+                    .map(|rate| compute_collateral_ratio(vault, rate).to_f64())
+                    .unwrap_or_default(),
+                debt: vault.borrowed_icusd_amount.to_u64(),
+            };
+            (cursor, vault)
+        })
+        .filter(|(cursor, _)| {
+            arg.min_collateral_ratio.map_or(true, |min| cursor.collateral_ratio >= min)
+                && arg.max_collateral_ratio.map_or(true, |max| cursor.collateral_ratio <= max)
+                && arg.min_debt.map_or(true, |min| cursor.debt >= min)
+        })
+        .filter(|(cursor, _)| match &arg.cursor {
+            Some(after) => {
+                let order = compare_cursors(sort_by, cursor, after);
+                if descending {
+                    order.is_lt()
+                } else {
+                    order.is_gt()
+                }
+            }
+            None => true,
+        })
+        .collect();
+    matching.sort_by(|(a, _), (b, _)| {
+        let order = compare_cursors(sort_by, a, b);
+        if descending {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+
+    let next_cursor = if matching.len() > limit {
+        Some(matching[limit - 1].0.clone())
+    } else {
+        None
+    };
+    Ok(ListVaultsResponse {
+        vaults: matching
+            .into_iter()
+            .take(limit)
+            .map(|(_, vault)| CandidVault::with_health(vault.clone(), s))
+            .collect(),
+        next_cursor,
+    })
+}
+
 pub async fn redeem_icp(_icusd_amount: u64) -> Result<SuccessWithFee, ProtocolError> {
+    redeem_icp_internal(_icusd_amount.into(), None).await
+}
+
+pub async fn redeem_icp_with_limits(
+    arg: RedeemWithLimitsArg,
+) -> Result<SuccessWithFee, ProtocolError> {
+    redeem_icp_internal(arg.icusd_amount.into(), Some(arg)).await
+}
+
+/// Checks the quote of a redemption of `icusd_amount` at `fee_rate` and
+/// `icp_rate` against the limits set by the caller.
+pub fn check_redemption_limits(
+    limits: &RedeemWithLimitsArg,
+    icusd_amount: ICUSD,
+    fee_rate: Ratio,
+    icp_rate: UsdIcp,
+    now: u64,
+) -> Result<(), ProtocolError> {
+    // Every comparison with NaN is false, so it would accept any fee.
+    if !limits.max_fee_rate.is_finite() || !(0.0..=1.0).contains(&limits.max_fee_rate) {
+        return Err(ProtocolError::GenericError(
+            "max_fee_rate must be between 0 and 1".to_string(),
+        ));
+    }
+    if let Some(deadline) = limits.deadline {
+        if now > deadline {
+            return Err(ProtocolError::DeadlineExpired);
+        }
+    }
+    let icp_out: ICP = (icusd_amount - icusd_amount * fee_rate) / icp_rate;
+    if fee_rate.to_f64() > limits.max_fee_rate || icp_out.to_u64() < limits.min_icp_out {
+        return Err(ProtocolError::RedemptionLimitExceeded {
+            fee_rate: fee_rate.to_f64(),
+            icp_out: icp_out.to_u64(),
+        });
+    }
+    Ok(())
+}
+
+async fn redeem_icp_internal(
+    icusd_amount: ICUSD,
+    limits: Option<RedeemWithLimitsArg>,
+) -> Result<SuccessWithFee, ProtocolError> {
     let caller = ic_cdk::api::caller();
     let _guard_principal = GuardPrincipal::new(caller, "redeem_icp")?;
 
-    let icusd_amount: ICUSD = _icusd_amount.into();
-
     if icusd_amount < MIN_ICUSD_AMOUNT {
         return Err(ProtocolError::AmountTooLow {
             minimum_amount: MIN_ICUSD_AMOUNT.to_u64(),
         });
     }
 
-    let current_icp_rate = read_state(|s| s.last_icp_rate.expect("no ICP rate entry"));
+    let current_icp_rate = read_state(current_icp_rate)?;
+    read_state(|s| s.check_redemptions_enabled(ic_cdk::api::time()))?;
+
+    // Quote the redemption before pulling any icUSD so that a caller whose
+    // limits are not met keeps their tokens.
+    let quoted_fee_rate = read_state(|s| s.get_redemption_fee(icusd_amount));
+    let redeemable_amount: ICUSD = read_state(|s| {
+        s.plan_redemption(icusd_amount, current_icp_rate, ic_cdk::api::time())
+            .iter()
+            .map(|(_, icusd, _)| *icusd)
+            .sum()
+    });
+    if redeemable_amount < icusd_amount - icusd_amount * quoted_fee_rate {
+        return Err(ProtocolError::GenericError(format!(
+            "only {redeemable_amount} icUSD can currently be redeemed"
+        )));
+    }
+    if let Some(limits) = &limits {
+        let now = ic_cdk::api::time();
+        if let Err(error) =
+            check_redemption_limits(limits, icusd_amount, quoted_fee_rate, current_icp_rate, now)
+        {
+            log!(INFO, "[redeem_icp] quote rejected for {}: {:?}", caller, error);
+            return Err(error);
+        }
+    }
 
     match transfer_icusd_from(icusd_amount, caller).await {
         Ok(block_index) => {
-            let fee_amount = mutate_state(|s| {
-                let base_fee = s.get_redemption_fee(icusd_amount);
-                s.current_base_rate = base_fee;
+            let (fee_amount, protocol_fee) = mutate_state(|s| {
+                let base_rate = s.get_redemption_fee(icusd_amount);
+                s.current_base_rate = base_rate;
                 s.last_redemption_time = ic_cdk::api::time();
-                let fee_amount = icusd_amount * base_fee;
+                // Another redemption may have landed during the transfer; never
+                // charge more than the quote the limits were checked against.
+                let fee_rate = if limits.is_some() {
+                    base_rate.min(quoted_fee_rate)
+                } else {
+                    base_rate
+                };
+                let fee_amount = icusd_amount * fee_rate;
 
-                record_redemption_on_vaults(
+                let protocol_fee = record_redemption_on_vaults(
                     s,
                     caller,
                     icusd_amount - fee_amount,
                     fee_amount,
                     current_icp_rate,
                     block_index,
+                    ic_cdk::api::time(),
                 );
-                fee_amount
+                (fee_amount, protocol_fee)
             });
             ic_cdk_timers::set_timer(std::time::Duration::from_secs(0), || {
                 ic_cdk::spawn(crate::process_pending_transfer())
             });
             
-            // Schedule redemption fee routing (async - don't block on failure)
-            if fee_amount.to_u64() > 0 {
-                let fee_u64 = fee_amount.to_u64();
+            // Schedule redemption fee routing (async - don't block on failure).
+            // Rebates owed to the redeemed vaults stay with the protocol until claimed.
+            if protocol_fee.to_u64() > 0 {
+                let fee_u64 = protocol_fee.to_u64();
                 ic_cdk_timers::set_timer(std::time::Duration::from_secs(1), move || {
                     ic_cdk::spawn(route_redemption_fee_to_treasury(fee_u64, block_index));
                 });
@@ -118,6 +575,154 @@ pub async fn redeem_icp(_icusd_amount: u64) -> Result<SuccessWithFee, ProtocolEr
     }
 }
 
+pub async fn claim_redemption_rebate(vault_id: u64) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller, "claim_redemption_rebate")?;
+
+    let rebate = match read_state(|s| s.redemption_rebates.get(&vault_id).cloned()) {
+        Some(rebate) => rebate,
+        None => {
+            return Err(ProtocolError::GenericError(format!(
+                "no redemption rebate for vault {vault_id}"
+            )))
+        }
+    };
+    if rebate.owner != caller {
+        return Err(ProtocolError::CallerNotOwner);
+    }
+
+    match mint_icusd(rebate.amount, caller).await {
+        Ok(block_index) => {
+            log!(
+                INFO,
+                "[claim_redemption_rebate] {caller} claimed {} for vault {vault_id}",
+                rebate.amount
+            );
+            mutate_state(|s| {
+                record_claim_redemption_rebate(s, vault_id, rebate.amount, block_index);
+            });
+            Ok(block_index)
+        }
+        Err(transfer_error) => Err(ProtocolError::TransferError(transfer_error)),
+    }
+}
+
+/// Burns icUSD from the caller, typically the treasury, against outstanding bad debt.
+pub async fn cover_bad_debt(amount: u64) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller, "cover_bad_debt")?;
+
+    let outstanding = read_state(|s| s.outstanding_bad_debt);
+    let amount = ICUSD::from(amount).min(outstanding);
+    if amount == 0 {
+        return Err(ProtocolError::GenericError(
+            "there is no outstanding bad debt".to_string(),
+        ));
+    }
+
+    match transfer_icusd_from(amount, caller).await {
+        Ok(block_index) => {
+            log!(INFO, "[cover_bad_debt] {caller} covered {amount} of bad debt");
+            mutate_state(|s| record_bad_debt_covered(s, amount, caller, block_index));
+            Ok(block_index)
+        }
+        Err(transfer_from_error) => Err(ProtocolError::TransferFromError(
+            transfer_from_error,
+            amount.to_u64(),
+        )),
+    }
+}
+
+pub const INTEREST_ACCRUAL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Charges the interest accrued since the previous accrual to all vaults.
+pub fn accrue_interest() {
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        if !s.needs_interest_accrual(now) {
+            return;
+        }
+        let interest = record_accrue_interest(s, now);
+        if interest > 0 {
+            log!(DEBUG, "[accrue_interest] accrued {interest} icUSD of interest");
+        }
+    });
+}
+
+/// Checks that `caller` can move their vault to the rate of `arg` at `now`
+/// and returns the new rate and the adjustment fee added to the debt.
+pub fn validate_interest_rate_adjustment(
+    s: &State,
+    caller: Principal,
+    arg: &AdjustInterestRateArg,
+    now: u64,
+) -> Result<(Ratio, ICUSD), ProtocolError> {
+    let interest_rate = match Decimal::from_f64(arg.interest_rate) {
+        Some(rate) => Ratio::from(rate),
+        None => {
+            return Err(ProtocolError::GenericError(
+                "invalid interest rate".to_string(),
+            ))
+        }
+    };
+    if interest_rate < MIN_INTEREST_RATE || interest_rate > MAX_INTEREST_RATE {
+        return Err(ProtocolError::GenericError(format!(
+            "interest rate must be between {} and {}",
+            MIN_INTEREST_RATE.to_f64(),
+            MAX_INTEREST_RATE.to_f64()
+        )));
+    }
+
+    let vault = s
+        .vault_id_to_vaults
+        .get(&arg.vault_id)
+        .ok_or(ProtocolError::VaultNotFound {
+            vault_id: arg.vault_id,
+        })?;
+    if vault.owner != caller {
+        return Err(ProtocolError::CallerNotOwner);
+    }
+
+    // The fee is new debt, so it is held to the same limits as a borrow
+    let fee = vault.interest_rate_adjustment_fee(interest_rate, now);
+    if fee > 0 {
+        let icp_rate = current_icp_rate(s)?;
+        let vault_after = Vault {
+            borrowed_icusd_amount: vault.borrowed_icusd_amount + fee,
+            ..vault.clone()
+        };
+        let ratio = compute_collateral_ratio(&vault_after, icp_rate);
+        if ratio < s.mode.get_minimum_liquidation_collateral_ratio() {
+            return Err(ProtocolError::CollateralRatioTooLow {
+                resulting: ratio.to_f64(),
+                required: s.mode.get_minimum_liquidation_collateral_ratio().to_f64(),
+            });
+        }
+        s.check_recovery_mode_rules(&vault_after)?;
+        s.check_debt_capacity(CollateralType::ICP, fee)?;
+    }
+    Ok((interest_rate, fee))
+}
+
+pub fn adjust_interest_rate(arg: AdjustInterestRateArg) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        let (interest_rate, fee) = validate_interest_rate_adjustment(s, caller, &arg, now)?;
+
+        // Settle the interest owed at the old rate before switching
+        record_accrue_interest(s, now);
+        record_adjust_interest_rate(s, arg.vault_id, interest_rate, fee, now);
+        log!(
+            INFO,
+            "[adjust_interest_rate] vault {} now at {}, paid {fee} icUSD adjustment fee",
+            arg.vault_id,
+            interest_rate.to_f64()
+        );
+        Ok(fee.to_u64())
+    })
+}
+
 pub async fn open_vault(icp_margin: u64) -> Result<OpenVaultSuccess, ProtocolError> {
     let caller = ic_cdk::api::caller();
     // Pass operation name to guard for better tracking
@@ -127,12 +732,6 @@ pub async fn open_vault(icp_margin: u64) -> Result<OpenVaultSuccess, ProtocolErr
             log!(INFO, "[open_vault] Principal {:?} already has an ongoing operation", caller);
             return Err(ProtocolError::AlreadyProcessing);
         },
-        Err(GuardError::StaleOperation) => {
-            log!(INFO, "[open_vault] Principal {:?} has a stale operation that's being cleaned up", caller);
-            return Err(ProtocolError::TemporarilyUnavailable(
-                "Previous operation is being cleaned up. Please try again in a few seconds.".to_string()
-            ));
-        },
         Err(err) => return Err(err.into()),
     };
 
@@ -146,22 +745,53 @@ pub async fn open_vault(icp_margin: u64) -> Result<OpenVaultSuccess, ProtocolErr
         });
     }
 
-    match transfer_icp_from(icp_margin_amount, caller).await {
+    if let Err(error) = read_state(|s| {
+        s.check_recovery_mode_rules(&Vault {
+            owner: caller,
+            borrowed_icusd_amount: 0.into(),
+            icp_margin_amount,
+            vault_id: s.next_available_vault_id,
+            liquidation_reserve: 0.into(),
+            interest_rate: MIN_INTEREST_RATE,
+            last_interest_rate_adjustment: ic_cdk::api::time(),
+            opened_at: ic_cdk::api::time(),
+        })
+    }) {
+        guard_principal.fail();
+        return Err(error);
+    }
+
+    // Journal the operation so that it can be resumed if this call never returns
+    let saga = mutate_state(|s| {
+        record_saga_started(
+            s,
+            SagaKind::OpenVault {
+                owner: caller,
+                icp_margin: icp_margin_amount,
+            },
+            ic_cdk::api::time(),
+        )
+    });
+
+    match saga::collect_margin(&saga).await {
         Ok(block_index) => {
             let vault_id = mutate_state(|s| {
-                let vault_id = s.increment_vault_id();
-                record_open_vault(
-                    s,
-                    Vault {
-                        owner: caller,
-                        borrowed_icusd_amount: 0.into(),
-                        icp_margin_amount,
-                        vault_id,
-                    },
-                    block_index,
-                );
-                vault_id
+                saga::finish_open_vault(s, saga.id, block_index, ic_cdk::api::time())
             });
+            // The saga was resumed and finished while this call was waiting
+            let vault_id = match vault_id {
+                Some(vault_id) => vault_id,
+                None => match read_state(|s| s.sagas.get(&saga.id).map(|saga| saga.status.clone())) {
+                    Some(SagaStatus::Completed { vault_id, .. }) => vault_id,
+                    status => {
+                        guard_principal.fail();
+                        return Err(ProtocolError::GenericError(format!(
+                            "open vault saga #{} ended as {:?}",
+                            saga.id, status
+                        )));
+                    }
+                },
+            };
             log!(INFO, "[open_vault] opened vault with id: {vault_id}");
             
             // Mark operation as successfully completed
@@ -175,6 +805,18 @@ pub async fn open_vault(icp_margin: u64) -> Result<OpenVaultSuccess, ProtocolErr
         Err(transfer_from_error) => {
             // Explicitly mark as failed when an error occurs
             guard_principal.fail();
+
+            // A transfer that may still go through is left to the saga timer
+            if !saga::is_transient_transfer_from_error(&transfer_from_error) {
+                mutate_state(|s| {
+                    saga::abort_saga(
+                        s,
+                        saga.id,
+                        format!("{:?}", transfer_from_error),
+                        ic_cdk::api::time(),
+                    )
+                });
+            }
             
             if let TransferFromError::BadFee { expected_fee } = transfer_from_error.clone() {
                 mutate_state(|s| {
@@ -182,7 +824,7 @@ pub async fn open_vault(icp_margin: u64) -> Result<OpenVaultSuccess, ProtocolErr
                         .0
                         .try_into()
                         .expect("failed to convert Nat to u64");
-                    s.icp_ledger_fee = ICP::from(expected_fee);
+                    crate::event::record_set_icp_ledger_fee(s, ICP::from(expected_fee));
                 });
             };
             Err(ProtocolError::TransferFromError(
@@ -193,67 +835,119 @@ pub async fn open_vault(icp_margin: u64) -> Result<OpenVaultSuccess, ProtocolErr
     }
 }
 
-pub async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, ProtocolError> {
-    let caller = ic_cdk::api::caller();
-    let guard_principal = match GuardPrincipal::new(caller, &format!("borrow_vault_{}", arg.vault_id)) {
-        Ok(guard) => guard,
-        Err(GuardError::AlreadyProcessing) => {
-            log!(INFO, "[borrow_from_vault] Principal {:?} already has an ongoing operation", caller);
-            return Err(ProtocolError::AlreadyProcessing);
-        },
-        Err(err) => return Err(err.into()),
-    };
-    
-    let amount: ICUSD = arg.amount.into();
+/// A borrow that passed validation.
+pub struct BorrowPlan {
+    pub vault_after: Vault,
+    /// Taken on by the first borrow of a vault.
+    pub liquidation_reserve: ICUSD,
+    pub fee: ICUSD,
+}
 
+/// Checks a borrow of `amount` from `vault_id` by `caller` against the current state.
+pub fn validate_borrow(
+    s: &State,
+    caller: Principal,
+    vault_id: u64,
+    amount: ICUSD,
+) -> Result<BorrowPlan, ProtocolError> {
     if amount < MIN_ICUSD_AMOUNT {
-        guard_principal.fail();
         return Err(ProtocolError::AmountTooLow {
             minimum_amount: MIN_ICUSD_AMOUNT.to_u64(),
         });
     }
 
-    let (vault, icp_rate) = match read_state(|s| {
-        match s.vault_id_to_vaults.get(&arg.vault_id) {
-            Some(vault) => Ok((
-                vault.clone(),
-                s.last_icp_rate.expect("no icp rate"),
-            )),
-            None => {
-                // Let's find if vault exists with a friendly error
-                Err("Vault not found. Please check the vault ID.")
-            }
-        }
-    }) {
-        Ok(result) => result,
-        Err(msg) => {
-            guard_principal.fail();
-            return Err(ProtocolError::GenericError(msg.to_string()));
-        }
-    };
+    let vault = s
+        .vault_id_to_vaults
+        .get(&vault_id)
+        .cloned()
+        .ok_or(ProtocolError::VaultNotFound { vault_id })?;
+    let icp_rate = current_icp_rate(s)?;
 
     if caller != vault.owner {
-        guard_principal.fail();
         return Err(ProtocolError::CallerNotOwner);
     }
 
-    let max_borrowable_amount = vault.icp_margin_amount * icp_rate
-        / read_state(|s| s.mode.get_minimum_liquidation_collateral_ratio());
+    // The first borrow of a vault also takes on the liquidation reserve
+    let liquidation_reserve = if vault.borrowed_icusd_amount == 0 {
+        LIQUIDATION_RESERVE
+    } else {
+        ICUSD::new(0)
+    };
 
-    if vault.borrowed_icusd_amount + amount > max_borrowable_amount {
-        guard_principal.fail();
-        return Err(ProtocolError::GenericError(format!(
-            "failed to borrow from vault, max borrowable: {max_borrowable_amount}, borrowed: {}, requested: {amount}",
-            vault.borrowed_icusd_amount
-        )));
+    if vault.net_debt() + amount < MIN_NET_DEBT {
+        return Err(ProtocolError::AmountTooLow {
+            minimum_amount: (MIN_NET_DEBT - vault.net_debt()).to_u64(),
+        });
+    }
+
+    let vault_after = Vault {
+        borrowed_icusd_amount: vault.borrowed_icusd_amount + amount + liquidation_reserve,
+        liquidation_reserve: vault.liquidation_reserve + liquidation_reserve,
+        ..vault.clone()
+    };
+    let minimum_ratio = s.mode.get_minimum_liquidation_collateral_ratio();
+    let max_borrowable_amount = vault.icp_margin_amount * icp_rate / minimum_ratio;
+    if vault_after.borrowed_icusd_amount > max_borrowable_amount {
+        return Err(ProtocolError::CollateralRatioTooLow {
+            resulting: compute_collateral_ratio(&vault_after, icp_rate).to_f64(),
+            required: minimum_ratio.to_f64(),
+        });
     }
 
-    let fee: ICUSD = read_state(|s| amount * s.get_borrowing_fee());
+    s.check_recovery_mode_rules(&vault_after)?;
+    s.check_debt_capacity(CollateralType::ICP, amount + liquidation_reserve)?;
+
+    Ok(BorrowPlan {
+        vault_after,
+        liquidation_reserve,
+        fee: amount * s.get_borrowing_fee(),
+    })
+}
+
+pub async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let guard_principal = match GuardPrincipal::new_for_vault(caller, "borrow_from_vault", arg.vault_id) {
+        Ok(guard) => guard,
+        Err(GuardError::AlreadyProcessing) => {
+            log!(INFO, "[borrow_from_vault] Principal {:?} already has an ongoing operation", caller);
+            return Err(ProtocolError::AlreadyProcessing);
+        },
+        Err(err) => return Err(err.into()),
+    };
+    
+    let amount: ICUSD = arg.amount.into();
+    let BorrowPlan {
+        liquidation_reserve,
+        fee,
+        ..
+    } = match read_state(|s| validate_borrow(s, caller, arg.vault_id, amount)) {
+        Ok(plan) => plan,
+        Err(error) => {
+            guard_principal.fail();
+            return Err(error);
+        }
+    };
+
+    // Hold the debt capacity across the mint so concurrent borrows can't exceed the ceilings
+    let _reservation = match DebtReservation::new(CollateralType::ICP, amount + liquidation_reserve) {
+        Ok(reservation) => reservation,
+        Err(error) => {
+            guard_principal.fail();
+            return Err(error);
+        }
+    };
 
     match mint_icusd(amount - fee, caller).await {
         Ok(block_index) => {
             mutate_state(|s| {
-                record_borrow_from_vault(s, arg.vault_id, amount, fee, block_index);
+                record_borrow_from_vault(
+                    s,
+                    arg.vault_id,
+                    amount,
+                    fee,
+                    liquidation_reserve,
+                    block_index,
+                );
             });
             
             // Schedule treasury fee routing (async - don't block on failure)
@@ -283,32 +977,62 @@ pub async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, Protocol
     }
 }
 
-pub async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
-    let caller = ic_cdk::api::caller();
-    let guard_principal = GuardPrincipal::new(caller, &format!("repay_vault_{}", arg.vault_id))?;
-    let amount: ICUSD = arg.amount.into();
-    let vault = read_state(|s| s.vault_id_to_vaults.get(&arg.vault_id).cloned().unwrap());
+/// Checks a repayment of `amount` to `vault_id` by `caller` against the
+/// current state and returns the vault after it.
+pub fn validate_repay(
+    s: &State,
+    caller: Principal,
+    vault_id: u64,
+    amount: ICUSD,
+) -> Result<Vault, ProtocolError> {
+    let vault = s
+        .vault_id_to_vaults
+        .get(&vault_id)
+        .cloned()
+        .ok_or(ProtocolError::VaultNotFound { vault_id })?;
 
     if caller != vault.owner {
-        guard_principal.fail();
         return Err(ProtocolError::CallerNotOwner);
     }
 
     if amount < MIN_ICUSD_AMOUNT {
-        guard_principal.fail();
         return Err(ProtocolError::AmountTooLow {
             minimum_amount: MIN_ICUSD_AMOUNT.to_u64(),
         });
     }
 
-    if vault.borrowed_icusd_amount < amount {
-        guard_principal.fail();
+    if vault.net_debt() < amount {
         return Err(ProtocolError::GenericError(format!(
             "cannot repay more than borrowed: {} ICUSD, repay: {} ICUSD",
-            vault.borrowed_icusd_amount, amount
+            vault.net_debt(), amount
         )));
     }
 
+    let remaining_net_debt = vault.net_debt() - amount;
+    if remaining_net_debt > 0 && remaining_net_debt < MIN_NET_DEBT {
+        return Err(ProtocolError::GenericError(format!(
+            "vault debt must be repaid in full or stay above {} ICUSD, remaining: {} ICUSD",
+            MIN_NET_DEBT, remaining_net_debt
+        )));
+    }
+
+    let mut vault_after = Vault {
+        borrowed_icusd_amount: vault.borrowed_icusd_amount - amount,
+        ..vault
+    };
+    vault_after.settle_liquidation_reserve();
+    Ok(vault_after)
+}
+
+pub async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let guard_principal = GuardPrincipal::new_for_vault(caller, "repay_to_vault", arg.vault_id)?;
+    let amount: ICUSD = arg.amount.into();
+    if let Err(error) = read_state(|s| validate_repay(s, caller, arg.vault_id, amount)) {
+        guard_principal.fail();
+        return Err(error);
+    }
+
     match transfer_icusd_from(amount, caller).await {
         Ok(block_index) => {
             mutate_state(|s| record_repayed_to_vault(s, arg.vault_id, amount, block_index));
@@ -327,7 +1051,7 @@ pub async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
 
 pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
     let caller = ic_cdk::api::caller();
-    let _guard_principal = GuardPrincipal::new(caller, &format!("add_margin_vault_{}", arg.vault_id))?;
+    let _guard_principal = GuardPrincipal::new_for_vault(caller, "add_margin_to_vault", arg.vault_id)?;
     let amount: ICP = arg.amount.into();
 
     if amount < MIN_ICP_AMOUNT {
@@ -336,7 +1060,11 @@ pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
         });
     }
 
-    let vault = read_state(|s| s.vault_id_to_vaults.get(&arg.vault_id).cloned().unwrap());
+    let vault = read_state(|s| s.vault_id_to_vaults.get(&arg.vault_id).cloned()).ok_or(
+        ProtocolError::VaultNotFound {
+            vault_id: arg.vault_id,
+        },
+    )?;
     if caller != vault.owner {
         return Err(ProtocolError::CallerNotOwner);
     }
@@ -353,7 +1081,7 @@ pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
                         .0
                         .try_into()
                         .expect("failed to convert Nat to u64");
-                    s.icp_ledger_fee = ICP::from(expected_fee);
+                    crate::event::record_set_icp_ledger_fee(s, ICP::from(expected_fee));
                 });
             };
             Err(ProtocolError::TransferFromError(error, amount.to_u64()))
@@ -363,7 +1091,7 @@ pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
 
 pub async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
     let caller = ic_cdk::caller();
-    let _guard_principal = GuardPrincipal::new(caller, &format!("close_vault_{}", vault_id))?;
+    let _guard_principal = GuardPrincipal::new_for_vault(caller, "close_vault", vault_id)?;
     
     // Check if the vault exists first
     let vault_exists = read_state(|s| s.vault_id_to_vaults.contains_key(&vault_id));
@@ -375,7 +1103,7 @@ pub async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
             vault_id,
             caller
         );
-        return Err(ProtocolError::GenericError(format!("Vault #{} not found", vault_id)));
+        return Err(ProtocolError::VaultNotFound { vault_id });
     }
     
     // Get the vault
@@ -383,7 +1111,7 @@ pub async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
         s.vault_id_to_vaults
             .get(&vault_id)
             .cloned()
-            .ok_or(ProtocolError::GenericError("Vault not found".to_string()))
+            .ok_or(ProtocolError::VaultNotFound { vault_id })
     })?;
 
     // Verify caller is the owner
@@ -440,7 +1168,7 @@ pub async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
             }
             
             // Record the close vault event
-            crate::event::record_close_vault(s, vault_id, None);
+            crate::event::record_close_vault(s, vault_id, None, ic_cdk::api::time());
             
             log!(
                 INFO,
@@ -462,58 +1190,62 @@ pub async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
     Ok(None)
 }
 
-pub async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
-    let caller = ic_cdk::caller();
-    let _guard_principal = GuardPrincipal::new(caller, &format!("withdraw_collateral_{}", vault_id))?;
-    
-    log!(
-        INFO,
-        "[withdraw_collateral] Request to withdraw collateral from vault #{} by principal {}",
-        vault_id,
-        caller
-    );
-    
-    // Check vault exists and caller is owner
-    let vault = read_state(|state| {
-        state.vault_id_to_vaults
-            .get(&vault_id)
-            .cloned()
-            .ok_or(ProtocolError::GenericError("Vault not found".to_string()))
-    })?;
-    
+/// Checks a withdrawal of all the collateral of `vault_id` by `caller`
+/// against the current state and returns the vault after it.
+pub fn validate_withdraw(s: &State, caller: Principal, vault_id: u64) -> Result<Vault, ProtocolError> {
+    let vault = s
+        .vault_id_to_vaults
+        .get(&vault_id)
+        .cloned()
+        .ok_or(ProtocolError::VaultNotFound { vault_id })?;
+
     if caller != vault.owner {
-        log!(
-            INFO,
-            "[withdraw_collateral] Caller {} is not the owner of vault #{}",
-            caller,
-            vault_id
-        );
         return Err(ProtocolError::CallerNotOwner);
     }
-    
-    // Check there's no debt
+
     if vault.borrowed_icusd_amount > ICUSD::new(0) {
-        log!(
-            INFO,
-            "[withdraw_collateral] Vault #{} has outstanding debt of {} icUSD",
-            vault_id,
-            vault.borrowed_icusd_amount
-        );
         return Err(ProtocolError::GenericError(format!(
             "Vault has {} icUSD debt. You must repay all debt before withdrawing collateral.",
             vault.borrowed_icusd_amount
         )));
     }
+
+    if vault.icp_margin_amount == ICP::new(0) {
+        return Err(ProtocolError::GenericError("No collateral to withdraw".to_string()));
+    }
+
+    let vault_after = Vault {
+        icp_margin_amount: ICP::new(0),
+        ..vault
+    };
+    s.check_recovery_mode_rules(&vault_after)?;
+    Ok(vault_after)
+}
+
+pub async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::caller();
+    let _guard_principal = GuardPrincipal::new_for_vault(caller, "withdraw_collateral", vault_id)?;
+    
+    log!(
+        INFO,
+        "[withdraw_collateral] Request to withdraw collateral from vault #{} by principal {}",
+        vault_id,
+        caller
+    );
     
-    // Check there's collateral to withdraw
-    if vault.icp_margin_amount == ICP::new(0) {
+    let vault = read_state(|s| {
+        validate_withdraw(s, caller, vault_id)?;
+        Ok::<_, ProtocolError>(s.vault_id_to_vaults[&vault_id].clone())
+    })
+    .map_err(|error| {
         log!(
             INFO,
-            "[withdraw_collateral] Vault #{} has no collateral to withdraw",
-            vault_id
+            "[withdraw_collateral] Cannot withdraw from vault #{}: {:?}",
+            vault_id,
+            error
         );
-        return Err(ProtocolError::GenericError("No collateral to withdraw".to_string()));
-    }
+        error
+    })?;
     
     // Get the amount to transfer
     let amount_to_transfer = vault.icp_margin_amount;
@@ -524,11 +1256,18 @@ pub async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
         vault_id
     );
     
-    // Set margin to zero in vault BEFORE transferring to avoid reentrancy issues
-    mutate_state(|state| {
-        if let Some(vault) = state.vault_id_to_vaults.get_mut(&vault_id) {
-            vault.icp_margin_amount = ICP::new(0);
-        }
+    // Journal the withdrawal, which takes the margin out of the vault BEFORE
+    // transferring to avoid reentrancy issues
+    let saga = mutate_state(|s| {
+        record_saga_started(
+            s,
+            SagaKind::WithdrawCollateral {
+                owner: caller,
+                vault_id,
+                amount: amount_to_transfer,
+            },
+            ic_cdk::api::time(),
+        )
     });
     
     // Make the ICP transfer with appropriate fee deduction
@@ -542,11 +1281,11 @@ pub async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
         caller
     );
     
-    match management::transfer_icp(transfer_amount, caller).await {
+    match saga::send_margin(&saga, ledger_fee).await {
         Ok(block_index) => {
-            // Fix for the lifetime issue - we need to use a separate mutate_state call
-            // Rather than passing a mutable reference to the state
-            mutate_state(|s| crate::event::record_collateral_withdrawn(s, vault_id, amount_to_transfer, block_index));
+            mutate_state(|s| {
+                saga::finish_withdraw_collateral(s, saga.id, block_index, ic_cdk::api::time())
+            });
             
             log!(
                 INFO,
@@ -559,13 +1298,6 @@ pub async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
             Ok(block_index)
         },
         Err(error) => {
-            // If the transfer fails, we need to restore the collateral in the vault
-            mutate_state(|state| {
-                if let Some(vault) = state.vault_id_to_vaults.get_mut(&vault_id) {
-                    vault.icp_margin_amount = amount_to_transfer;
-                }
-            });
-            
             log!(
                 DEBUG,
                 "[withdraw_collateral] Failed to transfer {} ICP to {}, error: {}",
@@ -573,7 +1305,18 @@ pub async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
                 caller,
                 error
             );
+
+            if saga::is_transient_transfer_error(&error) {
+                return Err(ProtocolError::TemporarilyUnavailable(format!(
+                    "the withdrawal will be retried as saga #{}",
+                    saga.id
+                )));
+            }
             
+            // If the transfer fails, the collateral goes back to the vault
+            mutate_state(|s| {
+                saga::abort_saga(s, saga.id, error.to_string(), ic_cdk::api::time())
+            });
             Err(ProtocolError::TransferError(error))
         }
     }
@@ -582,7 +1325,7 @@ pub async fn withdraw_collateral(vault_id: u64) -> Result<u64, ProtocolError> {
 pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
     let caller = ic_cdk::caller();
     // Use a specific name for better tracking
-    let _guard_principal = GuardPrincipal::new(caller, &format!("withdraw_and_close_{}", vault_id))?;
+    let _guard_principal = GuardPrincipal::new_for_vault(caller, "withdraw_and_close_vault", vault_id)?;
     
     log!(
         INFO,
@@ -596,7 +1339,7 @@ pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, Prot
         s.vault_id_to_vaults
             .get(&vault_id)
             .cloned()
-            .ok_or(ProtocolError::GenericError(format!("Vault #{} not found", vault_id)))
+            .ok_or(ProtocolError::VaultNotFound { vault_id })
     })?;
     
     // Verify caller is the owner
@@ -624,8 +1367,14 @@ pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, Prot
         )));
     }
     
+    read_state(|s| {
+        s.check_recovery_mode_rules(&Vault {
+            icp_margin_amount: ICP::new(0),
+            ..vault.clone()
+        })
+    })?;
+
     // If there's collateral, withdraw it first
-    let mut block_index: Option<u64> = None;
     let amount_to_transfer = vault.icp_margin_amount; // Get the amount even if zero
     
     if amount_to_transfer > ICP::new(0) {
@@ -636,11 +1385,18 @@ pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, Prot
             vault_id
         );
         
-        // Set margin to zero in vault BEFORE transferring to avoid reentrancy issues
-        mutate_state(|state| {
-            if let Some(vault) = state.vault_id_to_vaults.get_mut(&vault_id) {
-                vault.icp_margin_amount = ICP::new(0);
-            }
+        // Journal the withdrawal, which takes the margin out of the vault BEFORE
+        // transferring to avoid reentrancy issues
+        let saga = mutate_state(|s| {
+            record_saga_started(
+                s,
+                SagaKind::WithdrawAndCloseVault {
+                    owner: caller,
+                    vault_id,
+                    amount: amount_to_transfer,
+                },
+                ic_cdk::api::time(),
+            )
         });
         
         // Make the ICP transfer with appropriate fee deduction
@@ -654,29 +1410,24 @@ pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, Prot
             caller
         );
         
-        match management::transfer_icp(transfer_amount, caller).await {
+        match saga::send_margin(&saga, ledger_fee).await {
             Ok(idx) => {
-                // Record the withdrawal event
-                mutate_state(|s| crate::event::record_collateral_withdrawn(s, vault_id, amount_to_transfer, idx));
+                // Record the withdrawal and close the vault
+                mutate_state(|s| {
+                    saga::finish_withdraw_and_close(s, saga.id, idx, ic_cdk::api::time())
+                });
                 
                 log!(
                     INFO,
-                    "[withdraw_and_close] Successfully withdrew {} ICP from vault #{}, block_index: {}",
+                    "[withdraw_and_close] Successfully withdrew {} ICP and closed vault #{}, block_index: {}",
                     amount_to_transfer,
                     vault_id,
                     idx
                 );
                 
-                block_index = Some(idx);
+                return Ok(Some(idx));
             },
             Err(error) => {
-                // CRITICAL: If the transfer fails, restore the collateral and exit WITHOUT closing the vault
-                mutate_state(|state| {
-                    if let Some(vault) = state.vault_id_to_vaults.get_mut(&vault_id) {
-                        vault.icp_margin_amount = amount_to_transfer;
-                    }
-                });
-                
                 log!(
                     DEBUG,
                     "[withdraw_and_close] Failed to transfer {} ICP to {}, error: {}",
@@ -684,7 +1435,18 @@ pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, Prot
                     caller,
                     error
                 );
+
+                if saga::is_transient_transfer_error(&error) {
+                    return Err(ProtocolError::TemporarilyUnavailable(format!(
+                        "the withdrawal will be retried as saga #{}",
+                        saga.id
+                    )));
+                }
                 
+                // CRITICAL: If the transfer fails, restore the collateral and exit WITHOUT closing the vault
+                mutate_state(|s| {
+                    saga::abort_saga(s, saga.id, error.to_string(), ic_cdk::api::time())
+                });
                 return Err(ProtocolError::TransferError(error));
             }
         }
@@ -692,13 +1454,12 @@ pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, Prot
         log!(INFO, "[withdraw_and_close] Vault #{} has no collateral to withdraw", vault_id);
     };
     
-    // Now close the vault - only if we've successfully transferred any funds
-    // or if there were no funds to transfer
+    // Now close the vault, there were no funds to transfer
     mutate_state(|s| {
         // Make sure vault exists before attempting to remove
         if s.vault_id_to_vaults.contains_key(&vault_id) {
             // Record the combined withdraw and close event
-            crate::event::record_withdraw_and_close_vault(s, vault_id, amount_to_transfer, block_index);
+            crate::event::record_withdraw_and_close_vault(s, vault_id, amount_to_transfer, None, ic_cdk::api::time());
             
             log!(
                 INFO,
@@ -716,67 +1477,58 @@ pub async fn withdraw_and_close_vault(vault_id: u64) -> Result<Option<u64>, Prot
         }
     });
     
-    // Return the block index if we did a transfer, otherwise None
-    Ok(block_index)
+    // No transfer was made
+    Ok(None)
 }
 
-pub async fn liquidate_vault_partial(vault_id: u64, icusd_amount: u64) -> Result<SuccessWithFee, ProtocolError> {
-    let caller = ic_cdk::api::caller();
-    let guard_principal = GuardPrincipal::new(caller, &format!("liquidate_vault_partial_{}", vault_id))?;
-    
-    let liquidation_amount: ICUSD = icusd_amount.into();
-    
+/// Checks that `vault_id` can be partially liquidated for `liquidation_amount`
+/// and returns the vault, the ICP rate, the debt actually liquidated and the
+/// collateral seized for it.
+pub fn validate_partial_liquidation(
+    s: &State,
+    vault_id: u64,
+    liquidation_amount: ICUSD,
+) -> Result<(Vault, UsdIcp, ICUSD, ICP), ProtocolError> {
     if liquidation_amount < MIN_ICUSD_AMOUNT {
-        guard_principal.fail();
         return Err(ProtocolError::AmountTooLow {
             minimum_amount: MIN_ICUSD_AMOUNT.to_u64(),
         });
     }
+    let (vault, icp_rate, _mode) = validate_liquidation(s, vault_id)?;
+
+    // Calculate maximum liquidatable debt (e.g., 50% of total debt)
+    let max_liquidation_ratio = Ratio::new(dec!(0.5)); // 50% max
+    let max_liquidatable = vault.borrowed_icusd_amount * max_liquidation_ratio;
+
+    // Ensure requested amount doesn't exceed maximum
+    let actual_liquidation_amount = liquidation_amount.min(max_liquidatable).min(vault.net_debt());
+
+    if actual_liquidation_amount == ICUSD::new(0) {
+        return Err(ProtocolError::GenericError("Cannot liquidate zero amount".to_string()));
+    }
+
+    // Calculate collateral to transfer (debt + 10% bonus)
+    let liquidation_bonus = Ratio::new(dec!(1.1)); // 110% (10% bonus)
+    let icp_equivalent = actual_liquidation_amount / icp_rate;
+    let collateral_with_bonus = icp_equivalent * liquidation_bonus;
+    let collateral_to_transfer = collateral_with_bonus.min(vault.icp_margin_amount);
+
+    Ok((vault, icp_rate, actual_liquidation_amount, collateral_to_transfer))
+}
+
+pub async fn liquidate_vault_partial(vault_id: u64, icusd_amount: u64) -> Result<SuccessWithFee, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let guard_principal = GuardPrincipal::new_for_vault(caller, "liquidate_vault_partial", vault_id)?;
     
     // Step 1: Validate vault is liquidatable and get partial liquidation amounts
-    let (vault, icp_rate, _mode, max_liquidatable_debt, collateral_to_liquidator) = match read_state(|s| {
-        match s.vault_id_to_vaults.get(&vault_id) {
-            Some(vault) => {
-                let icp_rate = s.last_icp_rate.expect("no icp rate");
-                let ratio = compute_collateral_ratio(vault, icp_rate);
-                
-                if ratio >= s.mode.get_minimum_liquidation_collateral_ratio() {
-                    Err(format!(
-                        "Vault #{} is not liquidatable. Current ratio: {}, minimum: {}",
-                        vault_id, 
-                        ratio.to_f64(), 
-                        s.mode.get_minimum_liquidation_collateral_ratio().to_f64()
-                    ))
-                } else {
-                    // Calculate maximum liquidatable debt (e.g., 50% of total debt)
-                    let max_liquidation_ratio = Ratio::new(dec!(0.5)); // 50% max
-                    let max_liquidatable = vault.borrowed_icusd_amount * max_liquidation_ratio;
-                    
-                    // Ensure requested amount doesn't exceed maximum
-                    let actual_liquidation_amount = liquidation_amount.min(max_liquidatable).min(vault.borrowed_icusd_amount);
-                    
-                    if actual_liquidation_amount == ICUSD::new(0) {
-                        return Err("Cannot liquidate zero amount".to_string());
-                    }
-                    
-                    // Calculate collateral to transfer (debt + 10% bonus)
-                    let liquidation_bonus = Ratio::new(dec!(1.1)); // 110% (10% bonus)
-                    let icp_equivalent = actual_liquidation_amount / icp_rate;
-                    let collateral_with_bonus = icp_equivalent * liquidation_bonus;
-                    let collateral_to_transfer = collateral_with_bonus.min(vault.icp_margin_amount);
-                    
-                    Ok((vault.clone(), icp_rate, s.mode, actual_liquidation_amount, collateral_to_transfer))
-                }
-            },
-            None => Err(format!("Vault #{} not found", vault_id)),
-        }
-    }) {
-        Ok(result) => result,
-        Err(msg) => {
-            guard_principal.fail();
-            return Err(ProtocolError::GenericError(msg));
-        }
-    };
+    let (vault, icp_rate, max_liquidatable_debt, collateral_to_liquidator) =
+        match read_state(|s| validate_partial_liquidation(s, vault_id, icusd_amount.into())) {
+            Ok(result) => result,
+            Err(error) => {
+                guard_principal.fail();
+                return Err(error);
+            }
+        };
 
     log!(INFO, 
         "[liquidate_vault_partial] Vault #{}: liquidating {} icUSD (max: {}), getting {} ICP collateral",
@@ -799,7 +1551,7 @@ pub async fn liquidate_vault_partial(vault_id: u64, icusd_amount: u64) -> Result
     };
     
     // Step 3: Update protocol state (partial liquidation)
-    mutate_state(|s| {
+    let transfer_ids = mutate_state(|s| {
         s.liquidate_vault_partial(vault_id, max_liquidatable_debt, collateral_to_liquidator, icp_rate);
         
         // Record the partial liquidation event
@@ -813,25 +1565,27 @@ pub async fn liquidate_vault_partial(vault_id: u64, icusd_amount: u64) -> Result
         crate::storage::record_event(&event);
         
         // Create pending transfer for liquidator reward
-        s.pending_margin_transfers.insert(
-            vault_id, 
-            PendingMarginTransfer {
-                owner: caller,
-                margin: collateral_to_liquidator,
-            },
+        let transfer_id = crate::event::record_transfer_queued(
+            s,
+            vault_id,
+            TransferPurpose::LiquidationReward,
+            caller,
+            collateral_to_liquidator,
+            ic_cdk::api::time(),
         );
         
         log!(INFO, "[liquidate_vault_partial] Partial liquidation completed, {} pending transfers created", 1);
+        vec![transfer_id]
     });
     
     // Step 4: Process transfer (same as complete liquidation)
-    match try_process_pending_transfers_immediate(vault_id).await {
+    match try_process_pending_transfers_immediate(transfer_ids.clone()).await {
         Ok(processed_count) => {
             log!(INFO, "[liquidate_vault_partial] Successfully processed {} transfers immediately", processed_count);
         },
         Err(e) => {
             log!(INFO, "[liquidate_vault_partial] Immediate processing failed: {}. Transfers will be retried via timer", e);
-            schedule_transfer_retry(vault_id, 0);
+            schedule_transfer_retry(transfer_ids, 0);
         }
     }
     
@@ -861,50 +1615,153 @@ pub async fn liquidate_vault_partial(vault_id: u64, icusd_amount: u64) -> Result
     })
 }
 
+struct LiquidationAmounts {
+    /// icUSD the liquidator pays.
+    debt_amount: ICUSD,
+    icp_to_liquidator: ICP,
+    /// ICP returned to the vault owner.
+    excess_collateral: ICP,
+    /// Debt the collateral can't cover.
+    bad_debt: ICUSD,
+    /// Part of the liquidation penalty kept by the protocol for the treasury.
+    protocol_penalty: ICP,
+}
+
+fn full_liquidation_amounts(
+    vault: &Vault,
+    icp_rate: UsdIcp,
+    protocol_share: Ratio,
+) -> LiquidationAmounts {
+    let liquidation_bonus = Ratio::new(dec!(1.1)); // 110% (10% bonus)
+    let collateral_value = vault.icp_margin_amount * icp_rate;
+    // The liquidator is paid the liquidation reserve by only covering the net
+    // debt, and never pays more than the collateral is worth: only the real
+    // shortfall of an underwater vault becomes bad debt.
+    let net_debt = vault.net_debt();
+    let debt_amount = net_debt.min(collateral_value);
+    // The bonus is capped the same way whatever the collateral ratio, so that
+    // waiting for a vault to go underwater never pays more.
+    let icp_seized = (net_debt * liquidation_bonus / icp_rate).min(vault.icp_margin_amount);
+    let excess_collateral = vault.icp_margin_amount - icp_seized;
+    let protocol_penalty = icp_seized.saturating_sub(debt_amount / icp_rate) * protocol_share;
+    LiquidationAmounts {
+        debt_amount,
+        icp_to_liquidator: icp_seized - protocol_penalty,
+        excess_collateral,
+        bad_debt: net_debt - debt_amount,
+        protocol_penalty,
+    }
+}
+
+/// Selects the liquidatable vaults among `vault_ids`, lowest collateral ratio
+/// first, whose debt fits within `max_total_debt`. Vaults locked by another
+/// operation are skipped. Returns them with the rate and mode to liquidate at.
+pub fn select_vaults_to_liquidate(
+    s: &State,
+    vault_ids: &[u64],
+    max_total_debt: ICUSD,
+    now: u64,
+) -> Result<(Vec<Vault>, UsdIcp, Mode), ProtocolError> {
+    let icp_rate = current_icp_rate(s)?;
+    let minimum_ratio = s.mode.get_minimum_liquidation_collateral_ratio();
+    let mut candidates: Vec<(Ratio, Vault)> = s
+        .vault_id_to_vaults
+        .iter()
+        .filter(|(vault_id, _)| vault_ids.contains(vault_id))
+        .filter(|(vault_id, _)| !s.is_locked(&LockKey::Vault(**vault_id), now))
+        .map(|(_, vault)| (compute_collateral_ratio(vault, icp_rate), vault.clone()))
+        .filter(|(ratio, _)| *ratio < minimum_ratio)
+        .collect();
+    candidates.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.vault_id.cmp(&b.1.vault_id)));
+
+    let mut total_debt = ICUSD::new(0);
+    let mut selected = vec![];
+    for (_ratio, vault) in candidates {
+        let debt = full_liquidation_amounts(&vault, icp_rate, s.effective_liquidation_protocol_share()).debt_amount;
+        if total_debt + debt > max_total_debt {
+            continue;
+        }
+        total_debt += debt;
+        selected.push(vault);
+    }
+    if selected.is_empty() {
+        return Err(ProtocolError::GenericError(
+            "none of the vaults can be liquidated within the debt limit".to_string(),
+        ));
+    }
+    Ok((selected, icp_rate, s.mode))
+}
+
+/// Mints the icUSD refunds of batch liquidations owed to the caller.
+pub async fn claim_liquidation_refund() -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal =
+        GuardPrincipal::new_for_liquidation_refund(caller, "claim_liquidation_refund")?;
+
+    let amount = read_state(|s| s.get_liquidation_refund_of(caller));
+    if amount == 0 {
+        return Err(ProtocolError::GenericError(
+            "no liquidation refund to claim".to_string(),
+        ));
+    }
+
+    match mint_icusd(amount, caller).await {
+        Ok(block_index) => {
+            log!(INFO, "[claim_liquidation_refund] {caller} claimed {amount}");
+            mutate_state(|s| record_liquidation_refund_claimed(s, caller, amount, block_index));
+            Ok(block_index)
+        }
+        Err(transfer_error) => Err(ProtocolError::TransferError(transfer_error)),
+    }
+}
+
+/// Checks that `vault_id` can be liquidated and returns it with the rate and
+/// mode to liquidate it at.
+pub fn validate_liquidation(s: &State, vault_id: u64) -> Result<(Vault, UsdIcp, Mode), ProtocolError> {
+    let vault = s
+        .vault_id_to_vaults
+        .get(&vault_id)
+        .ok_or(ProtocolError::VaultNotFound { vault_id })?;
+    let icp_rate = current_icp_rate(s)?;
+    let ratio = compute_collateral_ratio(vault, icp_rate);
+    let minimum_ratio = s.mode.get_minimum_liquidation_collateral_ratio();
+    if ratio >= minimum_ratio {
+        return Err(ProtocolError::NotLiquidatable {
+            ratio: ratio.to_f64(),
+            minimum: minimum_ratio.to_f64(),
+        });
+    }
+    Ok((vault.clone(), icp_rate, s.mode))
+}
+
 pub async fn liquidate_vault(vault_id: u64) -> Result<SuccessWithFee, ProtocolError> {
     let caller = ic_cdk::api::caller();
-    let guard_principal = GuardPrincipal::new(caller, &format!("liquidate_vault_{}", vault_id))?;
+    let guard_principal = GuardPrincipal::new_for_vault(caller, "liquidate_vault", vault_id)?;
     
     // Step 1: Validate vault is liquidatable
-    let (vault, icp_rate, mode) = match read_state(|s| {
-        match s.vault_id_to_vaults.get(&vault_id) {
-            Some(vault) => {
-                let icp_rate = s.last_icp_rate.expect("no icp rate");
-                let ratio = compute_collateral_ratio(vault, icp_rate);
-                
-                if ratio >= s.mode.get_minimum_liquidation_collateral_ratio() {
-                    Err(format!(
-                        "Vault #{} is not liquidatable. Current ratio: {}, minimum: {}",
-                        vault_id, 
-                        ratio.to_f64(), 
-                        s.mode.get_minimum_liquidation_collateral_ratio().to_f64()
-                    ))
-                } else {
-                    Ok((vault.clone(), icp_rate, s.mode))
-                }
-            },
-            None => Err(format!("Vault #{} not found", vault_id)),
-        }
-    }) {
+    let (vault, icp_rate, mode) = match read_state(|s| validate_liquidation(s, vault_id)) {
         Ok(result) => result,
-        Err(msg) => {
+        Err(error) => {
             guard_principal.fail();
-            return Err(ProtocolError::GenericError(msg));
+            return Err(error);
         }
     };
 
     // Step 2: Calculate liquidation amounts
-    let debt_amount = vault.borrowed_icusd_amount;
-    let icp_equivalent = debt_amount / icp_rate;
-    let liquidation_bonus = Ratio::new(dec!(1.1)); // 110% (10% bonus)
-    let icp_with_bonus = icp_equivalent * liquidation_bonus;
-    let icp_to_liquidator = icp_with_bonus.min(vault.icp_margin_amount);
-    let excess_collateral = vault.icp_margin_amount.saturating_sub(icp_to_liquidator);
+    let protocol_share = read_state(|s| s.effective_liquidation_protocol_share());
+    let LiquidationAmounts {
+        debt_amount,
+        icp_to_liquidator,
+        excess_collateral,
+        bad_debt,
+        protocol_penalty,
+    } = full_liquidation_amounts(&vault, icp_rate, protocol_share);
     
     log!(INFO, 
-        "[liquidate_vault] Vault #{}: debt={} icUSD, liquidator gets {} ICP, excess={} ICP",
+        "[liquidate_vault] Vault #{}: debt={} icUSD, reserve={} icUSD, liquidator gets {} ICP, excess={} ICP",
         vault_id, 
         debt_amount.to_u64(),
+        vault.liquidation_reserve.to_u64(),
         icp_to_liquidator.to_u64(),
         excess_collateral.to_u64()
     );
@@ -922,7 +1779,7 @@ pub async fn liquidate_vault(vault_id: u64) -> Result<SuccessWithFee, ProtocolEr
     };
     
     // Step 4: Update protocol state ATOMICALLY (this is the critical section)
-    mutate_state(|s| {
+    let transfer_ids = mutate_state(|s| {
         // Execute the liquidation in state first (this must happen)
         s.liquidate_vault(vault_id, mode, icp_rate);
         
@@ -934,37 +1791,58 @@ pub async fn liquidate_vault(vault_id: u64) -> Result<SuccessWithFee, ProtocolEr
             liquidator: Some(caller),
         };
         crate::storage::record_event(&event);
+
+        if bad_debt > 0 {
+            log!(INFO, "[liquidate_vault] Vault #{} left {} icUSD of bad debt", vault_id, bad_debt.to_u64());
+            record_bad_debt(s, vault_id, bad_debt);
+        }
         
         // Create pending transfer for liquidator reward
-        s.pending_margin_transfers.insert(
-            vault_id, 
-            PendingMarginTransfer {
-                owner: caller,
-                margin: icp_to_liquidator,
-            },
-        );
+        let now = ic_cdk::api::time();
+        let mut transfer_ids = vec![crate::event::record_transfer_queued(
+            s,
+            vault_id,
+            TransferPurpose::LiquidationReward,
+            caller,
+            icp_to_liquidator,
+            now,
+        )];
         
         // Create pending transfer for excess collateral to vault owner (if any)
         if excess_collateral > ICP::new(0) {
             log!(INFO, "[liquidate_vault] Scheduling excess collateral return to vault owner");
-            s.pending_margin_transfers.insert(
-                vault_id + 1_000_000, // Different ID to avoid collision
-                PendingMarginTransfer {
-                    owner: vault.owner,
-                    margin: excess_collateral,
-                },
-            );
+            transfer_ids.push(crate::event::record_transfer_queued(
+                s,
+                vault_id,
+                TransferPurpose::LiquidationExcess,
+                vault.owner,
+                excess_collateral,
+                now,
+            ));
+        }
+
+        // The protocol's share of the penalty is owed to the treasury
+        if let (Some(treasury), true) = (s.treasury_principal, protocol_penalty > ICP::new(0)) {
+            transfer_ids.push(crate::event::record_transfer_queued(
+                s,
+                vault_id,
+                TransferPurpose::LiquidationPenalty,
+                treasury,
+                protocol_penalty,
+                now,
+            ));
         }
         
         log!(INFO, "[liquidate_vault] Protocol state updated, {} pending transfers created", 
-             if excess_collateral > ICP::new(0) { 2 } else { 1 });
+             transfer_ids.len());
+        transfer_ids
     });
-    
+
     // Step 5: Attempt immediate transfer processing (best effort)
     log!(INFO, "[liquidate_vault] Attempting immediate transfer processing...");
     
     // Try to process transfers immediately
-    match try_process_pending_transfers_immediate(vault_id).await {
+    match try_process_pending_transfers_immediate(transfer_ids.clone()).await {
         Ok(processed_count) => {
             log!(INFO, "[liquidate_vault] Successfully processed {} transfers immediately", processed_count);
         },
@@ -972,7 +1850,7 @@ pub async fn liquidate_vault(vault_id: u64) -> Result<SuccessWithFee, ProtocolEr
             log!(INFO, "[liquidate_vault] Immediate processing failed: {}. Transfers will be retried via timer", e);
             
             // Schedule retry with exponential backoff
-            schedule_transfer_retry(vault_id, 0);
+            schedule_transfer_retry(transfer_ids, 0);
         }
     }
     
@@ -1004,55 +1882,229 @@ pub async fn liquidate_vault(vault_id: u64) -> Result<SuccessWithFee, ProtocolEr
     })
 }
 
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct LiquidateVaultsSuccess {
+    pub block_index: u64,
+    pub liquidated_vault_ids: Vec<u64>,
+    pub debt_paid: u64,
+    pub collateral_received: u64,
+}
+
+/// Liquidates the unhealthy vaults among `vault_ids`, lowest collateral ratio
+/// first, as long as their total debt fits in `max_total_debt`. The liquidator
+/// pays with a single icUSD transfer and receives one aggregated ICP payout.
+pub async fn liquidate_vaults(
+    vault_ids: Vec<u64>,
+    max_total_debt: u64,
+) -> Result<LiquidateVaultsSuccess, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let mut guard_principal = GuardPrincipal::new_for_liquidation_refund(caller, "liquidate_vaults")?;
+
+    // Step 1: Select the vaults to liquidate
+    let protocol_share = read_state(|s| s.effective_liquidation_protocol_share());
+    let now = ic_cdk::api::time();
+    let (selected, icp_rate, mode) = match read_state(|s| {
+        select_vaults_to_liquidate(s, &vault_ids, max_total_debt.into(), now)
+    }) {
+        Ok(selection) => selection,
+        Err(error) => {
+            guard_principal.fail();
+            return Err(error);
+        }
+    };
+    let selected_ids: Vec<u64> = selected.iter().map(|vault| vault.vault_id).collect();
+    guard_principal.lock_vaults(&selected_ids)?;
+
+    let total_debt: ICUSD = selected
+        .iter()
+        .map(|vault| full_liquidation_amounts(vault, icp_rate, protocol_share).debt_amount)
+        .sum();
+    log!(
+        INFO,
+        "[liquidate_vaults] {caller} liquidating {} vaults for {} icUSD",
+        selected.len(),
+        total_debt.to_u64()
+    );
+
+    // Step 2: Take the icUSD for all the vaults at once
+    let icusd_block_index = match transfer_icusd_from(total_debt, caller).await {
+        Ok(block_index) => block_index,
+        Err(transfer_from_error) => {
+            guard_principal.fail();
+            return Err(ProtocolError::TransferFromError(
+                transfer_from_error,
+                total_debt.to_u64(),
+            ));
+        }
+    };
+
+    // Step 3: Liquidate in state. A vault that changed during the transfer is
+    // skipped and its debt refunded to the liquidator.
+    let payout_key = selected[0].vault_id;
+    let (liquidated_vault_ids, debt_paid, collateral_received, transfer_ids) = mutate_state(|s| {
+        let now = ic_cdk::api::time();
+        let mut transfer_ids = vec![];
+        let mut liquidated_vault_ids = vec![];
+        let mut debt_paid = ICUSD::new(0);
+        let mut collateral_received = ICP::new(0);
+        let mut total_protocol_penalty = ICP::new(0);
+        for vault in &selected {
+            if s.vault_id_to_vaults.get(&vault.vault_id) != Some(vault) {
+                log!(
+                    INFO,
+                    "[liquidate_vaults] Vault #{} changed during the transfer, skipping",
+                    vault.vault_id
+                );
+                continue;
+            }
+            let LiquidationAmounts {
+                debt_amount,
+                icp_to_liquidator,
+                excess_collateral,
+                bad_debt,
+                protocol_penalty,
+            } = full_liquidation_amounts(vault, icp_rate, protocol_share);
+
+            s.liquidate_vault(vault.vault_id, mode, icp_rate);
+            crate::storage::record_event(&crate::event::Event::LiquidateVault {
+                vault_id: vault.vault_id,
+                mode,
+                icp_rate,
+                liquidator: Some(caller),
+            });
+            if bad_debt > 0 {
+                record_bad_debt(s, vault.vault_id, bad_debt);
+            }
+
+            if excess_collateral > ICP::new(0) {
+                transfer_ids.push(crate::event::record_transfer_queued(
+                    s,
+                    vault.vault_id,
+                    TransferPurpose::LiquidationExcess,
+                    vault.owner,
+                    excess_collateral,
+                    now,
+                ));
+            }
+            liquidated_vault_ids.push(vault.vault_id);
+            debt_paid += debt_amount;
+            collateral_received += icp_to_liquidator;
+            total_protocol_penalty += protocol_penalty;
+        }
+
+        if collateral_received > ICP::new(0) {
+            transfer_ids.push(crate::event::record_transfer_queued(
+                s,
+                payout_key,
+                TransferPurpose::LiquidationReward,
+                caller,
+                collateral_received,
+                now,
+            ));
+        }
+        if let (Some(treasury), true) = (s.treasury_principal, total_protocol_penalty > ICP::new(0)) {
+            transfer_ids.push(crate::event::record_transfer_queued(
+                s,
+                payout_key,
+                TransferPurpose::LiquidationPenalty,
+                treasury,
+                total_protocol_penalty,
+                now,
+            ));
+        }
+        // Owe the debt of the skipped vaults before refunding it, so that it
+        // stays claimable if the mint fails
+        let refund = total_debt - debt_paid;
+        if refund > 0 {
+            record_liquidation_refund_owed(s, caller, refund);
+        }
+        (liquidated_vault_ids, debt_paid, collateral_received, transfer_ids)
+    });
+
+    let refund = total_debt - debt_paid;
+    if refund > 0 {
+        match mint_icusd(refund, caller).await {
+            Ok(block_index) => {
+                mutate_state(|s| record_liquidation_refund_claimed(s, caller, refund, block_index));
+            }
+            Err(error) => {
+                log!(
+                    INFO,
+                    "[liquidate_vaults] Failed to refund {} icUSD to {caller}, it can be claimed: {}",
+                    refund.to_u64(),
+                    error
+                );
+            }
+        }
+    }
+
+    // Step 4: Pay out, falling back to the retry timers
+    if let Err(e) = try_process_pending_transfers_immediate(transfer_ids.clone()).await {
+        log!(
+            INFO,
+            "[liquidate_vaults] Immediate processing failed: {}. Transfers will be retried via timer",
+            e
+        );
+        schedule_transfer_retry(transfer_ids, 0);
+    }
+    ic_cdk_timers::set_timer(std::time::Duration::from_secs(2), || {
+        ic_cdk::spawn(crate::process_pending_transfer())
+    });
+
+    guard_principal.complete();
+    log!(
+        INFO,
+        "[liquidate_vaults] Liquidated vaults {:?} for {} icUSD, {} ICP to the liquidator",
+        liquidated_vault_ids,
+        debt_paid.to_u64(),
+        collateral_received.to_u64()
+    );
+
+    Ok(LiquidateVaultsSuccess {
+        block_index: icusd_block_index,
+        liquidated_vault_ids,
+        debt_paid: debt_paid.to_u64(),
+        collateral_received: collateral_received.to_u64(),
+    })
+}
+
 // Helper function to attempt immediate transfer processing
-async fn try_process_pending_transfers_immediate(vault_id: u64) -> Result<u32, String> {
+async fn try_process_pending_transfers_immediate(transfer_ids: Vec<TransferId>) -> Result<u32, String> {
     let mut processed_count = 0;
-    let ledger_fee = read_state(|s| s.icp_ledger_fee);
     
-    // Get pending transfers for this liquidation
+    // Get the transfers that are still pending
     let transfers_to_process = read_state(|s| {
-        let mut transfers = Vec::new();
-        
-        // Primary transfer (liquidator reward)
-        if let Some(transfer) = s.pending_margin_transfers.get(&vault_id) {
-            transfers.push((vault_id, transfer.clone()));
-        }
-        
-        // Excess collateral transfer (if exists)
-        if let Some(transfer) = s.pending_margin_transfers.get(&(vault_id + 1_000_000)) {
-            transfers.push((vault_id + 1_000_000, transfer.clone()));
-        }
-        
-        transfers
+        transfer_ids
+            .iter()
+            .filter_map(|id| s.pending_transfers.get(id).cloned())
+            .collect::<Vec<_>>()
     });
     
     // Process each transfer
-    for (transfer_id, transfer) in transfers_to_process {
-        let transfer_amount = transfer.margin - ledger_fee;
-        
-        if transfer_amount <= ICP::new(0) {
-            log!(INFO, "[immediate_transfer] Skipping transfer {} - amount too small after fee", transfer_id);
+    for transfer in transfers_to_process {
+        if crate::drop_transfer_if_dust(&transfer) {
             continue;
         }
+        let transfer_amount = transfer.amount - transfer.fee;
         
         log!(INFO, "[immediate_transfer] Processing transfer {} of {} ICP to {}", 
-             transfer_id, transfer_amount.to_u64(), transfer.owner);
+             transfer.id, transfer_amount.to_u64(), transfer.destination);
         
-        match management::transfer_icp(transfer_amount, transfer.owner).await {
+        match crate::execute_pending_transfer(&transfer).await {
             Ok(block_index) => {
-                log!(INFO, "[immediate_transfer] Transfer {} successful, block: {}", transfer_id, block_index);
+                log!(INFO, "[immediate_transfer] Transfer {} successful, block: {}", transfer.id, block_index);
                 
                 // Remove from pending transfers
-                mutate_state(|s| {
-                    s.pending_margin_transfers.remove(&transfer_id);
-                });
+                mutate_state(|s| crate::event::record_margin_transfer(s, transfer.id, block_index));
+                notify_treasury_of_transfer(&transfer, block_index);
                 
                 processed_count += 1;
             },
             Err(error) => {
-                log!(INFO, "[immediate_transfer] Transfer {} failed: {}. Will retry later", transfer_id, error);
+                log!(INFO, "[immediate_transfer] Transfer {} failed: {}. Will retry later", transfer.id, error);
                 // Leave in pending transfers for retry
-                return Err(format!("Transfer {} failed: {}", transfer_id, error));
+                mutate_state(|s| s.record_transfer_failure(transfer.id, error.to_string()));
+                return Err(format!("Transfer {} failed: {}", transfer.id, error));
             }
         }
     }
@@ -1061,30 +2113,30 @@ async fn try_process_pending_transfers_immediate(vault_id: u64) -> Result<u32, S
 }
 
 // Helper function to schedule transfer retries with exponential backoff
-fn schedule_transfer_retry(vault_id: u64, retry_count: u32) {
+fn schedule_transfer_retry(transfer_ids: Vec<TransferId>, retry_count: u32) {
     let max_retries = 5;
     if retry_count >= max_retries {
-        log!(INFO, "[retry_scheduler] Max retries reached for vault #{}", vault_id);
+        log!(INFO, "[retry_scheduler] Max retries reached for transfers {:?}", transfer_ids);
         return;
     }
     
     // Exponential backoff: 1s, 2s, 4s, 8s, 16s
     let delay_seconds = 1u64 << retry_count;
     
-    log!(INFO, "[retry_scheduler] Scheduling retry #{} for vault #{} in {}s", 
-         retry_count + 1, vault_id, delay_seconds);
+    log!(INFO, "[retry_scheduler] Scheduling retry #{} for transfers {:?} in {}s", 
+         retry_count + 1, transfer_ids, delay_seconds);
     
     ic_cdk_timers::set_timer(std::time::Duration::from_secs(delay_seconds), move || {
         ic_cdk::spawn(async move {
-            log!(INFO, "[retry_scheduler] Retry #{} executing for vault #{}", retry_count + 1, vault_id);
+            log!(INFO, "[retry_scheduler] Retry #{} executing for transfers {:?}", retry_count + 1, transfer_ids);
             
-            match try_process_pending_transfers_immediate(vault_id).await {
+            match try_process_pending_transfers_immediate(transfer_ids.clone()).await {
                 Ok(processed) => {
                     log!(INFO, "[retry_scheduler] Retry #{} successful, processed {} transfers", retry_count + 1, processed);
                 },
                 Err(_) => {
                     log!(INFO, "[retry_scheduler] Retry #{} failed, scheduling next retry", retry_count + 1);
-                    schedule_transfer_retry(vault_id, retry_count + 1);
+                    schedule_transfer_retry(transfer_ids, retry_count + 1);
                 }
             }
         })
@@ -1192,4 +2244,97 @@ async fn route_redemption_fee_to_treasury(
             }
         }
     }
-}
\ No newline at end of file
+}
+
+const MAX_TREASURY_ROUTING_ATTEMPTS: u32 = 5;
+
+/// Reports a completed transfer of a liquidation penalty to the treasury as a
+/// `LiquidationSurplus` deposit. Other transfers are ignored.
+pub fn notify_treasury_of_transfer(transfer: &PendingTransfer, icp_block_index: u64) {
+    if transfer.purpose == TransferPurpose::LiquidationPenalty {
+        schedule_liquidation_penalty_deposit(transfer.vault_id, transfer.amount, icp_block_index, 0);
+    }
+}
+
+fn schedule_liquidation_penalty_deposit(
+    vault_id: u64,
+    penalty: ICP,
+    icp_block_index: u64,
+    attempt: u32,
+) {
+    if attempt >= MAX_TREASURY_ROUTING_ATTEMPTS {
+        log!(
+            INFO,
+            "[treasury] Giving up reporting liquidation penalty of {} ICP from vault {} (ICP block {})",
+            penalty.to_u64(),
+            vault_id,
+            icp_block_index
+        );
+        return;
+    }
+    // Exponential backoff: 1s, 2s, 4s, 8s, 16s
+    let delay_seconds = 1u64 << attempt;
+    ic_cdk_timers::set_timer(std::time::Duration::from_secs(delay_seconds), move || {
+        ic_cdk::spawn(report_liquidation_penalty_to_treasury(
+            vault_id,
+            penalty,
+            icp_block_index,
+            attempt,
+        ))
+    });
+}
+
+/// Tells the treasury about a liquidation penalty it already received. The
+/// ICP itself is sent through the pending transfer queue.
+async fn report_liquidation_penalty_to_treasury(
+    vault_id: u64,
+    penalty: ICP,
+    icp_block_index: u64,
+    attempt: u32,
+) {
+    let treasury_principal = match read_state(|s| s.treasury_principal) {
+        Some(treasury_principal) => treasury_principal,
+        None => return,
+    };
+
+    let deposit_args = crate::state::DepositArgs {
+        deposit_type: crate::state::DepositType::LiquidationSurplus,
+        asset_type: crate::state::AssetType::ICP,
+        amount: penalty.to_u64(),
+        block_index: icp_block_index,
+        memo: Some(format!("Liquidation penalty from vault {}", vault_id)),
+    };
+
+    let call_result: Result<(Result<u64, String>,), _> = ic_cdk::call(
+        treasury_principal,
+        "deposit",
+        (deposit_args,),
+    ).await;
+
+    match call_result {
+        Ok((Ok(_deposit_id),)) => {
+            log!(
+                INFO,
+                "[treasury] Liquidation penalty {} ICP from vault {} reported to treasury",
+                penalty.to_u64(),
+                vault_id
+            );
+        }
+        Ok((Err(err),)) => {
+            log!(
+                DEBUG,
+                "[treasury] Treasury deposit failed for liquidation penalty: {}",
+                err
+            );
+            schedule_liquidation_penalty_deposit(vault_id, penalty, icp_block_index, attempt + 1);
+        }
+        Err(call_err) => {
+            log!(
+                DEBUG,
+                "[treasury] Failed to call treasury for liquidation penalty: {:?}",
+                call_err
+            );
+            schedule_liquidation_penalty_deposit(vault_id, penalty, icp_block_index, attempt + 1);
+        }
+    }
+}
@@ -2,11 +2,10 @@ use crate::logs::TRACE_XRC;
 use crate::numeric::UsdIcp;  
 use crate::state::{mutate_state, read_state};
 use crate::Decimal;
-use crate::Mode;
+use crate::MIN_ICP_RATE;
 use ic_canister_log::log;
 use ic_xrc_types::GetExchangeRateResult;
 use rust_decimal::prelude::FromPrimitive;
-use rust_decimal_macros::dec;
 use std::time::Duration;
 
 pub const FETCHING_ICP_RATE_INTERVAL: Duration = Duration::from_secs(60);
@@ -23,13 +22,12 @@ pub async fn fetch_icp_rate() {
                 let rate = Decimal::from_u64(exchange_rate_result.rate).unwrap()
                     / Decimal::from_u64(10_u64.pow(exchange_rate_result.metadata.decimals))
                         .unwrap();
-                if rate < dec!(0.01) {  // Changed threshold for ICP
+                if UsdIcp::from(rate) < MIN_ICP_RATE {
                     log!(
                         TRACE_XRC,
                         "[FetchPrice] Warning: ICP rate is below $0.01 switching to read-only at timestamp: {}",
                         exchange_rate_result.timestamp
                     );
-                    mutate_state(|s| s.mode = Mode::ReadOnly);
                 };
                 log!(
                     TRACE_XRC,
@@ -73,6 +73,10 @@ mod fixtures {
             borrowed_icusd_amount: ICUSD::from(500 * 100_000_000),
             icp_margin_amount: ICP::from(10 * 100_000_000),
             vault_id,
+            liquidation_reserve: ICUSD::from(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         }
     }
     
@@ -82,6 +86,10 @@ mod fixtures {
             borrowed_icusd_amount: ICUSD::from(50 * 100_000_000),
             icp_margin_amount: ICP::from(10 * 100_000_000),
             vault_id,
+            liquidation_reserve: ICUSD::from(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         }
     }
     
@@ -91,6 +99,10 @@ mod fixtures {
             borrowed_icusd_amount: ICUSD::from(100 * 100_000_000),
             icp_margin_amount: ICP::from(5 * 100_000_000),
             vault_id,
+            liquidation_reserve: ICUSD::from(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         }
     }
 }
@@ -427,6 +439,10 @@ mod protocol_safety_tests {
             borrowed_icusd_amount: ICUSD::from(70 * 100_000_000), // 70 ICUSD borrowed
             icp_margin_amount: ICP::from(10 * 100_000_000),       // 10 ICP margin
             vault_id: borderline_vault_id,
+            liquidation_reserve: ICUSD::from(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         };
         
         state.vault_id_to_vaults.insert(healthy_vault_id, healthy_vault.clone());
@@ -643,6 +659,10 @@ mod minting_tests {
             borrowed_icusd_amount: ICUSD::from(0),
             icp_margin_amount: ICP::from(10 * 100_000_000), // 10 ICP
             vault_id,
+            liquidation_reserve: ICUSD::from(0),
+            interest_rate: Ratio::default(),
+            last_interest_rate_adjustment: 0,
+            opened_at: 0,
         };
         println!("💰 Created vault with {} ICP margin", vault.icp_margin_amount);
         
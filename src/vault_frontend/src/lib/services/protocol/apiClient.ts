@@ -345,7 +345,23 @@ private static async refreshVaultData(): Promise<void> {
     else if ('AlreadyProcessing' in error) {
       return 'This operation is already in progress. Please wait.';
     }
-    
+    // Typed errors, only returned once enabled with set_typed_errors
+    else if ('VaultNotFound' in error) {
+      return `Vault #${error.VaultNotFound.vault_id} not found`;
+    }
+    else if ('NotLiquidatable' in error) {
+      return `Vault is not liquidatable. Current ratio: ${(error.NotLiquidatable.ratio * 100).toFixed(2)}%, minimum: ${(error.NotLiquidatable.minimum * 100).toFixed(2)}%`;
+    }
+    else if ('CollateralRatioTooLow' in error) {
+      return `This would leave the vault at a collateral ratio of ${(error.CollateralRatioTooLow.resulting * 100).toFixed(2)}%, below the minimum of ${(error.CollateralRatioTooLow.required * 100).toFixed(2)}%`;
+    }
+    else if ('OperationPaused' in error) {
+      return `Service temporarily unavailable: ${error.OperationPaused.reason}`;
+    }
+    else if ('StalePrice' in error) {
+      return 'Service temporarily unavailable: Last known ICP price too old';
+    }
+
     return 'An error occurred with the operation';
   }
 